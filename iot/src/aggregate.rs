@@ -0,0 +1,207 @@
+use num_traits::ToPrimitive;
+
+/// 可以参与聚合的数据, 无法表示为 `f64` 时返回 `None`
+pub trait AggregateValue {
+    fn aggregate_value(&self) -> Option<f64>;
+}
+
+macro_rules! impl_aggregate_value_for {
+    ($($t:ty),*) => {
+        $(
+            impl AggregateValue for $t {
+                fn aggregate_value(&self) -> Option<f64> {
+                    self.to_f64()
+                }
+            }
+        )*
+    };
+}
+
+impl_aggregate_value_for!(i8, i16, i32, i64, i128, u8, u16, u32, u64, u128, f32, f64);
+
+/// 带毫秒时间戳的读数 `(timestamp, value)`
+impl<V: AggregateValue> AggregateValue for (i64, V) {
+    fn aggregate_value(&self) -> Option<f64> {
+        self.1.aggregate_value()
+    }
+}
+
+/// 收集过程中可以计算的聚合
+#[derive(Clone, Debug, PartialEq)]
+pub enum Aggregation {
+    Sum,
+    Count,
+    Min,
+    Max,
+    Mean,
+    /// 按给定的升序边界分桶计数
+    Histogram { bounds: Vec<f64> },
+}
+
+/// 直方图, `counts[i]` 为落在 `[bounds[i - 1], bounds[i])` 内的数据个数,
+/// 第一个桶没有下界, 最后一个桶统计大于等于最后一个边界的数据
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Histogram {
+    pub bounds: Vec<f64>,
+    pub counts: Vec<u64>,
+}
+
+impl Histogram {
+    pub fn new(bounds: Vec<f64>) -> Self {
+        let counts = vec![0; bounds.len() + 1];
+        Self { bounds, counts }
+    }
+
+    pub fn record(&mut self, value: f64) {
+        let idx = self.bounds.partition_point(|bound| *bound <= value);
+        self.counts[idx] += 1;
+    }
+}
+
+/// 聚合结果, 只有配置过的聚合才为 `Some`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Aggregates {
+    pub count: Option<u64>,
+    pub sum: Option<f64>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub mean: Option<f64>,
+    pub histogram: Option<Histogram>,
+}
+
+/// 增量计算配置的聚合
+#[derive(Clone, Debug, Default)]
+pub struct Aggregator {
+    aggregations: Vec<Aggregation>,
+    count: u64,
+    /// 能转换为 `f64` 并参与聚合的数据个数
+    aggregated: u64,
+    sum: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+    histogram: Option<Histogram>,
+}
+
+impl Aggregator {
+    pub fn new(aggregations: Vec<Aggregation>) -> Self {
+        let histogram = aggregations.iter().find_map(|agg| match agg {
+            Aggregation::Histogram { bounds } => Some(Histogram::new(bounds.clone())),
+            _ => None,
+        });
+        Self {
+            aggregations,
+            histogram,
+            ..Default::default()
+        }
+    }
+
+    /// 累加一个数据, 无法转换为 `f64` 的数据只计数
+    pub fn update<T: AggregateValue>(&mut self, value: &T) {
+        self.count += 1;
+        let Some(value) = value.aggregate_value() else {
+            return;
+        };
+        self.aggregated += 1;
+        self.sum += value;
+        self.min = Some(self.min.map_or(value, |min| min.min(value)));
+        self.max = Some(self.max.map_or(value, |max| max.max(value)));
+        if let Some(histogram) = self.histogram.as_mut() {
+            histogram.record(value);
+        }
+    }
+
+    /// 合并另一个聚合器的中间结果, 两者需要使用相同的配置
+    pub fn merge(&mut self, other: &Aggregator) {
+        self.count += other.count;
+        self.aggregated += other.aggregated;
+        self.sum += other.sum;
+        self.min = match (self.min, other.min) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.max = match (self.max, other.max) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+        if let (Some(histogram), Some(other)) = (self.histogram.as_mut(), other.histogram.as_ref()) {
+            for (count, other) in histogram.counts.iter_mut().zip(&other.counts) {
+                *count += other;
+            }
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn finish(&self) -> Aggregates {
+        let mut result = Aggregates::default();
+        for agg in &self.aggregations {
+            match agg {
+                Aggregation::Sum => result.sum = Some(self.sum),
+                Aggregation::Count => result.count = Some(self.count),
+                Aggregation::Min => result.min = self.min,
+                Aggregation::Max => result.max = self.max,
+                Aggregation::Mean => {
+                    result.mean = (self.aggregated > 0).then(|| self.sum / self.aggregated as f64)
+                }
+                Aggregation::Histogram { .. } => result.histogram = self.histogram.clone(),
+            }
+        }
+        result
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_aggregator() {
+        let mut aggregator = Aggregator::new(vec![
+            Aggregation::Sum,
+            Aggregation::Mean,
+            Aggregation::Max,
+            Aggregation::Histogram { bounds: vec![2.0, 5.0] },
+        ]);
+        for value in [1u16, 2, 4, 5, 8] {
+            aggregator.update(&value);
+        }
+        let result = aggregator.finish();
+        assert_eq!(result.sum, Some(20.0));
+        assert_eq!(result.mean, Some(4.0));
+        assert_eq!(result.max, Some(8.0));
+        assert_eq!(result.min, None);
+        assert_eq!(result.count, None);
+        assert_eq!(result.histogram.unwrap().counts, vec![1, 2, 2]);
+    }
+
+    /// `None` 表示无法转换为 `f64` 的读数
+    struct Reading(Option<f64>);
+
+    impl AggregateValue for Reading {
+        fn aggregate_value(&self) -> Option<f64> {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_mean_skips_unconvertible_values() {
+        let mut aggregator = Aggregator::new(vec![Aggregation::Count, Aggregation::Mean]);
+        for value in [Some(2.0), None, Some(4.0), None] {
+            aggregator.update(&Reading(value));
+        }
+        let mut other = Aggregator::new(vec![Aggregation::Count, Aggregation::Mean]);
+        other.update(&Reading(Some(6.0)));
+        aggregator.merge(&other);
+
+        let result = aggregator.finish();
+        assert_eq!(result.count, Some(5));
+        assert_eq!(result.mean, Some(4.0));
+
+        let mut empty = Aggregator::new(vec![Aggregation::Mean]);
+        empty.update(&Reading(None));
+        assert_eq!(empty.finish().mean, None);
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use pin_project::pin_project;
+use tokio_stream::Stream;
+use crate::reading::Reading;
+
+/// 异常检测器及其阈值
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Detector {
+    /// 与前 `window` 个读数的均值相差超过 `threshold` 个标准差,
+    /// 窗口未满或标准差为 0 时不检测
+    ZScore { window: usize, threshold: f64 },
+    /// EWMA 控制图: 读数超出 `均值 ± sigmas * 标准差`, 前 `warmup` 个读数只用于估计
+    Ewma { alpha: f64, sigmas: f64, warmup: usize },
+    /// 相邻读数的变化率超过 `max_per_sec`(单位/秒)
+    RateOfChange { max_per_sec: f64 },
+    /// 连续 `count` 个读数完全相同, 每段只告警一次
+    Stuck { count: usize },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AlertKind {
+    ZScore,
+    Ewma,
+    RateOfChange,
+    Stuck,
+}
+
+/// 告警记录
+#[derive(Clone, Debug, PartialEq)]
+pub struct Alert {
+    pub device_id: u32,
+    pub timestamp: i64,
+    pub kind: AlertKind,
+    pub value: f64,
+    /// 检测器算出的指标: z 分数, 偏离的标准差个数, 变化率或连续相同的次数
+    pub score: f64,
+    pub threshold: f64,
+}
+
+/// 检测算子的输出, 每个读数原样输出, 触发的告警紧跟在读数之后
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    Data(Reading),
+    Alert(Alert),
+}
+
+enum State {
+    ZScore { window: VecDeque<f64> },
+    Ewma { mean: f64, variance: f64, seen: usize },
+    RateOfChange { last: Option<(i64, f64)> },
+    Stuck { last: Option<f64>, run: usize },
+}
+
+impl State {
+    fn new(detector: &Detector) -> Self {
+        match detector {
+            Detector::ZScore { .. } => State::ZScore { window: VecDeque::new() },
+            Detector::Ewma { .. } => State::Ewma { mean: 0.0, variance: 0.0, seen: 0 },
+            Detector::RateOfChange { .. } => State::RateOfChange { last: None },
+            Detector::Stuck { .. } => State::Stuck { last: None, run: 0 },
+        }
+    }
+
+    /// 返回 `(告警类型, 指标, 阈值)`
+    fn observe(&mut self, detector: &Detector, timestamp: i64, x: f64) -> Option<(AlertKind, f64, f64)> {
+        match (self, detector) {
+            (State::ZScore { window }, Detector::ZScore { window: size, threshold }) => {
+                let mut alert = None;
+                if window.len() >= (*size).max(2) {
+                    let n = window.len() as f64;
+                    let mean = window.iter().sum::<f64>() / n;
+                    let std = (window.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n).sqrt();
+                    let z = (x - mean).abs() / std;
+                    if std > 0.0 && z > *threshold {
+                        alert = Some((AlertKind::ZScore, z, *threshold));
+                    }
+                }
+                window.push_back(x);
+                while window.len() > (*size).max(2) {
+                    window.pop_front();
+                }
+                alert
+            }
+            (State::Ewma { mean, variance, seen }, Detector::Ewma { alpha, sigmas, warmup }) => {
+                let mut alert = None;
+                if *seen == 0 {
+                    *mean = x;
+                } else {
+                    let std = variance.sqrt();
+                    let deviation = (x - *mean).abs() / std;
+                    if *seen >= *warmup && std > 0.0 && deviation > *sigmas {
+                        alert = Some((AlertKind::Ewma, deviation, *sigmas));
+                    }
+                    let diff = x - *mean;
+                    *mean += alpha * diff;
+                    *variance = (1.0 - alpha) * (*variance + alpha * diff * diff);
+                }
+                *seen += 1;
+                alert
+            }
+            (State::RateOfChange { last }, Detector::RateOfChange { max_per_sec }) => {
+                let alert = last.and_then(|(ts, prev)| {
+                    let dt = (timestamp - ts) as f64 / 1000.0;
+                    let rate = (x - prev).abs() / dt;
+                    (dt > 0.0 && rate > *max_per_sec).then_some((AlertKind::RateOfChange, rate, *max_per_sec))
+                });
+                *last = Some((timestamp, x));
+                alert
+            }
+            (State::Stuck { last, run }, Detector::Stuck { count }) => {
+                *run = if *last == Some(x) { *run + 1 } else { 1 };
+                *last = Some(x);
+                (*run == *count).then_some((AlertKind::Stuck, *run as f64, *count as f64))
+            }
+            _ => unreachable!("detector state does not match its detector"),
+        }
+    }
+}
+
+/// 按设备维护检测器状态, 没有单独配置的设备使用默认检测器
+pub struct AnomalyDetector {
+    defaults: Vec<Detector>,
+    devices: HashMap<u32, Vec<Detector>>,
+    states: HashMap<u32, Vec<State>>,
+}
+
+impl AnomalyDetector {
+    pub fn new() -> Self {
+        Self { defaults: Vec::new(), devices: HashMap::new(), states: HashMap::new() }
+    }
+
+    /// 添加对所有设备生效的检测器
+    pub fn detector(mut self, detector: Detector) -> Self {
+        self.defaults.push(detector);
+        self
+    }
+
+    /// 为设备单独配置检测器, 替换默认检测器
+    pub fn device(mut self, device_id: u32, detectors: Vec<Detector>) -> Self {
+        self.devices.insert(device_id, detectors);
+        self.states.remove(&device_id);
+        self
+    }
+
+    /// 检测一个读数, 返回触发的告警
+    pub fn observe(&mut self, reading: &Reading) -> Vec<Alert> {
+        let detectors = self.devices.get(&reading.device_id).unwrap_or(&self.defaults);
+        let states = self
+            .states
+            .entry(reading.device_id)
+            .or_insert_with(|| detectors.iter().map(State::new).collect());
+        detectors
+            .iter()
+            .zip(states.iter_mut())
+            .filter_map(|(detector, state)| state.observe(detector, reading.timestamp, reading.value))
+            .map(|(kind, score, threshold)| Alert {
+                device_id: reading.device_id,
+                timestamp: reading.timestamp,
+                kind,
+                value: reading.value,
+                score,
+                threshold,
+            })
+            .collect()
+    }
+
+    pub fn build<S>(self, stream: S) -> Detected<S>
+    where
+        S: Stream<Item = Reading>,
+    {
+        Detected { stream, detector: self, pending: VecDeque::new() }
+    }
+}
+
+impl Default for AnomalyDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `AnomalyDetector::build` 返回的数据流
+#[pin_project]
+pub struct Detected<S> {
+    #[pin]
+    stream: S,
+    detector: AnomalyDetector,
+    pending: VecDeque<Alert>,
+}
+
+impl<S> Detected<S> {
+    pub fn detector(&self) -> &AnomalyDetector {
+        &self.detector
+    }
+}
+
+impl<S> Stream for Detected<S>
+where
+    S: Stream<Item = Reading>,
+{
+    type Item = Event;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        if let Some(alert) = this.pending.pop_front() {
+            return Poll::Ready(Some(Event::Alert(alert)));
+        }
+        match this.stream.poll_next(cx) {
+            Poll::Ready(Some(reading)) => {
+                this.pending.extend(this.detector.observe(&reading));
+                Poll::Ready(Some(Event::Data(reading)))
+            }
+            other => other.map(|_| None),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use tokio_stream::StreamExt;
+    use super::*;
+
+    fn alerts(detector: Detector, values: &[f64]) -> Vec<(usize, f64)> {
+        let mut anomaly = AnomalyDetector::new().detector(detector);
+        values
+            .iter()
+            .enumerate()
+            .flat_map(|(i, v)| {
+                anomaly
+                    .observe(&Reading::new(1, i as i64 * 1000, *v))
+                    .into_iter()
+                    .map(move |alert| (i, alert.score))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_detectors() {
+        let zscore = alerts(Detector::ZScore { window: 4, threshold: 3.0 }, &[10.0, 11.0, 10.0, 11.0, 10.5, 30.0, 10.0]);
+        assert_eq!(zscore.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec![5]);
+        assert!(zscore[0].1 > 30.0);
+
+        let ewma = alerts(
+            Detector::Ewma { alpha: 0.3, sigmas: 3.0, warmup: 4 },
+            &[1.0, 2.0, 1.0, 2.0, 1.5, 9.0, 1.5],
+        );
+        assert_eq!(ewma.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec![5]);
+
+        let rate = alerts(Detector::RateOfChange { max_per_sec: 5.0 }, &[0.0, 4.0, 10.0, 9.0]);
+        assert_eq!(rate, vec![(2, 6.0)]);
+
+        let stuck = alerts(Detector::Stuck { count: 3 }, &[1.0, 2.0, 2.0, 2.0, 2.0, 3.0, 3.0, 3.0]);
+        assert_eq!(stuck, vec![(3, 3.0), (7, 3.0)]);
+    }
+
+    #[tokio::test]
+    async fn test_per_device_thresholds() {
+        let readings = vec![
+            Reading::new(1, 0, 20.0),
+            Reading::new(2, 0, 20.0),
+            Reading::new(1, 1000, 23.0),
+            Reading::new(2, 1000, 23.0),
+        ];
+        let events: Vec<Event> = AnomalyDetector::new()
+            .detector(Detector::RateOfChange { max_per_sec: 2.0 })
+            .device(2, vec![Detector::RateOfChange { max_per_sec: 5.0 }])
+            .build(tokio_stream::iter(readings.clone()))
+            .collect()
+            .await;
+
+        assert_eq!(events.len(), 5);
+        assert_eq!(events[2], Event::Data(readings[2]));
+        let Event::Alert(alert) = &events[3] else {
+            panic!("expected alert, got {:?}", events[3]);
+        };
+        assert_eq!((alert.device_id, alert.kind, alert.score, alert.threshold), (1, AlertKind::RateOfChange, 3.0, 2.0));
+        assert_eq!(events[4], Event::Data(readings[3]));
+    }
+}
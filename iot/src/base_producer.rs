@@ -1,126 +1,254 @@
+use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use log::{debug, warn};
 use pin_project::pin_project;
+use thiserror::Error;
 use tokio::sync::mpsc::Receiver;
 use tokio_stream::Stream;
+use crate::metrics::{ProducerMetrics, Registry};
+use crate::shutdown::{Shutdown, Signal};
 use crate::stream::DataAvailable;
 
-pub trait HasErrorValue {
-    fn is_error_value(&self) -> bool;
-    fn into_error_value(&self) -> Self;
+/// 设备或链路上发生的错误, 与正常读数通过同一个通道传递
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ProducerError {
+    #[error("device fault: {0}")]
+    DeviceFault(String),
+    #[error("timed out waiting for reading")]
+    Timeout,
+    #[error("failed to decode reading: {0}")]
+    Decode(String),
 }
 
-impl HasErrorValue for i32 {
-    fn is_error_value(&self) -> bool {
-        *self == -1
+/// 收到错误后的处理方式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// 结束数据流
+    Terminate,
+    /// 丢弃错误, 继续等待下一个读数
+    Skip,
+    /// 继续等待下一个读数, 同一种错误连续出现超过 `max_retries` 次后结束数据流.
+    /// 其他种类的错误不占用次数
+    Retry { max_retries: u32 },
+}
+
+/// 每种错误对应的处理方式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ErrorPolicies {
+    pub device_fault: ErrorPolicy,
+    pub timeout: ErrorPolicy,
+    pub decode: ErrorPolicy,
+}
+
+impl Default for ErrorPolicies {
+    fn default() -> Self {
+        Self {
+            device_fault: ErrorPolicy::Terminate,
+            timeout: ErrorPolicy::Retry { max_retries: 3 },
+            decode: ErrorPolicy::Skip,
+        }
+    }
+}
+
+impl ErrorPolicies {
+    pub fn policy_for(&self, error: &ProducerError) -> ErrorPolicy {
+        match error {
+            ProducerError::DeviceFault(_) => self.device_fault,
+            ProducerError::Timeout => self.timeout,
+            ProducerError::Decode(_) => self.decode,
+        }
     }
+}
 
-    fn into_error_value(&self) -> Self {
-        -1
+impl ProducerError {
+    /// 每种错误单独计数时使用的下标
+    fn kind(&self) -> usize {
+        match self {
+            ProducerError::DeviceFault(_) => 0,
+            ProducerError::Timeout => 1,
+            ProducerError::Decode(_) => 2,
+        }
     }
 }
 
 
 #[pin_project]
-pub struct Producer<T>
-where
-    T: HasErrorValue
-{
+pub struct Producer<T> {
     #[pin]
-    receiver: Receiver<T>,
+    receiver: Receiver<Result<T, ProducerError>>,
 
     post_process_fn: Box<dyn Fn(T) -> T + 'static + Send>,
 
     #[pin]
     data_available: DataAvailable,
+
+    policies: ErrorPolicies,
+    /// 每种错误连续出现的次数, 收到正常读数后清零
+    consecutive_errors: [u32; 3],
+    /// 被跳过的错误个数
+    skipped: u64,
+    /// 导致数据流结束的错误
+    error: Option<ProducerError>,
+    shutdown: Option<Signal>,
+    /// 停止信号已触发, 正在产出缓冲中的数据
+    draining: bool,
+    metrics: Option<ProducerMetrics>,
 }
 
 
-impl<T> Producer<T>
-where
-    T: HasErrorValue
-{
+impl<T> Producer<T> {
     pub fn new(
         data_available: DataAvailable,
-        receiver: Receiver<T>,
+        receiver: Receiver<Result<T, ProducerError>>,
         post_process_fn: Box<dyn Fn(T) -> T + Send + 'static>,
     ) -> Self {
         Self {
             data_available,
             receiver,
             post_process_fn,
+            policies: ErrorPolicies::default(),
+            consecutive_errors: [0; 3],
+            skipped: 0,
+            error: None,
+            shutdown: None,
+            draining: false,
+            metrics: None,
         }
     }
+
+    /// 停止信号触发后关闭通道, 产出已缓冲的数据后结束数据流
+    pub fn with_shutdown(mut self, shutdown: &Shutdown) -> Self {
+        self.shutdown = Some(shutdown.signal());
+        self
+    }
+
+    /// 在 `registry` 中以 `producer` 标签 `name` 记录 poll 次数, 读数速率和通道深度
+    pub fn with_metrics(mut self, registry: &dyn Registry, name: &str) -> Self {
+        self.metrics = Some(ProducerMetrics::new(registry, name));
+        self
+    }
+
+    pub fn with_policies(mut self, policies: ErrorPolicies) -> Self {
+        self.policies = policies;
+        self
+    }
+
+    /// 导致数据流结束的错误, 数据流正常结束时为 `None`
+    pub fn error(&self) -> Option<&ProducerError> {
+        self.error.as_ref()
+    }
+
+    /// 按策略跳过或重试的错误个数
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
 }
 
 
 
-impl<T> Stream for Producer<T>
-where
-    T: HasErrorValue
-{
+impl<T> Stream for Producer<T> {
     type Item = T;
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.as_mut().project();
+        let waker = match this.metrics.as_mut() {
+            Some(metrics) => {
+                metrics.channel_depth.set(this.receiver.len() as f64);
+                metrics.poll.on_poll(cx.waker())
+            }
+            None => cx.waker().clone(),
+        };
+        let poll = self.as_mut().poll_channel(&mut Context::from_waker(&waker));
+        if let (Poll::Ready(Some(_)), Some(metrics)) = (&poll, self.metrics.as_ref()) {
+            metrics.poll.on_reading();
+        }
+        poll
+    }
+}
+
+impl<T> Producer<T> {
+    fn poll_channel(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
         let mut this = self.project();
-        // First, poll the DataAvailable stream to check if data is ready to be processed
-        match this.data_available.poll_next(cx) {
-            // DataAvailable indicates data is ready
-            Poll::Ready(Some(true)) => {
-                // Poll the receiver (mpsc channel) to get the next data item
-                match this.receiver.poll_recv(cx) {
-                    // Data is available on the channel
-                    Poll::Ready(Some(data)) => {
-                        if data.is_error_value() {
-                            // Sender informs us about an error condition
-                            Poll::Ready(None)
-                        } else {
-                            // Apply the post-processing function to the data and return it
-                            Poll::Ready(Some((this.post_process_fn)(data)))
-                            // Return the processed data
-                        }
-                    }
-                    Poll::Ready(None) => {
-                        Poll::Ready(None) // Indicate that the stream has ended
-                    }
-                    // No data is available on the channel yet, return Poll::Pending
-                    Poll::Pending => {
-                        Poll::Pending // Still waiting for data, return Poll::Pending
-                    }
+        // A terminal error has already ended the stream
+        if this.error.is_some() {
+            return Poll::Ready(None);
+        }
+        // 停止信号触发后关闭通道, 不再等待 DataAvailable, 直接产出缓冲中的数据
+        if !*this.draining {
+            if let Some(shutdown) = this.shutdown.as_mut() {
+                if Pin::new(shutdown).poll(cx).is_ready() {
+                    debug!("producer shutting down, draining {} buffered readings", this.receiver.len());
+                    this.receiver.close();
+                    *this.draining = true;
                 }
             }
-            // DataAvailable indicates data is not yet available, return Poll::Pending
-            Poll::Ready(Some(false)) => {
-                Poll::Pending // Wait for data to become available
-            }
-            // DataAvailable is still in progress, waiting for the next cycle
-            Poll::Pending => {
-                Poll::Pending // DataAvailable is still pending, so we also return Poll::Pending
+        }
+        if !*this.draining {
+            // First, poll the DataAvailable stream to check if data is ready to be processed
+            match this.data_available.poll_next(cx) {
+                // DataAvailable indicates data is ready
+                Poll::Ready(Some(true)) => {}
+                // DataAvailable indicates data is not yet available, or is still waiting for the next cycle
+                Poll::Ready(Some(false)) | Poll::Pending => return Poll::Pending,
+                // DataAvailable stream has ended, no more items will be produced
+                Poll::Ready(None) => return Poll::Ready(None),
             }
-            // DataAvailable stream has ended, no more items will be produced
-            Poll::Ready(None) => {
-                Poll::Ready(None) // End of stream
+        }
+        // Poll the receiver (mpsc channel) to get the next data item
+        loop {
+            match this.receiver.poll_recv(cx) {
+                // Data is available on the channel
+                Poll::Ready(Some(Ok(data))) => {
+                    *this.consecutive_errors = [0; 3];
+                    // Apply the post-processing function to the data and return it
+                    return Poll::Ready(Some((this.post_process_fn)(data)));
+                }
+                // Sender informs us about an error condition
+                Poll::Ready(Some(Err(err))) => {
+                    let count = &mut this.consecutive_errors[err.kind()];
+                    *count += 1;
+                    let terminate = match this.policies.policy_for(&err) {
+                        ErrorPolicy::Terminate => true,
+                        ErrorPolicy::Skip => false,
+                        ErrorPolicy::Retry { max_retries } => *count > max_retries,
+                    };
+                    if terminate {
+                        warn!("producer terminated: {}", err);
+                        *this.error = Some(err);
+                        this.receiver.close();
+                        return Poll::Ready(None);
+                    }
+                    debug!("producer skipped error: {}", err);
+                    *this.skipped += 1;
+                }
+                Poll::Ready(None) => {
+                    return Poll::Ready(None); // Indicate that the stream has ended
+                }
+                // No data is available on the channel yet, return Poll::Pending
+                Poll::Pending => {
+                    return Poll::Pending; // Still waiting for data, return Poll::Pending
+                }
             }
-         }
+        }
     }
 }
 
 
+#[cfg(test)]
 mod test {
+    use std::time::Duration;
     use log::info;
     use tokio::sync::mpsc::channel;
     use tokio_stream::StreamExt;
     use super::*;
 
-    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    #[tokio::test(start_paused = true)]
     async fn my_test() {
-        if let None = std::env::var_os("RUST_LOG") {
-            std::env::set_var("RUST_LOG", "info")
-        }
-        env_logger::init();
+        let _ = env_logger::try_init();
 
-        // Create the DataAvailable stream
-        let data_available = DataAvailable::new();
+        // Create the DataAvailable stream, seeded so arrivals are repeatable under paused time
+        let data_available = DataAvailable::new().with_seed(7);
 
         // Create an mpsc channel to send and receive data
         let (tx, rx) = channel(1);
@@ -131,44 +259,94 @@ mod test {
         // Spawn a task to send data into the channel
         tokio::spawn(async move {
             for i in 0..=10 {
-                tx.send(i).await.unwrap();
+                tx.send(Ok(i)).await.unwrap();
             }
         });
 
-        // Consume the Producer stream and print the result
+        // Consume the Producer stream and record when each item arrived
+        let start = tokio::time::Instant::now();
+        let mut produced = Vec::new();
+        let mut arrivals = Vec::new();
         while let Some(data) = producer.next().await {
             info!("Produced data: {}", data);
+            produced.push(data);
+            arrivals.push(start.elapsed());
         }
+        assert_eq!(produced, (1..=11).collect::<Vec<i32>>());
+
+        // 同一个种子的到达时间完全相同
+        let start = tokio::time::Instant::now();
+        let replay: Vec<Duration> = DataAvailable::new()
+            .with_seed(7)
+            .take(11)
+            .map(|_| start.elapsed())
+            .collect()
+            .await;
+        assert_eq!(arrivals, replay);
     }
 
-}
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
-
+    #[tokio::test(start_paused = true)]
+    async fn test_error_policies() {
+        let (tx, rx) = channel(16);
+        let mut producer = Producer::new(DataAvailable::new(), rx, Box::new(|x: i32| x));
+
+        // -1 是合法读数, 不会结束数据流
+        tx.send(Ok(-1)).await.unwrap();
+        tx.send(Err(ProducerError::Decode("bad frame".to_string()))).await.unwrap();
+        tx.send(Ok(2)).await.unwrap();
+        tx.send(Err(ProducerError::Timeout)).await.unwrap();
+        tx.send(Ok(3)).await.unwrap();
+        tx.send(Err(ProducerError::DeviceFault("sensor offline".to_string()))).await.unwrap();
+        tx.send(Ok(4)).await.unwrap();
+
+        let data: Vec<i32> = (&mut producer).collect().await;
+        assert_eq!(data, vec![-1, 2, 3]);
+        assert_eq!(producer.skipped(), 2);
+        assert_eq!(producer.error(), Some(&ProducerError::DeviceFault("sensor offline".to_string())));
+
+        // 连续超时超过重试次数后结束
+        let (tx, rx) = channel(16);
+        let mut producer = Producer::new(DataAvailable::new(), rx, Box::new(|x: i32| x))
+            .with_policies(ErrorPolicies { timeout: ErrorPolicy::Retry { max_retries: 1 }, ..Default::default() });
+        tx.send(Err(ProducerError::Timeout)).await.unwrap();
+        tx.send(Err(ProducerError::Timeout)).await.unwrap();
+        tx.send(Ok(1)).await.unwrap();
+        assert_eq!(producer.next().await, None);
+        assert_eq!(producer.error(), Some(&ProducerError::Timeout));
+
+        // 跳过的解码错误不占用超时的重试次数
+        let (tx, rx) = channel(16);
+        let mut producer = Producer::new(DataAvailable::new(), rx, Box::new(|x: i32| x))
+            .with_policies(ErrorPolicies { timeout: ErrorPolicy::Retry { max_retries: 1 }, ..Default::default() });
+        for _ in 0..5 {
+            tx.send(Err(ProducerError::Decode("bad frame".to_string()))).await.unwrap();
+        }
+        tx.send(Err(ProducerError::Timeout)).await.unwrap();
+        tx.send(Ok(1)).await.unwrap();
+        drop(tx);
+        let data: Vec<i32> = (&mut producer).collect().await;
+        assert_eq!(data, vec![1]);
+        assert_eq!(producer.skipped(), 6);
+        assert!(producer.error().is_none());
+    }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown_drains_buffer() {
+        let shutdown = Shutdown::new();
+        let (tx, rx) = channel(16);
+        let mut producer = Producer::new(DataAvailable::new(), rx, Box::new(|x: i32| x))
+            .with_shutdown(&shutdown);
+        for i in 0..3 {
+            tx.send(Ok(i)).await.unwrap();
+        }
+        assert_eq!(producer.next().await, Some(0));
+
+        // 触发后通道关闭, 发送失败, 但已缓冲的数据仍然产出
+        shutdown.trigger();
+        assert_eq!(producer.next().await, Some(1));
+        assert!(tx.send(Ok(3)).await.is_err());
+        assert_eq!(producer.next().await, Some(2));
+        assert_eq!(producer.next().await, None);
+        assert!(producer.error().is_none());
+    }
+}
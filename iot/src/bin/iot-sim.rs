@@ -0,0 +1,184 @@
+use std::time::Duration;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, ValueEnum};
+use log::{error, info};
+use num_traits::{Bounded, NumCast, ToPrimitive};
+use iot::producer::{Endian, ToBytes};
+use iot::sim::{DeviceConfig, SimDevice, ValueDistribution};
+
+/// 模拟 N 个使用 `TCPProducer` 线路协议的设备, 用于压测收集端
+#[derive(Parser, Debug)]
+#[command(name = "iot-sim")]
+struct Args {
+    /// 模拟设备数量, 第 i 个设备连接 `base_port + i`
+    #[arg(short = 'n', long, default_value_t = 10)]
+    devices: u16,
+    #[arg(long, default_value = "127.0.0.1")]
+    host: String,
+    #[arg(long, default_value_t = 7800)]
+    base_port: u16,
+    /// 线路上的数值类型
+    #[arg(long, value_enum, default_value_t = ValueType::U64)]
+    value_type: ValueType,
+    #[arg(long, value_enum, default_value_t = DistributionKind::Uniform)]
+    distribution: DistributionKind,
+    #[arg(long, default_value_t = 0.0)]
+    low: f64,
+    #[arg(long, default_value_t = 250.0)]
+    high: f64,
+    #[arg(long, default_value_t = 100.0)]
+    mean: f64,
+    #[arg(long, default_value_t = 10.0)]
+    std_dev: f64,
+    #[arg(long, default_value_t = 50.0)]
+    amplitude: f64,
+    /// 正弦波一个周期的采样数
+    #[arg(long, default_value_t = 100)]
+    period: u32,
+    #[arg(long, default_value_t = 100.0)]
+    offset: f64,
+    #[arg(long, default_value_t = 0.0)]
+    noise: f64,
+    /// 阶跃电平, 逗号分隔
+    #[arg(long, value_delimiter = ',', default_value = "10,100")]
+    levels: Vec<f64>,
+    /// 每隔多少个采样切换阶跃电平
+    #[arg(long, default_value_t = 50)]
+    step_every: u32,
+    /// 每个设备的应答间隔(毫秒)
+    #[arg(long, default_value_t = 100)]
+    interval_ms: u64,
+    /// 应答间隔上叠加的最大抖动(毫秒)
+    #[arg(long, default_value_t = 0)]
+    jitter_ms: u64,
+    #[arg(long, default_value_t = 0.0)]
+    disconnect_prob: f64,
+    #[arg(long, default_value_t = 500)]
+    reconnect_delay_ms: u64,
+    /// 重连等待时间按指数增长的上限(毫秒)
+    #[arg(long, default_value_t = 10_000)]
+    max_reconnect_delay_ms: u64,
+    #[arg(long, default_value_t = 10)]
+    max_reconnects: u32,
+    #[arg(long, default_value_t = 0.0)]
+    malformed_prob: f64,
+    /// 读数的字节序
+    #[arg(long, value_enum, default_value_t = ByteOrder::Little)]
+    byte_order: ByteOrder,
+    /// 随机数种子, 第 i 个设备使用 `seed + i`
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ValueType {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    I64,
+    U64,
+    F32,
+    F64,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ByteOrder {
+    Little,
+    Big,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum DistributionKind {
+    Uniform,
+    Gaussian,
+    Sine,
+    Step,
+}
+
+impl Args {
+    fn distribution(&self) -> ValueDistribution {
+        match self.distribution {
+            DistributionKind::Uniform => ValueDistribution::Uniform { low: self.low, high: self.high },
+            DistributionKind::Gaussian => ValueDistribution::Gaussian { mean: self.mean, std_dev: self.std_dev },
+            DistributionKind::Sine => ValueDistribution::Sine {
+                amplitude: self.amplitude,
+                period: self.period,
+                offset: self.offset,
+                noise: self.noise,
+            },
+            DistributionKind::Step => ValueDistribution::Step { levels: self.levels.clone(), every: self.step_every },
+        }
+    }
+
+    fn device_config(&self, i: u16) -> DeviceConfig {
+        DeviceConfig {
+            addr: format!("{}:{}", self.host, self.base_port + i),
+            distribution: self.distribution(),
+            interval: Duration::from_millis(self.interval_ms),
+            jitter: Duration::from_millis(self.jitter_ms),
+            disconnect_probability: self.disconnect_prob,
+            reconnect_delay: Duration::from_millis(self.reconnect_delay_ms),
+            max_reconnect_delay: Duration::from_millis(self.max_reconnect_delay_ms),
+            max_reconnects: self.max_reconnects,
+            malformed_probability: self.malformed_prob,
+            endian: match self.byte_order {
+                ByteOrder::Little => Endian::Little,
+                ByteOrder::Big => Endian::Big,
+            },
+            seed: self.seed.map(|seed| seed.wrapping_add(i as u64)),
+        }
+    }
+}
+
+async fn run_devices<T>(args: &Args)
+where
+    T: ToBytes + NumCast + Bounded + ToPrimitive + Clone + Send + 'static,
+{
+    let mut tasks = Vec::with_capacity(args.devices as usize);
+    for i in 0..args.devices {
+        let device: SimDevice<T> = SimDevice::new(i as u32, args.device_config(i));
+        tasks.push((i, tokio::spawn(device.run())));
+    }
+    for (i, task) in tasks {
+        match task.await {
+            Ok(Ok(stats)) => info!("device {} finished: {:?}", i, stats),
+            Ok(Err(e)) => error!("device {} failed: {}", i, e),
+            Err(e) => error!("device {} panicked: {}", i, e),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    if std::env::var_os("RUST_LOG").is_none() {
+        std::env::set_var("RUST_LOG", "info")
+    }
+    env_logger::init();
+
+    let args = Args::parse();
+    // 最后一个设备的端口不能超过 65535
+    if args.base_port.checked_add(args.devices.saturating_sub(1)).is_none() {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                format!("--base-port {} with --devices {} exceeds port 65535", args.base_port, args.devices),
+            )
+            .exit();
+    }
+    info!("starting {} simulated devices against {}:{}", args.devices, args.host, args.base_port);
+    match args.value_type {
+        ValueType::I8 => run_devices::<i8>(&args).await,
+        ValueType::U8 => run_devices::<u8>(&args).await,
+        ValueType::I16 => run_devices::<i16>(&args).await,
+        ValueType::U16 => run_devices::<u16>(&args).await,
+        ValueType::I32 => run_devices::<i32>(&args).await,
+        ValueType::U32 => run_devices::<u32>(&args).await,
+        ValueType::I64 => run_devices::<i64>(&args).await,
+        ValueType::U64 => run_devices::<u64>(&args).await,
+        ValueType::F32 => run_devices::<f32>(&args).await,
+        ValueType::F64 => run_devices::<f64>(&args).await,
+    }
+}
@@ -0,0 +1,498 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use log::debug;
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::{SendTimeoutError, TrySendError};
+use tokio::time::Instant;
+use tokio_stream::{Stream, StreamExt};
+use crate::producer::{Endian, ToBytes};
+
+pub mod mqtt;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum BrokerError {
+    #[error("invalid topic name: {0:?}")]
+    InvalidTopic(String),
+    #[error("invalid topic filter: {0:?}")]
+    InvalidFilter(String),
+}
+
+/// 服务质量, 不支持 QoS 2
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum QoS {
+    /// 最多一次, 订阅者队列满时丢弃
+    #[default]
+    AtMostOnce,
+    /// 至少一次, 订阅者确认前会被重发, 队列满时发布方等待, 超时后断开该订阅
+    AtLeastOnce,
+}
+
+impl QoS {
+    /// QoS 2 降级为 QoS 1
+    pub fn from_level(level: u8) -> Self {
+        if level == 0 { QoS::AtMostOnce } else { QoS::AtLeastOnce }
+    }
+
+    pub fn level(&self) -> u8 {
+        match self {
+            QoS::AtMostOnce => 0,
+            QoS::AtLeastOnce => 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    /// 在 broker 内唯一, 用于确认 QoS 1 消息
+    pub id: u64,
+    pub topic: String,
+    pub payload: Vec<u8>,
+    /// 发布 QoS 与订阅 QoS 中较小的一个
+    pub qos: QoS,
+    /// 重发的消息
+    pub dup: bool,
+}
+
+/// 主题名按 `/` 分层, 不能为空, 不能包含通配符
+pub fn validate_topic(topic: &str) -> Result<(), BrokerError> {
+    if topic.is_empty() || topic.contains(['+', '#', '\0']) {
+        return Err(BrokerError::InvalidTopic(topic.to_string()));
+    }
+    Ok(())
+}
+
+/// `+` 匹配一层, `#` 匹配剩余所有层(包括父层本身), 只能出现在最后
+pub fn validate_filter(filter: &str) -> Result<(), BrokerError> {
+    let invalid = || BrokerError::InvalidFilter(filter.to_string());
+    if filter.is_empty() || filter.contains('\0') {
+        return Err(invalid());
+    }
+    let levels: Vec<&str> = filter.split('/').collect();
+    for (i, level) in levels.iter().enumerate() {
+        let wildcard_misused = (level.contains('+') && *level != "+")
+            || (level.contains('#') && (*level != "#" || i != levels.len() - 1));
+        if wildcard_misused {
+            return Err(invalid());
+        }
+    }
+    Ok(())
+}
+
+/// 主题是否匹配过滤器, `$` 开头的主题不会被首层通配符匹配
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut topic_levels = topic.split('/');
+    for level in filter.split('/') {
+        match level {
+            "#" => return true,
+            "+" => {
+                if topic_levels.next().is_none() {
+                    return false;
+                }
+            }
+            _ => {
+                if topic_levels.next() != Some(level) {
+                    return false;
+                }
+            }
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+
+struct Subscriber {
+    filter: String,
+    qos: QoS,
+    sender: mpsc::Sender<Message>,
+}
+
+/// broker 运行统计
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BrokerStats {
+    pub published: u64,
+    /// 放入订阅者队列的消息数
+    pub delivered: u64,
+    /// 订阅者队列满而被丢弃的 QoS 0 消息数
+    pub dropped: u64,
+    /// 队列满且超过 `send_timeout` 没有被读取而断开的订阅数
+    pub disconnected: u64,
+}
+
+#[derive(Default)]
+struct BrokerState {
+    subscribers: Vec<Subscriber>,
+    next_message_id: u64,
+    stats: BrokerStats,
+}
+
+/// 一次发布的结果
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PublishReport {
+    /// 匹配的订阅数
+    pub matched: usize,
+    /// 队列满被丢弃的订阅数
+    pub dropped: usize,
+    /// QoS 1 消息等待超时而被断开的订阅数
+    pub disconnected: usize,
+}
+
+/// QoS 1 消息在订阅者队列满时默认最多等待的时间
+pub const DEFAULT_SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 进程内的主题 broker, 克隆后共享同一组订阅
+///
+/// 每个订阅有自己的有界队列. QoS 0 消息在队列满时丢弃; QoS 1 消息在队列满时
+/// 让发布方最多等待 `send_timeout`, 超时后断开该订阅, 避免没人读取的订阅拖住发布方.
+/// 订阅者 `ack` 之前会按 `redeliver_after` 重发.
+#[derive(Clone)]
+pub struct Broker {
+    state: Arc<Mutex<BrokerState>>,
+    send_timeout: Duration,
+}
+
+impl Default for Broker {
+    fn default() -> Self {
+        Self {
+            state: Arc::default(),
+            send_timeout: DEFAULT_SEND_TIMEOUT,
+        }
+    }
+}
+
+impl Broker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 只影响通过这个 `Broker` 发布的消息, 克隆出的 `Broker` 沿用当时的设置
+    pub fn with_send_timeout(mut self, timeout: Duration) -> Self {
+        self.send_timeout = timeout;
+        self
+    }
+
+    /// 订阅匹配 `filter` 的主题, 队列最多缓存 `capacity` 条消息, 丢弃 `Subscription` 即退订
+    pub fn subscribe(&self, filter: &str, qos: QoS, capacity: usize) -> Result<Subscription, BrokerError> {
+        validate_filter(filter)?;
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        self.state.lock().unwrap().subscribers.push(Subscriber {
+            filter: filter.to_string(),
+            qos,
+            sender,
+        });
+        Ok(Subscription {
+            filter: filter.to_string(),
+            receiver,
+            acker: Acker::default(),
+            redeliver_after: Duration::from_secs(5),
+            closed: false,
+        })
+    }
+
+    pub async fn publish(&self, topic: &str, payload: Vec<u8>, qos: QoS) -> Result<PublishReport, BrokerError> {
+        validate_topic(topic)?;
+        let (id, targets) = {
+            let mut state = self.state.lock().unwrap();
+            state.subscribers.retain(|sub| !sub.sender.is_closed());
+            state.stats.published += 1;
+            state.next_message_id += 1;
+            let targets: Vec<(mpsc::Sender<Message>, QoS)> = state
+                .subscribers
+                .iter()
+                .filter(|sub| topic_matches(&sub.filter, topic))
+                .map(|sub| (sub.sender.clone(), sub.qos.min(qos)))
+                .collect();
+            (state.next_message_id, targets)
+        };
+
+        let mut report = PublishReport { matched: targets.len(), ..Default::default() };
+        let mut delivered = 0;
+        let mut slow = Vec::new();
+        for (sender, qos) in targets {
+            let message = Message { id, topic: topic.to_string(), payload: payload.clone(), qos, dup: false };
+            let sent = match qos {
+                QoS::AtMostOnce => match sender.try_send(message) {
+                    Ok(()) => true,
+                    Err(TrySendError::Full(_)) => {
+                        report.dropped += 1;
+                        false
+                    }
+                    Err(TrySendError::Closed(_)) => false,
+                },
+                QoS::AtLeastOnce => match sender.send_timeout(message, self.send_timeout).await {
+                    Ok(()) => true,
+                    Err(SendTimeoutError::Timeout(_)) => {
+                        debug!("subscriber queue full for {:?}, disconnecting", self.send_timeout);
+                        slow.push(sender);
+                        false
+                    }
+                    Err(SendTimeoutError::Closed(_)) => false,
+                },
+            };
+            delivered += sent as u64;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        // 丢弃 broker 持有的发送端, 订阅者读完队列后数据流结束
+        state.subscribers.retain(|sub| !slow.iter().any(|sender| sender.same_channel(&sub.sender)));
+        report.disconnected = slow.len();
+        state.stats.delivered += delivered;
+        state.stats.dropped += report.dropped as u64;
+        state.stats.disconnected += report.disconnected as u64;
+        Ok(report)
+    }
+
+    /// 把数据流中的每个数据编码后发布到 `topic`, 返回发布的消息数
+    pub async fn publish_stream<S, T>(&self, topic: &str, qos: QoS, endian: Endian, stream: S) -> Result<u64, BrokerError>
+    where
+        S: Stream<Item = T>,
+        T: ToBytes,
+    {
+        validate_topic(topic)?;
+        tokio::pin!(stream);
+        let mut count = 0;
+        while let Some(value) = stream.next().await {
+            self.publish(topic, value.to_bytes(endian), qos).await?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    pub fn stats(&self) -> BrokerStats {
+        self.state.lock().unwrap().stats
+    }
+}
+
+
+/// 确认 QoS 1 消息, 可以克隆后交给其他任务
+#[derive(Clone, Default)]
+pub struct Acker {
+    inflight: Arc<Mutex<BTreeMap<u64, (Message, Instant)>>>,
+}
+
+impl Acker {
+    /// 返回消息是否在等待确认
+    pub fn ack(&self, id: u64) -> bool {
+        self.inflight.lock().unwrap().remove(&id).is_some()
+    }
+}
+
+pub struct Subscription {
+    filter: String,
+    receiver: mpsc::Receiver<Message>,
+    acker: Acker,
+    redeliver_after: Duration,
+    /// broker 已经关闭, 只剩未确认的消息需要重发
+    closed: bool,
+}
+
+impl Subscription {
+    /// 未确认的 QoS 1 消息经过该时间后重发, 默认 5 秒
+    pub fn with_redelivery(mut self, after: Duration) -> Self {
+        self.redeliver_after = after;
+        self
+    }
+
+    pub fn filter(&self) -> &str {
+        &self.filter
+    }
+
+    pub fn acker(&self) -> Acker {
+        self.acker.clone()
+    }
+
+    pub fn ack(&self, id: u64) -> bool {
+        self.acker.ack(id)
+    }
+
+    /// 等待待下一条消息, 优先返回确认超时的重发消息;
+    /// broker 已经关闭并且没有未确认的消息时返回 `None`
+    pub async fn recv(&mut self) -> Option<Message> {
+        loop {
+            let next_redelivery = {
+                let mut inflight = self.acker.inflight.lock().unwrap();
+                let now = Instant::now();
+                let expired = inflight.values_mut().find(|(_, sent)| now >= *sent + self.redeliver_after);
+                if let Some((message, sent)) = expired {
+                    *sent = now;
+                    debug!("redelivering message {} on {}", message.id, message.topic);
+                    return Some(Message { dup: true, ..message.clone() });
+                }
+                inflight.values().map(|(_, sent)| *sent + self.redeliver_after).min()
+            };
+
+            let received = match (next_redelivery, self.closed) {
+                (None, true) => return None,
+                (Some(deadline), true) => {
+                    tokio::time::sleep_until(deadline).await;
+                    continue;
+                }
+                (Some(deadline), false) => tokio::select! {
+                    message = self.receiver.recv() => message,
+                    _ = tokio::time::sleep_until(deadline) => continue,
+                },
+                (None, false) => self.receiver.recv().await,
+            };
+            let Some(message) = received else {
+                self.closed = true;
+                continue;
+            };
+            if message.qos == QoS::AtLeastOnce {
+                self.acker.inflight.lock().unwrap().insert(message.id, (message.clone(), Instant::now()));
+            }
+            return Some(message);
+        }
+    }
+
+    /// 转换为解码后的数据流, 收到即确认, 长度不对的消息被跳过
+    pub fn into_values<T: ToBytes>(self, endian: Endian) -> impl Stream<Item = T> {
+        futures::stream::unfold(self, move |mut sub| async move {
+            loop {
+                let message = sub.recv().await?;
+                sub.ack(message.id);
+                if message.payload.len() == T::SIZE {
+                    return Some((T::from_bytes(&message.payload, endian), sub));
+                }
+                debug!("skipped payload of {} bytes on {}", message.payload.len(), message.topic);
+            }
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::aggregate::Aggregation;
+    use crate::collector::{CollectorBuilder, StopReason};
+    use super::*;
+
+    #[test]
+    fn test_topic_matching() {
+        assert!(topic_matches("site/+/temp", "site/kitchen/temp"));
+        assert!(!topic_matches("site/+/temp", "site/kitchen/oven/temp"));
+        assert!(topic_matches("site/#", "site"));
+        assert!(topic_matches("site/#", "site/a/b"));
+        assert!(topic_matches("+/+", "site/a"));
+        assert!(!topic_matches("+", "site/a"));
+        assert!(!topic_matches("#", "$SYS/uptime"));
+        assert!(topic_matches("$SYS/#", "$SYS/uptime"));
+
+        assert!(validate_filter("a/+/#").is_ok());
+        assert!(validate_filter("a/#/b").is_err());
+        assert!(validate_filter("a/b+").is_err());
+        assert!(validate_topic("a/+").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_qos0_drops_when_queue_full() {
+        let broker = Broker::new();
+        let mut sub = broker.subscribe("site/#", QoS::AtMostOnce, 1).unwrap();
+        let other = broker.subscribe("other/#", QoS::AtMostOnce, 1).unwrap();
+        broker.publish("site/a", vec![1], QoS::AtLeastOnce).await.unwrap();
+        let report = broker.publish("site/b", vec![2], QoS::AtMostOnce).await.unwrap();
+        assert_eq!(report, PublishReport { matched: 1, dropped: 1, disconnected: 0 });
+
+        // 订阅 QoS 0, 消息按 QoS 0 投递
+        let message = sub.recv().await.unwrap();
+        assert_eq!((message.topic.as_str(), message.qos), ("site/a", QoS::AtMostOnce));
+        assert_eq!(broker.stats(), BrokerStats { published: 2, delivered: 1, dropped: 1, disconnected: 0 });
+
+        // 退订后不再匹配
+        drop(other);
+        let report = broker.publish("other/x", vec![3], QoS::AtMostOnce).await.unwrap();
+        assert_eq!(report.matched, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_qos1_redelivers_until_acked() {
+        let broker = Broker::new();
+        let mut sub = broker
+            .subscribe("dev/+", QoS::AtLeastOnce, 4)
+            .unwrap()
+            .with_redelivery(Duration::from_secs(1));
+        broker.publish("dev/1", vec![7], QoS::AtLeastOnce).await.unwrap();
+
+        let first = sub.recv().await.unwrap();
+        assert!(!first.dup);
+        let again = sub.recv().await.unwrap();
+        assert_eq!((again.id, again.dup), (first.id, true));
+        assert!(sub.ack(first.id));
+        assert!(!sub.ack(first.id));
+
+        // 确认后 broker 关闭, 数据流结束
+        drop(broker);
+        assert_eq!(sub.recv().await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_qos1_disconnects_slow_subscriber() {
+        let broker = Broker::new().with_send_timeout(Duration::from_secs(1));
+        let mut slow = broker.subscribe("dev/+", QoS::AtLeastOnce, 1).unwrap();
+        let mut fast = broker.subscribe("dev/+", QoS::AtLeastOnce, 4).unwrap();
+        broker.publish("dev/1", vec![1], QoS::AtLeastOnce).await.unwrap();
+
+        // 没人读取的订阅不会让发布方一直等待
+        let report = broker.publish("dev/1", vec![2], QoS::AtLeastOnce).await.unwrap();
+        assert_eq!(report, PublishReport { matched: 2, dropped: 0, disconnected: 1 });
+        let report = broker.publish("dev/1", vec![3], QoS::AtLeastOnce).await.unwrap();
+        assert_eq!((report.matched, report.disconnected), (1, 0));
+        assert_eq!(broker.stats().disconnected, 1);
+
+        let payloads = |sub: &mut Subscription| {
+            let mut payloads = Vec::new();
+            while let Ok(message) = sub.receiver.try_recv() {
+                payloads.push(message.payload[0]);
+            }
+            payloads
+        };
+        assert_eq!(payloads(&mut fast), vec![1, 2, 3]);
+        // 断开前已经入队的消息仍然可以读取, 之后数据流结束
+        assert_eq!(payloads(&mut slow), vec![1]);
+        assert!(slow.receiver.is_closed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_qos1_redelivers_after_broker_closed() {
+        let broker = Broker::new();
+        let mut sub = broker
+            .subscribe("dev/+", QoS::AtLeastOnce, 4)
+            .unwrap()
+            .with_redelivery(Duration::from_secs(1));
+        broker.publish("dev/1", vec![7], QoS::AtLeastOnce).await.unwrap();
+        drop(broker);
+
+        // 未确认的消息在 broker 关闭后仍然重发, 确认后才结束
+        let first = sub.recv().await.unwrap();
+        let again = sub.recv().await.unwrap();
+        assert_eq!((again.id, again.dup), (first.id, true));
+        assert!(sub.ack(first.id));
+        assert_eq!(sub.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_publish_stream_to_collector() {
+        let broker = Broker::new();
+        let sub = broker.subscribe("site/+/temp", QoS::AtLeastOnce, 16).unwrap();
+        let values = sub.into_values::<i32>(Endian::Little);
+
+        let publisher = broker.clone();
+        tokio::spawn(async move {
+            publisher
+                .publish_stream("site/lab/temp", QoS::AtLeastOnce, Endian::Little, tokio_stream::iter(vec![3i32, 4, 5]))
+                .await
+                .unwrap();
+        });
+
+        let result = CollectorBuilder::new()
+            .aggregate(Aggregation::Sum)
+            .max_items(3)
+            .collect(values)
+            .await;
+        assert_eq!(result.stop_reason, StopReason::MaxItems);
+        assert_eq!(result.aggregates.sum, Some(12.0));
+    }
+}
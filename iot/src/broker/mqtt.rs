@@ -0,0 +1,563 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use log::{debug, info, warn};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use crate::broker::{validate_topic, Acker, Broker, QoS};
+use crate::shutdown::Shutdown;
+
+/// MQTT 3.1.1 的一个子集:
+/// - 支持 CONNECT, PUBLISH(QoS 0/1), PUBACK, SUBSCRIBE, UNSUBSCRIBE, PINGREQ, DISCONNECT
+/// - QoS 2 的订阅降级为 QoS 1, QoS 2 的发布视为协议错误
+/// - 不支持遗嘱, 保留消息, 持久会话和认证, keep alive 不做检查
+const PROTOCOL_LEVEL: u8 = 4;
+/// 单个报文的最大长度
+const MAX_PACKET_SIZE: usize = 1 << 20;
+
+#[derive(Error, Debug)]
+pub enum MqttError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("protocol error: {0}")]
+    Protocol(String),
+}
+
+fn protocol_error<T>(msg: impl Into<String>) -> Result<T, MqttError> {
+    Err(MqttError::Protocol(msg.into()))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Packet {
+    Connect { client_id: String, keep_alive: u16, clean_session: bool },
+    ConnAck { session_present: bool, code: u8 },
+    Publish { dup: bool, qos: u8, retain: bool, topic: String, packet_id: Option<u16>, payload: Vec<u8> },
+    PubAck { packet_id: u16 },
+    /// 过滤器和请求的 QoS
+    Subscribe { packet_id: u16, filters: Vec<(String, u8)> },
+    /// 每个过滤器授予的 QoS, 失败为 `0x80`
+    SubAck { packet_id: u16, codes: Vec<u8> },
+    Unsubscribe { packet_id: u16, filters: Vec<String> },
+    UnsubAck { packet_id: u16 },
+    PingReq,
+    PingResp,
+    Disconnect,
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend((s.len() as u16).to_be_bytes());
+    buf.extend(s.as_bytes());
+}
+
+/// 报文体的读取游标
+struct Body<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Body<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], MqttError> {
+        if self.bytes.len() < n {
+            return protocol_error("packet too short");
+        }
+        let (head, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, MqttError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, MqttError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn string(&mut self) -> Result<String, MqttError> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).or_else(|_| protocol_error("invalid utf-8 string"))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn rest(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.bytes).to_vec()
+    }
+}
+
+impl Packet {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        let header = match self {
+            Packet::Connect { client_id, keep_alive, clean_session } => {
+                put_str(&mut body, "MQTT");
+                body.push(PROTOCOL_LEVEL);
+                body.push(if *clean_session { 0x02 } else { 0 });
+                body.extend(keep_alive.to_be_bytes());
+                put_str(&mut body, client_id);
+                0x10
+            }
+            Packet::ConnAck { session_present, code } => {
+                body.push(*session_present as u8);
+                body.push(*code);
+                0x20
+            }
+            Packet::Publish { dup, qos, retain, topic, packet_id, payload } => {
+                put_str(&mut body, topic);
+                if let Some(id) = packet_id {
+                    body.extend(id.to_be_bytes());
+                }
+                body.extend(payload);
+                0x30 | (*dup as u8) << 3 | qos << 1 | *retain as u8
+            }
+            Packet::PubAck { packet_id } => {
+                body.extend(packet_id.to_be_bytes());
+                0x40
+            }
+            Packet::Subscribe { packet_id, filters } => {
+                body.extend(packet_id.to_be_bytes());
+                for (filter, qos) in filters {
+                    put_str(&mut body, filter);
+                    body.push(*qos);
+                }
+                0x82
+            }
+            Packet::SubAck { packet_id, codes } => {
+                body.extend(packet_id.to_be_bytes());
+                body.extend(codes);
+                0x90
+            }
+            Packet::Unsubscribe { packet_id, filters } => {
+                body.extend(packet_id.to_be_bytes());
+                for filter in filters {
+                    put_str(&mut body, filter);
+                }
+                0xa2
+            }
+            Packet::UnsubAck { packet_id } => {
+                body.extend(packet_id.to_be_bytes());
+                0xb0
+            }
+            Packet::PingReq => 0xc0,
+            Packet::PingResp => 0xd0,
+            Packet::Disconnect => 0xe0,
+        };
+
+        let mut frame = vec![header];
+        // 剩余长度: 每字节 7 位, 最高位表示后面还有字节
+        let mut len = body.len();
+        loop {
+            let mut byte = (len % 128) as u8;
+            len /= 128;
+            if len > 0 {
+                byte |= 0x80;
+            }
+            frame.push(byte);
+            if len == 0 {
+                break;
+            }
+        }
+        frame.extend(body);
+        frame
+    }
+
+    pub fn decode(header: u8, body: &[u8]) -> Result<Packet, MqttError> {
+        let mut body = Body { bytes: body };
+        let flags = header & 0x0f;
+        let packet = match header >> 4 {
+            1 => {
+                let protocol = body.string()?;
+                let level = body.u8()?;
+                if protocol != "MQTT" || level != PROTOCOL_LEVEL {
+                    return protocol_error(format!("unsupported protocol {} level {}", protocol, level));
+                }
+                let connect_flags = body.u8()?;
+                let keep_alive = body.u16()?;
+                let client_id = body.string()?;
+                // 遗嘱, 用户名和密码不支持, 直接忽略
+                Packet::Connect { client_id, keep_alive, clean_session: connect_flags & 0x02 != 0 }
+            }
+            2 => Packet::ConnAck { session_present: body.u8()? & 1 == 1, code: body.u8()? },
+            3 => {
+                let qos = (flags >> 1) & 0x03;
+                let topic = body.string()?;
+                let packet_id = if qos > 0 { Some(body.u16()?) } else { None };
+                Packet::Publish { dup: flags & 0x08 != 0, qos, retain: flags & 1 == 1, topic, packet_id, payload: body.rest() }
+            }
+            4 => Packet::PubAck { packet_id: body.u16()? },
+            8 => {
+                let packet_id = body.u16()?;
+                let mut filters = Vec::new();
+                while !body.is_empty() {
+                    filters.push((body.string()?, body.u8()?));
+                }
+                if filters.is_empty() {
+                    return protocol_error("subscribe without filters");
+                }
+                Packet::Subscribe { packet_id, filters }
+            }
+            9 => Packet::SubAck { packet_id: body.u16()?, codes: body.rest() },
+            10 => {
+                let packet_id = body.u16()?;
+                let mut filters = Vec::new();
+                while !body.is_empty() {
+                    filters.push(body.string()?);
+                }
+                Packet::Unsubscribe { packet_id, filters }
+            }
+            11 => Packet::UnsubAck { packet_id: body.u16()? },
+            12 => Packet::PingReq,
+            13 => Packet::PingResp,
+            14 => Packet::Disconnect,
+            kind => return protocol_error(format!("unsupported packet type {}", kind)),
+        };
+        Ok(packet)
+    }
+
+    /// 读取一个完整的报文, 连接在报文边界上关闭时返回 `None`
+    pub async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Packet>, MqttError> {
+        let mut header = [0u8; 1];
+        if reader.read(&mut header).await? == 0 {
+            return Ok(None);
+        }
+        let mut len = 0usize;
+        for i in 0..4 {
+            let byte = reader.read_u8().await?;
+            len |= ((byte & 0x7f) as usize) << (7 * i);
+            if byte & 0x80 == 0 {
+                break;
+            }
+            if i == 3 {
+                return protocol_error("malformed remaining length");
+            }
+        }
+        if len > MAX_PACKET_SIZE {
+            return protocol_error(format!("packet of {} bytes is too large", len));
+        }
+        let mut body = vec![0u8; len];
+        reader.read_exact(&mut body).await?;
+        Packet::decode(header[0], &body).map(Some)
+    }
+}
+
+
+/// 让设备通过 MQTT 连接 `Broker` 的 TCP 前端
+pub struct MqttServer {
+    broker: Broker,
+    /// 每个订阅的队列长度
+    queue_capacity: usize,
+}
+
+impl MqttServer {
+    pub fn new(broker: Broker) -> Self {
+        Self { broker, queue_capacity: 256 }
+    }
+
+    pub fn with_queue_capacity(mut self, capacity: usize) -> Self {
+        self.queue_capacity = capacity;
+        self
+    }
+
+    /// 接受连接直到停止信号触发, 已建立的连接在各自的任务中继续运行直到对端断开
+    pub async fn serve(self, listener: TcpListener, shutdown: Shutdown) -> std::io::Result<()> {
+        loop {
+            let (stream, addr) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                _ = shutdown.triggered() => return Ok(()),
+            };
+            debug!("mqtt client connected from {}", addr);
+            let broker = self.broker.clone();
+            let capacity = self.queue_capacity;
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(broker, stream, capacity).await {
+                    warn!("mqtt connection from {} closed: {}", addr, e);
+                }
+            });
+        }
+    }
+}
+
+/// 发给客户端的 QoS 1 消息, 客户端 PUBACK 后确认对应的订阅.
+/// 重发时沿用第一次发送的报文标识符, 以连接内的订阅序号和 broker 消息 id 查找
+#[derive(Default)]
+struct Outgoing {
+    next_packet_id: u16,
+    /// 报文标识符 -> (订阅序号, 订阅的确认句柄, broker 消息 id)
+    by_packet: HashMap<u16, (usize, Acker, u64)>,
+    by_message: HashMap<(usize, u64), u16>,
+}
+
+impl Outgoing {
+    /// 消息的报文标识符, 第一次发送时分配一个没有被占用的; 全部被占用时返回 `None`
+    fn packet_id(&mut self, sub: usize, acker: &Acker, message_id: u64) -> Option<u16> {
+        if let Some(packet_id) = self.by_message.get(&(sub, message_id)) {
+            return Some(*packet_id);
+        }
+        if self.by_packet.len() >= u16::MAX as usize {
+            return None;
+        }
+        loop {
+            // 报文标识符不能为 0
+            self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+            if !self.by_packet.contains_key(&self.next_packet_id) {
+                break;
+            }
+        }
+        let packet_id = self.next_packet_id;
+        self.by_packet.insert(packet_id, (sub, acker.clone(), message_id));
+        self.by_message.insert((sub, message_id), packet_id);
+        Some(packet_id)
+    }
+
+    fn ack(&mut self, packet_id: u16) {
+        if let Some((sub, acker, message_id)) = self.by_packet.remove(&packet_id) {
+            self.by_message.remove(&(sub, message_id));
+            acker.ack(message_id);
+        }
+    }
+
+    /// 退订后丢弃该订阅未确认的消息
+    fn remove_subscription(&mut self, sub: usize) {
+        self.by_packet.retain(|_, (s, _, _)| *s != sub);
+        self.by_message.retain(|(s, _), _| *s != sub);
+    }
+}
+
+async fn handle_connection(broker: Broker, stream: TcpStream, capacity: usize) -> Result<(), MqttError> {
+    let (mut reader, mut writer) = stream.into_split();
+    let client_id = match Packet::read(&mut reader).await? {
+        Some(Packet::Connect { client_id, .. }) => client_id,
+        Some(other) => return protocol_error(format!("expected CONNECT, got {:?}", other)),
+        None => return Ok(()),
+    };
+    info!("mqtt client {:?} connected", client_id);
+
+    let (out_tx, mut out_rx) = mpsc::channel::<Packet>(64);
+    let writer_task = tokio::spawn(async move {
+        while let Some(packet) = out_rx.recv().await {
+            if writer.write_all(&packet.encode()).await.is_err() {
+                break;
+            }
+        }
+    });
+    let _ = out_tx.send(Packet::ConnAck { session_present: false, code: 0 }).await;
+
+    let outgoing: Arc<Mutex<Outgoing>> = Arc::default();
+    let mut next_sub = 0;
+    let mut forwarders: Vec<(String, usize, JoinHandle<()>)> = Vec::new();
+
+    let result = loop {
+        let packet = match Packet::read(&mut reader).await {
+            Ok(Some(packet)) => packet,
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        };
+        match packet {
+            Packet::Publish { qos, topic, packet_id, payload, .. } => {
+                if qos > 1 {
+                    break protocol_error("QoS 2 is not supported");
+                }
+                if let Err(e) = validate_topic(&topic) {
+                    break protocol_error(e.to_string());
+                }
+                let _ = broker.publish(&topic, payload, QoS::from_level(qos)).await;
+                if let Some(packet_id) = packet_id {
+                    let _ = out_tx.send(Packet::PubAck { packet_id }).await;
+                }
+            }
+            Packet::PubAck { packet_id } => {
+                outgoing.lock().unwrap().ack(packet_id);
+            }
+            Packet::Subscribe { packet_id, filters } => {
+                let mut codes = Vec::with_capacity(filters.len());
+                for (filter, qos) in filters {
+                    match broker.subscribe(&filter, QoS::from_level(qos), capacity) {
+                        Ok(mut sub) => {
+                            let out_tx = out_tx.clone();
+                            let outgoing = outgoing.clone();
+                            let sub_index = next_sub;
+                            next_sub += 1;
+                            let task = tokio::spawn(async move {
+                                while let Some(message) = sub.recv().await {
+                                    let packet_id = match message.qos {
+                                        QoS::AtMostOnce => None,
+                                        QoS::AtLeastOnce => {
+                                            let packet_id = outgoing.lock().unwrap().packet_id(sub_index, &sub.acker(), message.id);
+                                            if packet_id.is_none() {
+                                                // 确认超时后会重发
+                                                warn!("no free packet id, delaying message {}", message.id);
+                                                continue;
+                                            }
+                                            packet_id
+                                        }
+                                    };
+                                    let packet = Packet::Publish {
+                                        dup: message.dup,
+                                        qos: message.qos.level(),
+                                        retain: false,
+                                        topic: message.topic,
+                                        packet_id,
+                                        payload: message.payload,
+                                    };
+                                    if out_tx.send(packet).await.is_err() {
+                                        break;
+                                    }
+                                }
+                            });
+                            codes.push(qos.min(1));
+                            forwarders.push((filter, sub_index, task));
+                        }
+                        Err(e) => {
+                            debug!("rejected subscription: {}", e);
+                            codes.push(0x80);
+                        }
+                    }
+                }
+                let _ = out_tx.send(Packet::SubAck { packet_id, codes }).await;
+            }
+            Packet::Unsubscribe { packet_id, filters } => {
+                forwarders.retain(|(filter, sub_index, task)| {
+                    let keep = !filters.contains(filter);
+                    if !keep {
+                        task.abort();
+                        outgoing.lock().unwrap().remove_subscription(*sub_index);
+                    }
+                    keep
+                });
+                let _ = out_tx.send(Packet::UnsubAck { packet_id }).await;
+            }
+            Packet::PingReq => {
+                let _ = out_tx.send(Packet::PingResp).await;
+            }
+            Packet::Disconnect => break Ok(()),
+            other => break protocol_error(format!("unexpected packet {:?}", other)),
+        }
+    };
+
+    for (_, _, task) in forwarders {
+        task.abort();
+    }
+    drop(out_tx);
+    let _ = writer_task.await;
+    info!("mqtt client {:?} disconnected", client_id);
+    result
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use super::*;
+
+    async fn connect(addr: std::net::SocketAddr, client_id: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let connect = Packet::Connect { client_id: client_id.to_string(), keep_alive: 30, clean_session: true };
+        stream.write_all(&connect.encode()).await.unwrap();
+        assert_eq!(
+            Packet::read(&mut stream).await.unwrap(),
+            Some(Packet::ConnAck { session_present: false, code: 0 })
+        );
+        stream
+    }
+
+    #[test]
+    fn test_outgoing_reuses_packet_id() {
+        let mut outgoing = Outgoing::default();
+        let acker = Acker::default();
+        let first = outgoing.packet_id(0, &acker, 10).unwrap();
+        // 重发沿用同一个报文标识符, 另一个订阅收到的同一条消息使用新的
+        assert_eq!(outgoing.packet_id(0, &acker, 10), Some(first));
+        let other = outgoing.packet_id(1, &acker, 10).unwrap();
+        assert_ne!(other, first);
+
+        outgoing.ack(first);
+        assert_eq!((outgoing.by_packet.len(), outgoing.by_message.len()), (1, 1));
+        outgoing.remove_subscription(1);
+        assert!(outgoing.by_packet.is_empty() && outgoing.by_message.is_empty());
+
+        // 回绕后跳过仍被占用的标识符
+        outgoing.next_packet_id = u16::MAX - 1;
+        assert_eq!(outgoing.packet_id(0, &acker, 20), Some(u16::MAX));
+        outgoing.next_packet_id = 0;
+        outgoing.packet_id(0, &acker, 21);
+        outgoing.next_packet_id = u16::MAX - 1;
+        assert_eq!(outgoing.packet_id(0, &acker, 22), Some(2));
+    }
+
+    #[test]
+    fn test_packet_round_trip() {
+        let packets = vec![
+            Packet::Publish {
+                dup: true,
+                qos: 1,
+                retain: false,
+                topic: "site/a".to_string(),
+                packet_id: Some(9),
+                payload: vec![0; 200],
+            },
+            Packet::Subscribe { packet_id: 1, filters: vec![("a/#".to_string(), 1), ("b/+".to_string(), 0)] },
+            Packet::SubAck { packet_id: 1, codes: vec![1, 0x80] },
+            Packet::PingReq,
+        ];
+        for packet in packets {
+            let frame = packet.encode();
+            // 200 字节的报文体需要两个字节的剩余长度
+            let body_start = if frame[1] & 0x80 != 0 { 3 } else { 2 };
+            assert_eq!(Packet::decode(frame[0], &frame[body_start..]).unwrap(), packet);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mqtt_publish_subscribe() {
+        let broker = Broker::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = Shutdown::new();
+        let server = tokio::spawn(MqttServer::new(broker.clone()).serve(listener, shutdown.clone()));
+
+        let mut subscriber = connect(addr, "dashboard").await;
+        let subscribe = Packet::Subscribe {
+            packet_id: 1,
+            filters: vec![("site/+/temp".to_string(), 2), ("bad/#/x".to_string(), 0)],
+        };
+        subscriber.write_all(&subscribe.encode()).await.unwrap();
+        assert_eq!(
+            Packet::read(&mut subscriber).await.unwrap(),
+            Some(Packet::SubAck { packet_id: 1, codes: vec![1, 0x80] })
+        );
+
+        let mut device = connect(addr, "sensor-1").await;
+        let publish = Packet::Publish {
+            dup: false,
+            qos: 1,
+            retain: false,
+            topic: "site/lab/temp".to_string(),
+            packet_id: Some(42),
+            payload: 21.5f32.to_le_bytes().to_vec(),
+        };
+        device.write_all(&publish.encode()).await.unwrap();
+        assert_eq!(Packet::read(&mut device).await.unwrap(), Some(Packet::PubAck { packet_id: 42 }));
+
+        let delivered = tokio::time::timeout(Duration::from_secs(5), Packet::read(&mut subscriber))
+            .await
+            .unwrap()
+            .unwrap();
+        let Some(Packet::Publish { qos: 1, topic, packet_id: Some(packet_id), payload, .. }) = delivered else {
+            panic!("unexpected packet {:?}", delivered);
+        };
+        assert_eq!(topic, "site/lab/temp");
+        assert_eq!(payload, 21.5f32.to_le_bytes());
+        subscriber.write_all(&Packet::PubAck { packet_id }.encode()).await.unwrap();
+
+        subscriber.write_all(&Packet::PingReq.encode()).await.unwrap();
+        assert_eq!(Packet::read(&mut subscriber).await.unwrap(), Some(Packet::PingResp));
+        assert_eq!(broker.stats().delivered, 1);
+
+        shutdown.trigger();
+        server.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,404 @@
+use thiserror::Error;
+use crate::producer::ToBytes;
+use crate::reading::Reading;
+
+/// 块格式: `format: u8` + `value_size: u8` + `count: u32`(小端) + 位流
+///
+/// 位流中每个数据点依次为(仅 `FORMAT_READINGS`)设备号, 时间戳, 数值:
+/// - 设备号: 第一个点 32 位; 之后与上一个相同为 `0`, 否则 `1` + 32 位
+/// - 时间戳: 第一个点 64 位; 之后编码二阶差分 `dod`, 为 0 时 `0`, 否则按范围
+///   `10` + 7 位, `110` + 9 位, `1110` + 12 位, `1111` + 64 位
+/// - 数值: Gorilla XOR, 第一个点 64 位; 之后与上一个值异或为 0 时 `0`, 否则 `1` 加
+///   `0` + 沿用上一个有效位窗口的有效位, 或 `1` + 前导零个数(5 位) + 有效位长度
+///   (6 位, 0 表示 64) + 有效位
+pub const BLOCK_HEADER_LEN: usize = 6;
+/// `(timestamp, value)` 数据点
+pub const FORMAT_POINTS: u8 = 1;
+/// `Reading`
+pub const FORMAT_READINGS: u8 = 2;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum CodecError {
+    #[error("block truncated")]
+    Truncated,
+    #[error("corrupt block")]
+    Corrupt,
+    #[error("unsupported block format {0}")]
+    UnsupportedFormat(u8),
+    #[error("value size mismatch: expected {expected}, found {found}")]
+    ValueSize { expected: usize, found: usize },
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// 最后一个字节中已经使用的位数, 0 表示需要新的字节
+    used: u32,
+}
+
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.used == 0 {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().expect("pushed above") |= 0x80 >> self.used;
+        }
+        self.used = (self.used + 1) % 8;
+    }
+
+    /// 从高到低写入 `value` 的低 `n` 位
+    fn write_bits(&mut self, value: u64, n: u32) {
+        for i in (0..n).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn read_bit(&mut self) -> Result<bool, CodecError> {
+        let byte = self.data.get(self.pos / 8).ok_or(CodecError::Truncated)?;
+        let bit = byte & (0x80 >> (self.pos % 8)) != 0;
+        self.pos += 1;
+        Ok(bit)
+    }
+
+    fn read_bits(&mut self, n: u32) -> Result<u64, CodecError> {
+        let mut value = 0;
+        for _ in 0..n {
+            value = (value << 1) | self.read_bit()? as u64;
+        }
+        Ok(value)
+    }
+}
+
+/// 二阶差分的编码范围: 前缀长度, 位数, 偏移
+const DOD_RANGES: [(u32, u32, i64); 3] = [(2, 7, 63), (3, 9, 255), (4, 12, 2047)];
+
+#[derive(Default)]
+struct DeltaOfDelta {
+    prev: Option<i64>,
+    delta: i64,
+}
+
+impl DeltaOfDelta {
+    fn encode(&mut self, writer: &mut BitWriter, timestamp: i64) {
+        let Some(prev) = self.prev.replace(timestamp) else {
+            writer.write_bits(timestamp as u64, 64);
+            return;
+        };
+        let delta = timestamp.wrapping_sub(prev);
+        let dod = delta.wrapping_sub(self.delta);
+        self.delta = delta;
+        if dod == 0 {
+            writer.write_bit(false);
+            return;
+        }
+        for (prefix, bits, bias) in DOD_RANGES {
+            if (-bias..=bias + 1).contains(&dod) {
+                // 前缀为 prefix - 1 个 1 加一个 0
+                writer.write_bits((1 << prefix) - 2, prefix);
+                writer.write_bits((dod + bias) as u64, bits);
+                return;
+            }
+        }
+        writer.write_bits(0b1111, 4);
+        writer.write_bits(dod as u64, 64);
+    }
+
+    fn decode(&mut self, reader: &mut BitReader) -> Result<i64, CodecError> {
+        let Some(prev) = self.prev else {
+            let timestamp = reader.read_bits(64)? as i64;
+            self.prev = Some(timestamp);
+            return Ok(timestamp);
+        };
+        let mut dod = None;
+        if reader.read_bit()? {
+            for (_, bits, bias) in DOD_RANGES {
+                if !reader.read_bit()? {
+                    dod = Some(reader.read_bits(bits)? as i64 - bias);
+                    break;
+                }
+            }
+            if dod.is_none() {
+                dod = Some(reader.read_bits(64)? as i64);
+            }
+        }
+        self.delta = self.delta.wrapping_add(dod.unwrap_or(0));
+        let timestamp = prev.wrapping_add(self.delta);
+        self.prev = Some(timestamp);
+        Ok(timestamp)
+    }
+}
+
+#[derive(Default)]
+struct Xor {
+    prev: Option<u64>,
+    /// 上一个有效位窗口的前导零和末尾零个数
+    window: Option<(u32, u32)>,
+}
+
+impl Xor {
+    fn encode(&mut self, writer: &mut BitWriter, bits: u64) {
+        let Some(prev) = self.prev.replace(bits) else {
+            writer.write_bits(bits, 64);
+            return;
+        };
+        let xor = bits ^ prev;
+        if xor == 0 {
+            writer.write_bit(false);
+            return;
+        }
+        writer.write_bit(true);
+        let leading = xor.leading_zeros().min(31);
+        let trailing = xor.trailing_zeros();
+        match self.window {
+            Some((prev_leading, prev_trailing)) if leading >= prev_leading && trailing >= prev_trailing => {
+                writer.write_bit(false);
+                writer.write_bits(xor >> prev_trailing, 64 - prev_leading - prev_trailing);
+            }
+            _ => {
+                let significant = 64 - leading - trailing;
+                writer.write_bit(true);
+                writer.write_bits(leading as u64, 5);
+                writer.write_bits(significant as u64 & 0x3f, 6);
+                writer.write_bits(xor >> trailing, significant);
+                self.window = Some((leading, trailing));
+            }
+        }
+    }
+
+    fn decode(&mut self, reader: &mut BitReader) -> Result<u64, CodecError> {
+        let Some(prev) = self.prev else {
+            let bits = reader.read_bits(64)?;
+            self.prev = Some(bits);
+            return Ok(bits);
+        };
+        if !reader.read_bit()? {
+            return Ok(prev);
+        }
+        let (leading, trailing) = match (reader.read_bit()?, self.window) {
+            (false, Some(window)) => window,
+            (false, None) => return Err(CodecError::Corrupt),
+            (true, _) => {
+                let leading = reader.read_bits(5)? as u32;
+                let significant = match reader.read_bits(6)? as u32 {
+                    0 => 64,
+                    n => n,
+                };
+                let trailing = 64u32.checked_sub(leading + significant).ok_or(CodecError::Corrupt)?;
+                self.window = Some((leading, trailing));
+                (leading, trailing)
+            }
+        };
+        let bits = prev ^ (reader.read_bits(64 - leading - trailing)? << trailing);
+        self.prev = Some(bits);
+        Ok(bits)
+    }
+}
+
+/// 不超过 8 字节的数值按小端补零为 `u64` 做异或
+fn to_bits<T: ToBytes>(value: &T) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes[..T::SIZE].copy_from_slice(&value.to_le_bytes());
+    u64::from_le_bytes(bytes)
+}
+
+fn from_bits<T: ToBytes>(bits: u64) -> T {
+    T::from_le_bytes(&bits.to_le_bytes()[..T::SIZE])
+}
+
+/// 按 `(timestamp, value)` 逐个编码一块数据点, 数值类型不超过 8 字节
+pub struct BlockEncoder<T> {
+    writer: BitWriter,
+    count: u32,
+    timestamps: DeltaOfDelta,
+    values: Xor,
+    _marker: std::marker::PhantomData<T>,
+}
+
+impl<T: ToBytes> Default for BlockEncoder<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: ToBytes> BlockEncoder<T> {
+    pub fn new() -> Self {
+        assert!(T::SIZE <= 8, "values wider than 8 bytes are not supported");
+        Self {
+            writer: BitWriter::default(),
+            count: 0,
+            timestamps: DeltaOfDelta::default(),
+            values: Xor::default(),
+            _marker: std::marker::PhantomData,
+        }
+    }
+
+    pub fn push(&mut self, timestamp: i64, value: &T) {
+        self.timestamps.encode(&mut self.writer, timestamp);
+        self.values.encode(&mut self.writer, to_bits(value));
+        self.count += 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.count as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// 当前编码后的字节数(不含块头)
+    pub fn encoded_len(&self) -> usize {
+        self.writer.bytes.len()
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        with_header(FORMAT_POINTS, T::SIZE, self.count, self.writer)
+    }
+}
+
+fn with_header(format: u8, value_size: usize, count: u32, writer: BitWriter) -> Vec<u8> {
+    let mut block = Vec::with_capacity(BLOCK_HEADER_LEN + writer.bytes.len());
+    block.push(format);
+    block.push(value_size as u8);
+    block.extend_from_slice(&count.to_le_bytes());
+    block.extend(writer.bytes);
+    block
+}
+
+/// 校验块头, 返回数据点个数和位流
+fn read_header(block: &[u8], format: u8, value_size: usize) -> Result<(usize, BitReader<'_>), CodecError> {
+    let header = block.get(..BLOCK_HEADER_LEN).ok_or(CodecError::Truncated)?;
+    if header[0] != format {
+        return Err(CodecError::UnsupportedFormat(header[0]));
+    }
+    if header[1] as usize != value_size {
+        return Err(CodecError::ValueSize { expected: value_size, found: header[1] as usize });
+    }
+    let count = u32::from_le_bytes(header[2..].try_into().expect("4 bytes")) as usize;
+    Ok((count, BitReader { data: &block[BLOCK_HEADER_LEN..], pos: 0 }))
+}
+
+pub fn encode_block<T: ToBytes>(points: &[(i64, T)]) -> Vec<u8> {
+    let mut encoder = BlockEncoder::new();
+    for (timestamp, value) in points {
+        encoder.push(*timestamp, value);
+    }
+    encoder.finish()
+}
+
+pub fn decode_block<T: ToBytes>(block: &[u8]) -> Result<Vec<(i64, T)>, CodecError> {
+    let (count, mut reader) = read_header(block, FORMAT_POINTS, T::SIZE)?;
+    let mut timestamps = DeltaOfDelta::default();
+    let mut values = Xor::default();
+    // 数量来自线路, 不按它预先分配
+    let mut points = Vec::with_capacity(count.min(block.len()));
+    for _ in 0..count {
+        let timestamp = timestamps.decode(&mut reader)?;
+        points.push((timestamp, from_bits(values.decode(&mut reader)?)));
+    }
+    Ok(points)
+}
+
+/// 编码一块读数, 可以包含多个设备的读数, 同一设备连续的读数压缩效果最好
+pub fn encode_readings(readings: &[Reading]) -> Vec<u8> {
+    let mut writer = BitWriter::default();
+    let mut timestamps = DeltaOfDelta::default();
+    let mut values = Xor::default();
+    let mut device = None;
+    for reading in readings {
+        match device.replace(reading.device_id) {
+            None => writer.write_bits(reading.device_id as u64, 32),
+            Some(prev) if prev == reading.device_id => writer.write_bit(false),
+            Some(_) => {
+                writer.write_bit(true);
+                writer.write_bits(reading.device_id as u64, 32);
+            }
+        }
+        timestamps.encode(&mut writer, reading.timestamp);
+        values.encode(&mut writer, reading.value.to_bits());
+    }
+    with_header(FORMAT_READINGS, 8, readings.len() as u32, writer)
+}
+
+pub fn decode_readings(block: &[u8]) -> Result<Vec<Reading>, CodecError> {
+    let (count, mut reader) = read_header(block, FORMAT_READINGS, 8)?;
+    let mut timestamps = DeltaOfDelta::default();
+    let mut values = Xor::default();
+    let mut device = None;
+    let mut readings = Vec::with_capacity(count.min(block.len()));
+    for _ in 0..count {
+        let device_id = match device {
+            Some(prev) if !reader.read_bit()? => prev,
+            _ => reader.read_bits(32)? as u32,
+        };
+        device = Some(device_id);
+        let timestamp = timestamps.decode(&mut reader)?;
+        let value = f64::from_bits(values.decode(&mut reader)?);
+        readings.push(Reading::new(device_id, timestamp, value));
+    }
+    Ok(readings)
+}
+
+
+#[cfg(test)]
+mod test {
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use super::*;
+
+    #[test]
+    fn test_roundtrip_points() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut ts = 1_700_000_000_000i64;
+        let mut value = 1000u64;
+        let points: Vec<(i64, u64)> = (0..1000)
+            .map(|i| {
+                // 大多数间隔固定, 偶尔抖动或长时间断开
+                ts += match i % 100 {
+                    0 => 3_600_000,
+                    1..=9 => rng.gen_range(900..1100),
+                    _ => 1000,
+                };
+                value = value.wrapping_add(rng.gen_range(0..3));
+                (ts, value)
+            })
+            .collect();
+        let block = encode_block(&points);
+        assert_eq!(decode_block::<u64>(&block).unwrap(), points);
+        assert!(block.len() * 4 < points.len() * 16, "{} bytes", block.len());
+
+        let edges: Vec<(i64, i16)> = vec![(i64::MIN, i16::MIN), (i64::MAX, -1), (0, 0), (0, i16::MAX), (-5, 0)];
+        assert_eq!(decode_block::<i16>(&encode_block(&edges)).unwrap(), edges);
+        let floats: Vec<(i64, f64)> = [0.0, -0.0, f64::NAN, f64::INFINITY, 1e-300, 21.5, 21.5]
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| (i as i64, v))
+            .collect();
+        let decoded = decode_block::<f64>(&encode_block(&floats)).unwrap();
+        assert!(decoded.iter().zip(&floats).all(|(a, b)| a.0 == b.0 && a.1.to_bits() == b.1.to_bits()));
+        assert!(decode_block::<u64>(&encode_block::<u64>(&[])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_roundtrip_readings() {
+        let readings: Vec<Reading> = (0..200)
+            .map(|i| Reading::new(1 + i / 100, 1000 + i as i64 * 500, 20.0 + (i / 10) as f64 * 0.25))
+            .collect();
+        let block = encode_readings(&readings);
+        assert_eq!(decode_readings(&block).unwrap(), readings);
+        assert!(block.len() < readings.len() * 20 / 4, "{} bytes", block.len());
+
+        assert_eq!(decode_readings(&block[..block.len() - 1]), Err(CodecError::Truncated));
+        assert_eq!(decode_block::<f64>(&block), Err(CodecError::UnsupportedFormat(FORMAT_READINGS)));
+        let points = encode_block(&[(1i64, 1u32)]);
+        assert_eq!(decode_block::<u64>(&points), Err(CodecError::ValueSize { expected: 8, found: 4 }));
+    }
+}
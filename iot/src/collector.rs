@@ -1,9 +1,16 @@
 use std::future::Future;
+use std::ops::{AddAssign, Rem};
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
+use log::debug;
 use pin_project::pin_project;
 use tokio_stream::Stream;
+use crate::aggregate::{AggregateValue, Aggregates, Aggregation, Aggregator};
+use crate::condition::{AnyOf, CountReached, ReadyCondition, SumMultipleOf};
+use crate::metrics::{CollectorMetrics, Registry};
 use crate::producer::Producer;
+use crate::shutdown::{Shutdown, Signal};
 
 #[pin_project]
 pub struct Collector<T, P> {
@@ -13,132 +20,129 @@ pub struct Collector<T, P> {
     producer: T,
     /// 收集数据
     result: Vec<P>,
-    /// 生产数据计数器
-    sum: P,
-    /// debugging
+    /// 完成条件
+    condition: Box<dyn ReadyCondition<P>>,
+    /// 停止信号触发后返回已收集的数据
+    shutdown: Option<Signal>,
+    /// 收集器编号, 作为指标的 `collector` 标签
     num: u32,
-    thread_id: Option<std::thread::ThreadId>
-}
-
-
-// perform the next step in polling
-macro_rules! poll_step {
-    ($self:ident) => {
-        // no core functionality of future
-        // check is working thread changed
-        // can get removed
-        if let Some(new_id) = check_thread_id!($self) {
-            *$self.thread_id = Some(new_id);
-        }
-        // get new data and check if we are ready
-        if new_data_and_check_ready!($self) {
-            println!("MATCH {} Steps: {}", $self.num, $self.status);
-            return Poll::Ready($self.result.clone()); // Return the result
-        }
-        return Poll::Pending // Continue polling if condition not met
-    };
-}
-
-
-// get new data and check if read_condition is reached
-macro_rules! new_data_and_check_ready {
-    ($self:ident) => {{
-        if !$self.producer.data_available() {
-            false // Return false immediately if no data is available
-        } else {
-            let data = $self.producer.produce(); // Produce new data
-            $self.result.push(data); // Store the produced data
-            *$self.sum += data; // Update the future's sum
-            // Increment future's status
-            *$self.status += 1;
-
-            // check ready-condition
-            if ready_condition!($self) {
-                $self.producer.stop(); // Stop if the ready condition is met
-                true // Return true if the condition is met
-            } else {
-                false // Return false if the condition is not met
-            }
-        }
-    }};
-}
-
-
-// logic to check when the collector's ready condition is met
-macro_rules! ready_condition {
-    ($self:ident) => {
-        *$self.sum % P::from(17) == P::from(0)
-    };
-}
-
-
-// Check if the future changes its working thread
-// nothing to do with core functionality
-// can be removed
-macro_rules! check_thread_id {
-    ($self:ident) => {{
-        let new_thread_id = std::thread::current().id();
-        if *$self.thread_id != Some(new_thread_id) {
-            if let Some(stored_thread_id) = *$self.thread_id {
-                println!(
-                    "---------------->Thread ID Changed: {} from/to {:?}/{:?}",
-                    $self.num, stored_thread_id, new_thread_id
-                );
-            }
-            Some(new_thread_id) // return the updated thread ID
-        } else {
-            *$self.thread_id // No change, return the original thread ID
-        }
-    }};
+    metrics: Option<CollectorMetrics>,
 }
 
 
 impl <T, P> Collector<T, P>
 where
     T: Producer<P>,
-    P: std::convert::From<u8>
+    P: From<u8> + Copy + AddAssign + Rem<Output = P> + PartialEq + Send + 'static,
 {
-    const NUM_DATA: usize = 108;
+    /// 默认最多收集的数据个数
+    pub const NUM_DATA: usize = 108;
 
+    /// 使用默认完成条件: 累加和是 17 的整数倍, 或者收集满 `NUM_DATA` 个数据
     #[allow(dead_code)]
     pub fn new(producer: T, num: u32) -> Self {
+        let condition: AnyOf<P> = AnyOf(vec![
+            Box::new(SumMultipleOf::new(P::from(17))),
+            Box::new(CountReached(Self::NUM_DATA)),
+        ]);
+        Self::with_condition(producer, num, condition)
+    }
+}
+
+impl <T, P> Collector<T, P>
+where
+    T: Producer<P>,
+{
+    pub fn with_condition(producer: T, num: u32, condition: impl ReadyCondition<P> + 'static) -> Self {
         Collector {
             status: 0,
             producer,
-            result: Vec::with_capacity(Collector::<T, P>::NUM_DATA),
-            sum: P::from(0),
+            result: Vec::new(),
+            condition: Box::new(condition),
+            shutdown: None,
             num,
-            thread_id: None
+            metrics: None,
         }
     }
+
+    pub fn with_shutdown(mut self, shutdown: &Shutdown) -> Self {
+        self.shutdown = Some(shutdown.signal());
+        self
+    }
+
+    /// 在 `registry` 中记录 poll 次数, 唤醒次数, 读数速率, 完成耗时和线程迁移
+    pub fn with_metrics(mut self, registry: &dyn Registry) -> Self {
+        self.metrics = Some(CollectorMetrics::new(registry, &self.num.to_string()));
+        self
+    }
 }
 
 impl<T, P> Future for Collector<T, P>
 where
     T: Producer<P> + std::marker::Unpin,
-    P: std::convert::From<u8> + Copy + std::ops::Rem + std::ops::AddAssign,
-    <P as std::ops::Rem>::Output: PartialEq<P>,
+    P: Copy,
 {
     type Output = Vec<P>; // The output type is a vector of produced data
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let this = self.project();
 
-        // Store the current waker
-        this.producer.store_waker(cx.waker());
+        // Store the current waker, wrapped to count wake-ups when metrics are enabled
+        let waker = match this.metrics.as_mut() {
+            Some(metrics) => metrics.poll.on_poll(cx.waker()),
+            None => cx.waker().clone(),
+        };
+        if this.producer.store_waker(&waker) {
+            if let Some(metrics) = this.metrics.as_ref() {
+                metrics.waker_changes.inc();
+            }
+        }
 
-        match *this.status {
-            // Handle case where all data is collected but no condition match
-            Collector::<T, P>::NUM_DATA => {
-                println!("READY, NO MATCH: {}", *this.num);
-                this.producer.stop(); // Stop the producer
-                Poll::Ready(this.result.clone()) // Return the result
+        if let Some(shutdown) = this.shutdown.as_mut() {
+            if Pin::new(shutdown).poll(cx).is_ready() {
+                this.producer.stop();
+                // 保留停止前已经到达的数据
+                for data in this.producer.drain() {
+                    this.result.push(data);
+                    *this.status += 1;
+                    if let Some(metrics) = this.metrics.as_ref() {
+                        metrics.poll.on_reading();
+                    }
+                }
+                debug!("SHUTDOWN {} Steps: {}", this.num, this.status);
+                if let Some(metrics) = this.metrics.as_ref() {
+                    metrics.on_ready();
+                }
+                return Poll::Ready(std::mem::take(this.result));
             }
-            // Handle case where collection of data not ready
-            _ => {
-                poll_step!(this);
+        }
+
+        if !this.producer.data_available() {
+            return Poll::Pending;
+        }
+
+        let Some(data) = this.producer.produce() else {
+            debug!("CLOSED {} Steps: {}", this.num, this.status);
+            if let Some(metrics) = this.metrics.as_ref() {
+                metrics.on_ready();
+            }
+            return Poll::Ready(std::mem::take(this.result));
+        };
+        this.result.push(data); // Store the produced data
+        *this.status += 1;
+        if let Some(metrics) = this.metrics.as_ref() {
+            metrics.poll.on_reading();
+        }
+
+        if this.condition.is_ready(&data, this.result) {
+            this.producer.stop(); // Stop if the ready condition is met
+            debug!("MATCH {} Steps: {}", this.num, this.status);
+            if let Some(metrics) = this.metrics.as_ref() {
+                metrics.on_ready();
             }
+            return Poll::Ready(std::mem::take(this.result));
         }
+        Poll::Pending // Continue polling if condition not met
     }
 }
 
@@ -191,31 +195,422 @@ where
 }
 
 
+/// 收集结束的原因
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// 上游流结束
+    StreamEnded,
+    /// 完成条件满足
+    ConditionMet,
+    /// 收集数量达到 `max_items`
+    MaxItems,
+    /// 超过 `timeout`
+    Timeout,
+    /// 停止信号被触发, 且上游流在停止后结束
+    Shutdown,
+}
+
+/// 收集结果中是否保留原始数据
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputMode {
+    /// 保留所有数据和聚合结果
+    #[default]
+    Items,
+    /// 只保留聚合结果
+    Aggregates,
+}
+
+/// `CollectorBuilder` 收集的结果
+#[derive(Clone, Debug, PartialEq)]
+pub struct CollectionResult<T> {
+    /// 收集的数据, `OutputMode::Aggregates` 时为空
+    pub items: Vec<T>,
+    pub aggregates: Aggregates,
+    /// 从上游收到的数据个数
+    pub count: u64,
+    pub stop_reason: StopReason,
+    pub elapsed: Duration,
+}
+
+/// 配置并创建消费任意 `Stream` 的收集器
+///
+/// ```ignore
+/// let result = CollectorBuilder::new()
+///     .aggregate(Aggregation::Mean)
+///     .stop_when(Matches(|v: &i32| *v > 100))
+///     .timeout(Duration::from_secs(5))
+///     .collect(producer)
+///     .await;
+/// ```
+pub struct CollectorBuilder<T> {
+    aggregations: Vec<Aggregation>,
+    condition: Option<Box<dyn ReadyCondition<T>>>,
+    max_items: Option<usize>,
+    timeout: Option<Duration>,
+    shutdown: Option<Shutdown>,
+    output: OutputMode,
+    metrics: Option<CollectorMetrics>,
+}
+
+impl<T> Default for CollectorBuilder<T> {
+    fn default() -> Self {
+        Self {
+            aggregations: Vec::new(),
+            condition: None,
+            max_items: None,
+            timeout: None,
+            shutdown: None,
+            output: OutputMode::default(),
+            metrics: None,
+        }
+    }
+}
+
+impl<T> CollectorBuilder<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn aggregate(mut self, aggregation: Aggregation) -> Self {
+        self.aggregations.push(aggregation);
+        self
+    }
+
+    /// 完成条件, `OutputMode::Aggregates` 下不保留数据, 条件收到的 `collected` 为空
+    pub fn stop_when(mut self, condition: impl ReadyCondition<T> + 'static) -> Self {
+        self.condition = Some(Box::new(condition));
+        self
+    }
+
+    pub fn max_items(mut self, max_items: usize) -> Self {
+        self.max_items = Some(max_items);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// 停止信号触发后继续读取上游已经缓冲的数据, 直到流结束才返回 `StopReason::Shutdown`
+    ///
+    /// 上游需要在停止信号触发后结束(例如 `base_producer::Producer::with_shutdown`),
+    /// 否则收集只会在完成条件, `max_items` 或 `timeout` 满足时结束.
+    pub fn shutdown(mut self, shutdown: &Shutdown) -> Self {
+        self.shutdown = Some(shutdown.clone());
+        self
+    }
+
+    pub fn output(mut self, output: OutputMode) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// 在 `registry` 中以 `collector` 标签 `name` 记录收集器指标
+    pub fn metrics(mut self, registry: &dyn Registry, name: &str) -> Self {
+        self.metrics = Some(CollectorMetrics::new(registry, name));
+        self
+    }
+
+    pub fn collect<S>(self, stream: S) -> Collection<S, T>
+    where
+        S: Stream<Item = T>,
+    {
+        Collection {
+            stream,
+            deadline: self.timeout.map(|timeout| Box::pin(tokio::time::sleep(timeout))),
+            shutdown: self.shutdown.as_ref().map(Shutdown::signal),
+            draining: false,
+            aggregator: Aggregator::new(self.aggregations),
+            condition: self.condition,
+            max_items: self.max_items,
+            output: self.output,
+            items: Vec::new(),
+            started: tokio::time::Instant::now(),
+            metrics: self.metrics,
+        }
+    }
+}
+
+/// `CollectorBuilder::collect` 返回的 future
+#[pin_project]
+pub struct Collection<S, T> {
+    #[pin]
+    stream: S,
+    deadline: Option<Pin<Box<tokio::time::Sleep>>>,
+    shutdown: Option<Signal>,
+    /// 停止信号已经触发, 正在读取上游剩余的数据
+    draining: bool,
+    aggregator: Aggregator,
+    condition: Option<Box<dyn ReadyCondition<T>>>,
+    max_items: Option<usize>,
+    output: OutputMode,
+    items: Vec<T>,
+    started: tokio::time::Instant,
+    metrics: Option<CollectorMetrics>,
+}
+
+impl<S, T> Collection<S, T> {
+    fn finish(self: Pin<&mut Self>, stop_reason: StopReason) -> CollectionResult<T> {
+        let this = self.project();
+        if let Some(metrics) = this.metrics.as_ref() {
+            metrics.on_ready();
+        }
+        CollectionResult {
+            items: std::mem::take(this.items),
+            aggregates: this.aggregator.finish(),
+            count: this.aggregator.count(),
+            stop_reason,
+            elapsed: this.started.elapsed(),
+        }
+    }
+}
+
+impl<S, T> Future for Collection<S, T>
+where
+    S: Stream<Item = T>,
+    T: AggregateValue,
+{
+    type Output = CollectionResult<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.as_mut().project();
+        if let Some(deadline) = this.deadline.as_mut() {
+            if deadline.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(self.finish(StopReason::Timeout));
+            }
+        }
+        if !*this.draining {
+            if let Some(shutdown) = this.shutdown.as_mut() {
+                *this.draining = Pin::new(shutdown).poll(cx).is_ready();
+            }
+        }
+
+        let mut this = self.as_mut().project();
+        let waker = match this.metrics.as_mut() {
+            Some(metrics) => metrics.poll.on_poll(cx.waker()),
+            None => cx.waker().clone(),
+        };
+        let mut stream_cx = Context::from_waker(&waker);
+        loop {
+            // 先检查数量上限, `max_items(0)` 不消费任何数据
+            if this.max_items.is_some_and(|max| this.aggregator.count() >= max as u64) {
+                return Poll::Ready(self.finish(StopReason::MaxItems));
+            }
+            let item = match this.stream.as_mut().poll_next(&mut stream_cx) {
+                Poll::Ready(Some(item)) => item,
+                Poll::Ready(None) => {
+                    let reason = if *this.draining { StopReason::Shutdown } else { StopReason::StreamEnded };
+                    return Poll::Ready(self.finish(reason));
+                }
+                Poll::Pending => return Poll::Pending,
+            };
+
+            this.aggregator.update(&item);
+            if let Some(metrics) = this.metrics.as_ref() {
+                metrics.poll.on_reading();
+            }
+            let ready = match *this.output {
+                OutputMode::Items => {
+                    this.items.push(item);
+                    let (last, _) = this.items.split_last().expect("just pushed");
+                    this.condition.as_mut().is_some_and(|cond| cond.is_ready(last, this.items))
+                }
+                OutputMode::Aggregates => {
+                    this.condition.as_mut().is_some_and(|cond| cond.is_ready(&item, &[]))
+                }
+            };
+
+            if ready {
+                return Poll::Ready(self.finish(StopReason::ConditionMet));
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
 mod test {
+    use std::task::Waker;
     use log::info;
     use tokio::sync::mpsc;
-    use crate::base_producer::HasErrorValue;
+    use crate::base_producer::ProducerError;
+    use crate::condition::Matches;
+    use crate::deterministic::Determinism;
+    use crate::producer::{ChannelProducer, RandProducer, TCPProducer};
+    use crate::metrics::MetricsRegistry;
     use crate::stream::DataAvailable;
     use super::*;
 
-    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    async fn test_stream_collect() {
-        if let None = std::env::var_os("RUST_LOG") {
-            std::env::set_var("RUST_LOG", "info")
+    /// 依次产生 1, 2, 3, ... 的生产者
+    #[derive(Default)]
+    struct CountingProducer {
+        next: u16,
+        waker: Option<Waker>,
+        /// `drain` 返回的已缓冲数据
+        buffered: Vec<u16>,
+    }
+
+    impl Producer<u16> for CountingProducer {
+        fn produce(&mut self) -> Option<u16> {
+            self.next += 1;
+            if let Some(waker) = &self.waker {
+                waker.wake_by_ref();
+            }
+            Some(self.next)
+        }
+
+        fn data_available(&mut self) -> bool {
+            true
+        }
+
+        fn drain(&mut self) -> Vec<u16> {
+            std::mem::take(&mut self.buffered)
+        }
+
+        fn set_waker(&mut self, waker: Option<Waker>) {
+            self.waker = waker;
+        }
+
+        fn get_waker(&self) -> Option<&Waker> {
+            self.waker.as_ref()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_collector_ready_condition() {
+        // 1 + 2 + ... + 16 = 136 = 17 * 8
+        let collected = Collector::new(CountingProducer::default(), 0).await;
+        assert_eq!(collected, (1..=16).collect::<Vec<u16>>());
+
+        let collected = Collector::with_condition(CountingProducer::default(), 1, Matches(|v: &u16| *v == 5)).await;
+        assert_eq!(collected, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[tokio::test]
+    async fn test_collector_shutdown() {
+        let shutdown = Shutdown::new();
+        shutdown.trigger();
+        let collected = Collector::new(CountingProducer::default(), 0).with_shutdown(&shutdown).await;
+        assert!(collected.is_empty());
+
+        // 停止前已经到达的数据保留在结果中
+        let producer = CountingProducer { buffered: vec![7, 8], ..Default::default() };
+        let collected = Collector::new(producer, 0).with_shutdown(&shutdown).await;
+        assert_eq!(collected, vec![7, 8]);
+
+        let result = CollectorBuilder::new()
+            .shutdown(&shutdown)
+            .collect(tokio_stream::iter(vec![1i32, 2, 3]))
+            .await;
+        assert_eq!(result.items, vec![1, 2, 3]);
+        assert_eq!(result.stop_reason, StopReason::Shutdown);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_collection_drains_after_shutdown() {
+        let shutdown = Shutdown::new();
+        let (tx, rx) = mpsc::channel(8);
+        let producer = crate::base_producer::Producer::new(DataAvailable::new(), rx, Box::new(|x: i32| x))
+            .with_shutdown(&shutdown);
+        let collection = CollectorBuilder::new()
+            .shutdown(&shutdown)
+            .collect(producer);
+
+        // 读数在停止信号之后才被收集器看到
+        shutdown.trigger();
+        for value in 1..=3 {
+            tx.send(Ok(value)).await.unwrap();
         }
-        env_logger::init();
+        let result = collection.await;
+        assert_eq!(result.items, vec![1, 2, 3]);
+        assert_eq!(result.stop_reason, StopReason::Shutdown);
+        drop(tx);
+    }
+
+    #[tokio::test]
+    async fn test_collector_metrics() {
+        let registry = MetricsRegistry::new();
+        let collected = Collector::with_condition(CountingProducer::default(), 7, Matches(|v: &u16| *v == 5))
+            .with_metrics(&registry)
+            .await;
+        assert_eq!(collected.len(), 5);
+
+        let metrics = CollectorMetrics::new(&registry, "7");
+        assert_eq!(metrics.poll.readings.get(), 5);
+        assert_eq!(metrics.poll.polls.get(), 5);
+        // 生产者每次产出数据后唤醒任务, 最后一次唤醒时收集器已经完成
+        assert_eq!(metrics.poll.wakes.get(), 5);
+        assert_eq!(metrics.time_to_ready.count(), 1);
+        assert!(registry.render().contains("iot_collector_readings_total{collector=\"7\"} 5\n"));
+    }
+
+    #[tokio::test]
+    async fn test_collector_builder() {
+        let result = CollectorBuilder::new()
+            .aggregate(Aggregation::Sum)
+            .aggregate(Aggregation::Mean)
+            .collect(tokio_stream::iter(vec![1i32, 2, 3, 6]))
+            .await;
+        assert_eq!(result.stop_reason, StopReason::StreamEnded);
+        assert_eq!(result.items, vec![1, 2, 3, 6]);
+        assert_eq!(result.aggregates.sum, Some(12.0));
+        assert_eq!(result.aggregates.mean, Some(3.0));
+
+        let result = CollectorBuilder::new()
+            .aggregate(Aggregation::Max)
+            .stop_when(Matches(|v: &i32| *v >= 3))
+            .output(OutputMode::Aggregates)
+            .collect(tokio_stream::iter(vec![1i32, 2, 3, 6]))
+            .await;
+        assert_eq!(result.stop_reason, StopReason::ConditionMet);
+        assert!(result.items.is_empty());
+        assert_eq!(result.count, 3);
+        assert_eq!(result.aggregates.max, Some(3.0));
+
+        let result = CollectorBuilder::new()
+            .max_items(2)
+            .collect(tokio_stream::iter(vec![1i32, 2, 3, 6]))
+            .await;
+        assert_eq!(result.stop_reason, StopReason::MaxItems);
+        assert_eq!(result.items, vec![1, 2]);
+
+        let mut stream = tokio_stream::iter(vec![1i32, 2]);
+        let result = CollectorBuilder::new()
+            .max_items(0)
+            .collect(&mut stream)
+            .await;
+        assert_eq!(result.stop_reason, StopReason::MaxItems);
+        assert_eq!(result.count, 0);
+        assert_eq!(stream.size_hint(), (2, Some(2)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_collector_builder_timeout() {
+        let result = CollectorBuilder::<i32>::new()
+            .timeout(Duration::from_secs(1))
+            .collect(tokio_stream::pending())
+            .await;
+        assert_eq!(result.stop_reason, StopReason::Timeout);
+        assert_eq!(result.count, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stream_collect() {
+        let _ = env_logger::try_init();
         let (tx, rx) = mpsc::channel(1);
 
         // Create the Producer with a simple post-processing function (e.g., add 1 to an integer)
-        let producer = crate::base_producer::Producer::new(DataAvailable::new(), rx, Box::new(|x: i32| x + 1));
+        let data_available = DataAvailable::new().with_seed(11);
+        let producer = crate::base_producer::Producer::new(data_available, rx, Box::new(|x: i32| x + 1));
 
         // Spawn a task to send data into the channel and send an error
         tokio::spawn(async move {
             for i in 0i32..900 {
                 if i == 11 {
-                    let err = i.into_error_value();
-                    tx.send(err).await.unwrap();
-                } else if let Err(_e) = tx.send(i).await {
+                    let err = ProducerError::DeviceFault(format!("reading {}", i));
+                    tx.send(Err(err)).await.unwrap();
+                } else if let Err(_e) = tx.send(Ok(i)).await {
                     break; // Stop sending if the channel is closed
                 }
             }
@@ -228,6 +623,30 @@ mod test {
         let collected_data = collector.await;
 
         info!("Collected data: {:?}", collected_data);
+        // 设备故障结束数据流, 之前的读数全部收到
+        assert_eq!(collected_data, (1..=11).collect::<Vec<i32>>());
+    }
+
+    #[tokio::test]
+    async fn test_seeded_producers_are_repeatable() {
+        let rand = || Collector::new(RandProducer::<i16>::with_determinism(Determinism::seeded(5)), 0);
+        let first = rand().await;
+        assert!(!first.is_empty());
+        assert_eq!(first, rand().await);
+
+        let channel = || Collector::new(ChannelProducer::<u16>::with_options(Determinism::seeded(5), None), 0);
+        let first = channel().await;
+        assert_eq!(first, channel().await);
+
+        // 内置 TCP 设备的会话号和读数也来自种子
+        let tcp = || {
+            let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+            let producer = TCPProducer::<u64>::with_options(format!("127.0.0.1:{}", port), Determinism::seeded(5), None);
+            Collector::with_condition(producer, 0, CountReached(20))
+        };
+        let first = tcp().await;
+        assert_eq!(first.len(), 20);
+        assert_eq!(first, tcp().await);
     }
 }
 
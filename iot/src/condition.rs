@@ -0,0 +1,222 @@
+use std::ops::{AddAssign, Rem};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// 收集器的完成条件
+///
+/// 每收集到一个新数据调用一次 `is_ready`, `value` 为新数据,
+/// `collected` 为包含新数据在内的所有已收集数据. 返回 `true` 表示收集完成.
+///
+/// 闭包 `FnMut(&P, &[P]) -> bool` 也可以直接作为完成条件使用.
+pub trait ReadyCondition<P>: Send {
+    fn is_ready(&mut self, value: &P, collected: &[P]) -> bool;
+}
+
+impl<P, F> ReadyCondition<P> for F
+where
+    F: FnMut(&P, &[P]) -> bool + Send,
+{
+    fn is_ready(&mut self, value: &P, collected: &[P]) -> bool {
+        self(value, collected)
+    }
+}
+
+impl<P> ReadyCondition<P> for Box<dyn ReadyCondition<P>> {
+    fn is_ready(&mut self, value: &P, collected: &[P]) -> bool {
+        self.as_mut().is_ready(value, collected)
+    }
+}
+
+
+/// 收集到指定数量的数据
+pub struct CountReached(pub usize);
+
+impl<P> ReadyCondition<P> for CountReached {
+    fn is_ready(&mut self, _value: &P, collected: &[P]) -> bool {
+        collected.len() >= self.0
+    }
+}
+
+
+/// 数据累加和达到(大于等于)阈值
+pub struct SumReached<P> {
+    threshold: P,
+    sum: Option<P>,
+}
+
+impl<P> SumReached<P> {
+    pub fn new(threshold: P) -> Self {
+        Self { threshold, sum: None }
+    }
+}
+
+impl<P> ReadyCondition<P> for SumReached<P>
+where
+    P: Copy + AddAssign + PartialOrd + Send,
+{
+    fn is_ready(&mut self, value: &P, _collected: &[P]) -> bool {
+        let sum = match self.sum.as_mut() {
+            Some(sum) => {
+                *sum += *value;
+                *sum
+            }
+            None => *self.sum.insert(*value),
+        };
+        sum >= self.threshold
+    }
+}
+
+
+/// 数据累加和是 `divisor` 的整数倍, 即原先硬编码的 `sum % 17 == 0`
+pub struct SumMultipleOf<P> {
+    divisor: P,
+    sum: P,
+}
+
+impl<P> SumMultipleOf<P>
+where
+    P: From<u8> + PartialEq,
+{
+    /// `divisor` 为 0 时 panic
+    pub fn new(divisor: P) -> Self {
+        assert!(divisor != P::from(0), "SumMultipleOf divisor must not be zero");
+        Self { divisor, sum: P::from(0) }
+    }
+}
+
+impl<P> ReadyCondition<P> for SumMultipleOf<P>
+where
+    P: Copy + From<u8> + AddAssign + Rem<Output = P> + PartialEq + Send,
+{
+    fn is_ready(&mut self, value: &P, _collected: &[P]) -> bool {
+        self.sum += *value;
+        self.sum % self.divisor == P::from(0)
+    }
+}
+
+
+/// 从收到第一个数据开始, 经过指定时间后完成
+///
+/// 只在收到新数据时检查, 所以实际完成时间是窗口结束后的第一个数据.
+/// 使用 tokio 的时钟, `tokio::time::pause` 后按暂停的时间计算.
+pub struct TimeElapsed {
+    window: Duration,
+    started: Option<Instant>,
+}
+
+impl TimeElapsed {
+    pub fn new(window: Duration) -> Self {
+        Self { window, started: None }
+    }
+}
+
+impl<P> ReadyCondition<P> for TimeElapsed {
+    fn is_ready(&mut self, _value: &P, _collected: &[P]) -> bool {
+        let started = *self.started.get_or_insert_with(Instant::now);
+        started.elapsed() >= self.window
+    }
+}
+
+
+/// 新数据满足给定谓词
+pub struct Matches<F>(pub F);
+
+impl<P, F> ReadyCondition<P> for Matches<F>
+where
+    F: FnMut(&P) -> bool + Send,
+{
+    fn is_ready(&mut self, value: &P, _collected: &[P]) -> bool {
+        (self.0)(value)
+    }
+}
+
+
+/// 任一子条件满足即完成
+///
+/// 每个子条件都会被调用, 保证有状态的条件(如累加和)不会漏掉数据.
+pub struct AnyOf<P>(pub Vec<Box<dyn ReadyCondition<P>>>);
+
+impl<P> ReadyCondition<P> for AnyOf<P> {
+    fn is_ready(&mut self, value: &P, collected: &[P]) -> bool {
+        let mut ready = false;
+        for cond in self.0.iter_mut() {
+            ready |= cond.is_ready(value, collected);
+        }
+        ready
+    }
+}
+
+
+/// 所有子条件同时满足才完成
+pub struct AllOf<P>(pub Vec<Box<dyn ReadyCondition<P>>>);
+
+impl<P> ReadyCondition<P> for AllOf<P> {
+    fn is_ready(&mut self, value: &P, collected: &[P]) -> bool {
+        let mut ready = true;
+        for cond in self.0.iter_mut() {
+            ready &= cond.is_ready(value, collected);
+        }
+        ready
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn first_ready<P: Copy, C: ReadyCondition<P>>(mut cond: C, data: &[P]) -> Option<usize> {
+        let mut collected = Vec::new();
+        for value in data {
+            collected.push(*value);
+            if cond.is_ready(value, &collected) {
+                return Some(collected.len());
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn test_builtin_conditions() {
+        let data = [5u16, 6, 6, 10, 4];
+        assert_eq!(first_ready(CountReached(3), &data), Some(3));
+        assert_eq!(first_ready(SumReached::new(20u16), &data), Some(4));
+        assert_eq!(first_ready(SumMultipleOf::new(17u16), &data), Some(3));
+        assert_eq!(first_ready(Matches(|v: &u16| *v >= 10), &data), Some(4));
+        assert_eq!(first_ready(TimeElapsed::new(Duration::from_secs(60)), &data), None);
+        assert_eq!(first_ready(|_: &u16, c: &[u16]| c.len() == 2, &data), Some(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "SumMultipleOf divisor must not be zero")]
+    fn test_sum_multiple_of_zero() {
+        SumMultipleOf::new(0u16);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_time_elapsed_uses_tokio_clock() {
+        let mut cond = TimeElapsed::new(Duration::from_secs(60));
+        assert!(!cond.is_ready(&1u16, &[1]));
+        tokio::time::advance(Duration::from_secs(59)).await;
+        assert!(!cond.is_ready(&2u16, &[1, 2]));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(cond.is_ready(&3u16, &[1, 2, 3]));
+    }
+
+    #[test]
+    fn test_combined_conditions() {
+        let data = [5u16, 6, 6, 10, 4];
+        let any: AnyOf<u16> = AnyOf(vec![
+            Box::new(SumReached::new(100u16)),
+            Box::new(Matches(|v: &u16| *v == 10)),
+        ]);
+        assert_eq!(first_ready(any, &data), Some(4));
+
+        // 两个条件都满足时才完成, 累加和在每一步都被更新
+        let all: AllOf<u16> = AllOf(vec![
+            Box::new(SumReached::new(20u16)),
+            Box::new(Matches(|v: &u16| *v < 5)),
+        ]);
+        assert_eq!(first_ready(all, &data), Some(5));
+    }
+}
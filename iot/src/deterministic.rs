@@ -0,0 +1,103 @@
+use std::cell::{Cell, RefCell};
+use std::ops::RangeInclusive;
+use std::time::Duration;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// 同步生产者在 `data_available` 中等待的方式
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Clock {
+    /// 真正阻塞当前线程
+    #[default]
+    Real,
+    /// 不阻塞, 只累加等待时间, 用于测试
+    Virtual,
+}
+
+/// 生产者的随机数种子和时钟
+///
+/// 固定种子时, 生产者内部每个用途(读数, 等待时间, 会话号)从种子派生出各自的
+/// `StdRng`, 输出只取决于种子. 基于 tokio 的部分(`DataAvailable`, 模拟设备)
+/// 配合 `#[tokio::test(start_paused = true)]` 使用虚拟时间.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Determinism {
+    /// `None` 时使用系统熵
+    pub seed: Option<u64>,
+    pub clock: Clock,
+}
+
+/// `Determinism::rng` 的用途编号
+pub const STREAM_VALUES: u64 = 1;
+pub const STREAM_PACING: u64 = 2;
+pub const STREAM_SESSION: u64 = 3;
+
+impl Determinism {
+    /// 固定种子并使用虚拟时钟
+    pub fn seeded(seed: u64) -> Self {
+        Self { seed: Some(seed), clock: Clock::Virtual }
+    }
+
+    /// 为某个用途创建随机数生成器, 同一种子的不同用途互不影响
+    pub fn rng(&self, stream: u64) -> StdRng {
+        match self.seed {
+            Some(seed) => StdRng::seed_from_u64(seed ^ stream.wrapping_mul(0x9e37_79b9_7f4a_7c15)),
+            None => StdRng::from_entropy(),
+        }
+    }
+}
+
+/// 同步生产者在两次数据之间的随机等待
+pub(crate) struct Throttle {
+    rng: RefCell<StdRng>,
+    clock: Clock,
+    range: RangeInclusive<u64>,
+    /// 虚拟时钟下累计的等待时间
+    waited: Cell<Duration>,
+}
+
+impl Throttle {
+    /// 每次等待 `range` 毫秒内的随机时间
+    pub(crate) fn new(determinism: &Determinism, range: RangeInclusive<u64>) -> Self {
+        Self {
+            rng: RefCell::new(determinism.rng(STREAM_PACING)),
+            clock: determinism.clock,
+            range,
+            waited: Cell::new(Duration::ZERO),
+        }
+    }
+
+    pub(crate) fn wait(&self) {
+        let delay = Duration::from_millis(self.rng.borrow_mut().gen_range(self.range.clone()));
+        match self.clock {
+            Clock::Real => std::thread::sleep(delay),
+            Clock::Virtual => self.waited.set(self.waited.get() + delay),
+        }
+    }
+
+    pub(crate) fn waited(&self) -> Duration {
+        self.waited.get()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_seeded_streams_are_independent_and_repeatable() {
+        let determinism = Determinism::seeded(9);
+        let values: Vec<u32> = (0..4).map(|_| determinism.rng(STREAM_VALUES).gen()).collect();
+        assert!(values.windows(2).all(|w| w[0] == w[1]));
+        assert_ne!(determinism.rng(STREAM_VALUES).gen::<u64>(), determinism.rng(STREAM_PACING).gen::<u64>());
+
+        let throttle = Throttle::new(&determinism, 100..=1000);
+        let again = Throttle::new(&determinism, 100..=1000);
+        for _ in 0..5 {
+            throttle.wait();
+            again.wait();
+        }
+        assert_eq!(throttle.waited(), again.waited());
+        assert!((Duration::from_millis(500)..=Duration::from_millis(5000)).contains(&throttle.waited()));
+    }
+}
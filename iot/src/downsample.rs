@@ -0,0 +1,432 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use pin_project::pin_project;
+use tokio_stream::Stream;
+use crate::aggregate::AggregateValue;
+use crate::reading::Reading;
+
+/// 没有数据的时间桶如何填充
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Fill {
+    /// 不输出
+    #[default]
+    None,
+    /// 输出数值均为 `None` 的桶
+    Null,
+    /// 沿用前一个值
+    Previous,
+    /// 在前一个值和后一个值之间按桶的开始时间线性插值
+    Linear,
+}
+
+/// 一个时间桶的降采样结果
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bucket {
+    /// 序列的键, 读数为设备号
+    pub key: u32,
+    /// 桶开始时间(包含), 毫秒
+    pub start: i64,
+    /// 桶结束时间(不包含), 毫秒
+    pub end: i64,
+    /// 桶中的数据个数, 填充的桶为 0
+    pub count: u64,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub avg: Option<f64>,
+    pub last: Option<f64>,
+    /// 由 `Fill` 生成的桶
+    pub filled: bool,
+}
+
+struct BucketState {
+    start: i64,
+    count: u64,
+    /// 可以转换为 `f64` 的数据个数
+    values: u64,
+    sum: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+    /// 最后一个值和它的时间戳
+    last: Option<(i64, f64)>,
+}
+
+impl BucketState {
+    fn new(start: i64) -> Self {
+        Self { start, count: 0, values: 0, sum: 0.0, min: None, max: None, last: None }
+    }
+
+    fn update(&mut self, timestamp: i64, value: Option<f64>) {
+        self.count += 1;
+        let Some(value) = value else {
+            return;
+        };
+        self.values += 1;
+        self.sum += value;
+        self.min = Some(self.min.map_or(value, |min| min.min(value)));
+        self.max = Some(self.max.map_or(value, |max| max.max(value)));
+        self.last = Some((timestamp, value));
+    }
+}
+
+/// 一个键的序列
+struct Series {
+    current: BucketState,
+    /// 之前已经输出的桶中最后一个值
+    last: Option<(i64, f64)>,
+}
+
+/// 与流无关的降采样状态机, 按键分别把数据归入对齐到 `size` 整数倍的时间桶
+///
+/// 每个键的数据需要按时间顺序到达, 早于当前桶的数据被丢弃. 桶在同一个键的下一个桶
+/// 出现数据时输出, 所以实时数据流中设备停止上报时最后一个桶要等到 `flush` 才输出.
+pub struct Downsampler {
+    size: i64,
+    fill: Fill,
+    max_fill: u64,
+    series: BTreeMap<u32, Series>,
+    dropped: u64,
+    resets: u64,
+}
+
+/// 两个数据之间最多填充的空桶数
+pub const DEFAULT_MAX_FILL: u64 = 10_000;
+
+impl Downsampler {
+    /// `size` 为桶的毫秒数
+    pub fn new(size: i64, fill: Fill) -> Self {
+        assert!(size > 0, "bucket size must be positive");
+        Self { size, fill, max_fill: DEFAULT_MAX_FILL, series: BTreeMap::new(), dropped: 0, resets: 0 }
+    }
+
+    /// 空桶超过 `max_fill` 个的间隔(例如错误的时间戳)视为序列重新开始, 不填充,
+    /// 之后的 `Fill::Previous` 和 `Fill::Linear` 也不使用间隔之前的值
+    pub fn with_max_fill(mut self, max_fill: u64) -> Self {
+        self.max_fill = max_fill;
+        self
+    }
+
+    /// 因为乱序被丢弃的数据个数
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// 因为间隔太大没有填充的次数
+    pub fn resets(&self) -> u64 {
+        self.resets
+    }
+
+    /// 靠近 `i64::MIN` 的桶开始时间无法表示时取 `i64::MIN`
+    fn bucket_start(&self, timestamp: i64) -> i64 {
+        timestamp.saturating_sub(timestamp.rem_euclid(self.size))
+    }
+
+    fn record(&self, key: u32, state: &BucketState) -> Bucket {
+        Bucket {
+            key,
+            start: state.start,
+            end: state.start.saturating_add(self.size),
+            count: state.count,
+            min: state.min,
+            max: state.max,
+            avg: (state.values > 0).then(|| state.sum / state.values as f64),
+            last: state.last.map(|(_, value)| value),
+            filled: false,
+        }
+    }
+
+    fn filled(&self, key: u32, start: i64, value: Option<f64>) -> Bucket {
+        Bucket {
+            key,
+            start,
+            end: start.saturating_add(self.size),
+            count: 0,
+            min: value,
+            max: value,
+            avg: value,
+            last: value,
+            filled: true,
+        }
+    }
+
+    /// 处理键 `key` 在 `timestamp` 的一个数据, 返回因此结束的桶和填充的空桶
+    pub fn on_value(&mut self, key: u32, timestamp: i64, value: Option<f64>) -> Vec<Bucket> {
+        let start = self.bucket_start(timestamp);
+        let Some(series) = self.series.get(&key) else {
+            let mut current = BucketState::new(start);
+            current.update(timestamp, value);
+            self.series.insert(key, Series { current, last: None });
+            return Vec::new();
+        };
+
+        if start < series.current.start {
+            self.dropped += 1;
+            return Vec::new();
+        }
+        if start == series.current.start {
+            self.series.get_mut(&key).expect("checked above").current.update(timestamp, value);
+            return Vec::new();
+        }
+
+        let mut output = vec![self.record(key, &series.current)];
+        let mut last = series.current.last.or(series.last);
+        let next = value.map(|value| (timestamp, value));
+        let mut gap = series.current.start.saturating_add(self.size);
+        // 空桶数超出 i64 时同样视为超过 `max_fill`
+        let empty = start.checked_sub(gap).map(|empty| empty / self.size);
+        if self.fill != Fill::None && empty.is_none_or(|empty| empty as u64 > self.max_fill) {
+            self.resets += 1;
+            last = None;
+            gap = start;
+        }
+        while gap < start && self.fill != Fill::None {
+            let value = match (self.fill, last, next) {
+                (Fill::Previous, Some((_, prev)), _) => Some(prev),
+                (Fill::Linear, Some((t0, v0)), Some((t1, v1))) => {
+                    Some(v0 + (v1 - v0) * (gap as f64 - t0 as f64) / (t1 as f64 - t0 as f64))
+                }
+                _ => None,
+            };
+            output.push(self.filled(key, gap, value));
+            gap = gap.saturating_add(self.size);
+        }
+
+        let mut current = BucketState::new(start);
+        current.update(timestamp, value);
+        self.series.insert(key, Series { current, last });
+        output
+    }
+
+    /// 输出所有未结束的桶, 按键排序
+    pub fn flush(&mut self) -> Vec<Bucket> {
+        let series = std::mem::take(&mut self.series);
+        series.iter().map(|(key, series)| self.record(*key, &series.current)).collect()
+    }
+}
+
+
+/// 配置降采样算子
+///
+/// ```ignore
+/// let buckets = DownsampleBuilder::readings(Duration::from_secs(60))
+///     .fill(Fill::Linear)
+///     .build(readings);
+/// ```
+pub struct DownsampleBuilder<T> {
+    size: i64,
+    fill: Fill,
+    max_fill: u64,
+    timestamp: Box<dyn Fn(&T) -> i64 + Send>,
+    key: Box<dyn Fn(&T) -> u32 + Send>,
+}
+
+impl<T> DownsampleBuilder<T> {
+    /// `timestamp` 从数据中取出毫秒时间戳, 所有数据属于同一个序列.
+    /// 桶按毫秒划分, `size` 小于 1 毫秒时 panic
+    pub fn new(size: Duration, timestamp: impl Fn(&T) -> i64 + Send + 'static) -> Self {
+        let millis = size.as_millis();
+        assert!(millis >= 1, "bucket size must be at least 1ms, got {:?}", size);
+        Self {
+            size: millis.min(i64::MAX as u128) as i64,
+            fill: Fill::default(),
+            max_fill: DEFAULT_MAX_FILL,
+            timestamp: Box::new(timestamp),
+            key: Box::new(|_| 0),
+        }
+    }
+
+    pub fn fill(mut self, fill: Fill) -> Self {
+        self.fill = fill;
+        self
+    }
+
+    /// 见 `Downsampler::with_max_fill`
+    pub fn max_fill(mut self, max_fill: u64) -> Self {
+        self.max_fill = max_fill;
+        self
+    }
+
+    /// 按 `key` 把数据分为多个序列分别降采样
+    pub fn key_by(mut self, key: impl Fn(&T) -> u32 + Send + 'static) -> Self {
+        self.key = Box::new(key);
+        self
+    }
+
+    pub fn build<S>(self, stream: S) -> Downsampled<S, T>
+    where
+        S: Stream<Item = T>,
+    {
+        Downsampled {
+            stream,
+            downsampler: Downsampler::new(self.size, self.fill).with_max_fill(self.max_fill),
+            timestamp: self.timestamp,
+            key: self.key,
+            pending: VecDeque::new(),
+            ended: false,
+        }
+    }
+
+    /// 对已经收集或存储的数据降采样, 例如 `StreamCollector` 的输出
+    pub fn apply<I>(self, items: I) -> Vec<Bucket>
+    where
+        I: IntoIterator<Item = T>,
+        T: AggregateValue,
+    {
+        let mut downsampler = Downsampler::new(self.size, self.fill).with_max_fill(self.max_fill);
+        let mut output = Vec::new();
+        for item in items {
+            output.extend(downsampler.on_value((self.key)(&item), (self.timestamp)(&item), item.aggregate_value()));
+        }
+        output.extend(downsampler.flush());
+        output
+    }
+}
+
+impl DownsampleBuilder<Reading> {
+    /// 按读数的时间戳和设备号降采样
+    pub fn readings(size: Duration) -> Self {
+        Self::new(size, |reading: &Reading| reading.timestamp).key_by(|reading| reading.device_id)
+    }
+}
+
+/// 对上游数据降采样, 每个时间桶输出一条 `Bucket`
+#[pin_project]
+pub struct Downsampled<S, T> {
+    #[pin]
+    stream: S,
+    downsampler: Downsampler,
+    timestamp: Box<dyn Fn(&T) -> i64 + Send>,
+    key: Box<dyn Fn(&T) -> u32 + Send>,
+    pending: VecDeque<Bucket>,
+    ended: bool,
+}
+
+impl<S, T> Downsampled<S, T> {
+    pub fn downsampler(&self) -> &Downsampler {
+        &self.downsampler
+    }
+}
+
+impl<S, T> Stream for Downsampled<S, T>
+where
+    S: Stream<Item = T>,
+    T: AggregateValue,
+{
+    type Item = Bucket;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            if let Some(bucket) = this.pending.pop_front() {
+                return Poll::Ready(Some(bucket));
+            }
+            if *this.ended {
+                return Poll::Ready(None);
+            }
+            match this.stream.as_mut().poll_next(cx) {
+                Poll::Ready(Some(item)) => {
+                    let buckets = this.downsampler.on_value((this.key)(&item), (this.timestamp)(&item), item.aggregate_value());
+                    this.pending.extend(buckets);
+                }
+                Poll::Ready(None) => {
+                    this.pending.extend(this.downsampler.flush());
+                    *this.ended = true;
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::collector::StreamCollector;
+    use super::*;
+
+    fn values(buckets: &[Bucket]) -> Vec<(i64, u64, Option<f64>, bool)> {
+        buckets.iter().map(|b| (b.start, b.count, b.last, b.filled)).collect()
+    }
+
+    #[test]
+    #[should_panic(expected = "bucket size must be at least 1ms")]
+    fn test_sub_millisecond_bucket() {
+        DownsampleBuilder::new(Duration::from_micros(500), |p: &(i64, f64)| p.0);
+    }
+
+    #[test]
+    fn test_downsample_and_fill() {
+        // 1s 的桶, 第 2, 3 秒没有数据
+        let points = vec![(100i64, 1.0), (900, 3.0), (1500, 2.0), (4000, 8.0), (4999, 6.0), (1200, 9.0)];
+        let batch = |fill| DownsampleBuilder::new(Duration::from_secs(1), |p: &(i64, f64)| p.0).fill(fill).apply(points.clone());
+
+        let buckets = batch(Fill::None);
+        assert_eq!(values(&buckets), vec![(0, 2, Some(3.0), false), (1000, 1, Some(2.0), false), (4000, 2, Some(6.0), false)]);
+        assert_eq!((buckets[0].min, buckets[0].max, buckets[0].avg), (Some(1.0), Some(3.0), Some(2.0)));
+        assert_eq!(buckets[2].end, 5000);
+
+        assert_eq!(values(&batch(Fill::Null))[2..4], [(2000, 0, None, true), (3000, 0, None, true)]);
+        assert_eq!(values(&batch(Fill::Previous))[2..4], [(2000, 0, Some(2.0), true), (3000, 0, Some(2.0), true)]);
+        // 在 (1500, 2.0) 和 (4000, 8.0) 之间插值
+        let linear = batch(Fill::Linear);
+        let interpolated: Vec<f64> = linear[2..4].iter().map(|b| b.avg.unwrap()).collect();
+        assert!((interpolated[0] - 3.2).abs() < 1e-9 && (interpolated[1] - 5.6).abs() < 1e-9);
+        assert!(linear[2..4].iter().all(|b| b.filled && b.count == 0));
+        assert_eq!(linear.len(), 5);
+
+        // 间隔超过 max_fill 个桶时不填充, 之后也不沿用间隔之前的值
+        let mut downsampler = Downsampler::new(1000, Fill::Previous).with_max_fill(2);
+        downsampler.on_value(1, 0, Some(1.0));
+        assert_eq!(values(&downsampler.on_value(1, 3000, None)), [(0, 1, Some(1.0), false), (1000, 0, Some(1.0), true), (2000, 0, Some(1.0), true)]);
+        let jump = downsampler.on_value(1, i64::MAX / 2, Some(5.0));
+        assert_eq!(values(&jump), [(3000, 1, None, false)]);
+        assert_eq!(downsampler.resets(), 1);
+
+        let mut downsampler = Downsampler::new(1000, Fill::None);
+        downsampler.on_value(1, 5000, Some(1.0));
+        assert!(downsampler.on_value(1, 100, Some(1.0)).is_empty());
+        assert_eq!(downsampler.dropped(), 1);
+    }
+
+    #[test]
+    fn test_extreme_timestamps() {
+        for fill in [Fill::None, Fill::Previous, Fill::Linear] {
+            let mut downsampler = Downsampler::new(1000, fill);
+            assert!(downsampler.on_value(1, i64::MIN, Some(1.0)).is_empty());
+            // 从 i64::MIN 到 i64::MAX 的间隔溢出, 视为重新开始, 不填充
+            let buckets = downsampler.on_value(1, i64::MAX, Some(2.0));
+            assert_eq!(values(&buckets), [(i64::MIN, 1, Some(1.0), false)]);
+            assert_eq!(downsampler.resets(), u64::from(fill != Fill::None));
+
+            let last = downsampler.flush();
+            assert_eq!(last[0].start, i64::MAX - i64::MAX.rem_euclid(1000));
+            assert_eq!(last[0].end, i64::MAX);
+        }
+
+        // 间隔在 `max_fill` 以内时正常填充到 i64::MAX 所在的桶
+        let mut downsampler = Downsampler::new(1000, Fill::Linear);
+        let start = i64::MAX - i64::MAX.rem_euclid(1000);
+        downsampler.on_value(1, start - 2000, Some(0.0));
+        let buckets = downsampler.on_value(1, i64::MAX, Some(2.0));
+        assert_eq!(buckets.iter().map(|b| (b.start, b.filled)).collect::<Vec<_>>(), [(start - 2000, false), (start - 1000, true)]);
+        assert!(buckets[1].avg.unwrap().is_finite());
+    }
+
+    #[tokio::test]
+    async fn test_downsample_stream_by_device() {
+        let readings: Vec<Reading> = (0..12)
+            .map(|i| Reading::new(1 + i % 2, i as i64 * 500, i as f64))
+            .filter(|r| !(r.device_id == 2 && (2000..4000).contains(&r.timestamp)))
+            .collect();
+        let downsampled = DownsampleBuilder::readings(Duration::from_secs(2))
+            .fill(Fill::Previous)
+            .build(tokio_stream::iter(readings.clone()));
+        let live = StreamCollector::new(Box::pin(downsampled)).await;
+        assert_eq!(live, DownsampleBuilder::readings(Duration::from_secs(2)).fill(Fill::Previous).apply(readings));
+
+        let device = |id| live.iter().filter(|b| b.key == id).map(|b| (b.start, b.count, b.avg)).collect::<Vec<_>>();
+        assert_eq!(device(1), vec![(0, 2, Some(1.0)), (2000, 2, Some(5.0)), (4000, 2, Some(9.0))]);
+        assert_eq!(device(2), vec![(0, 2, Some(2.0)), (2000, 0, Some(3.0)), (4000, 2, Some(10.0))]);
+    }
+}
@@ -0,0 +1,22 @@
+pub mod aggregate;
+pub mod anomaly;
+pub mod base_producer;
+pub mod broker;
+pub mod codec;
+pub mod collector;
+pub mod condition;
+pub mod deterministic;
+pub mod downsample;
+pub mod metrics;
+pub mod pipeline;
+pub mod producer;
+pub mod protocol;
+pub mod reading;
+pub mod registry;
+pub mod replay;
+pub mod sim;
+pub mod shutdown;
+pub mod sink;
+pub mod stream;
+pub mod supervisor;
+pub mod window;
@@ -1,44 +1,45 @@
-use tokio_stream::StreamExt;
-use crate::collector::Collector;
-use crate::producer::{ChannelProducer, RandProducer, TCPProducer};
-
-mod collector;
-mod producer;
-mod stream;
-mod base_producer;
+use iot::metrics::MetricsRegistry;
+use iot::registry::DeviceRegistry;
+use iot::shutdown::Shutdown;
+use iot::supervisor::Supervisor;
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 1)]
 async fn main() {
-    let mut tasks: Vec<tokio::task::JoinHandle<()>> = Vec::new();
+    // Ctrl-C 后所有收集器返回已收集的数据, 然后等待生产者的线程退出
+    let shutdown = Shutdown::new();
+    shutdown.trigger_on_ctrl_c();
 
-    for i in 0..500 {
-        let task: tokio::task::JoinHandle<()> = tokio::spawn(async move {
-            if i % 3 == 0 {
-                // ChannelProducer for u16
-                let p: ChannelProducer<u16> = ChannelProducer::new();
-                let collector = Collector::new(p, i);
-                let _res = collector.await;
-                //println!("Data from ChannelProducer: {:#?}", _res);
-            } else if i % 3 == 1 {
-                // RandProducer
-                let p: RandProducer<i16> = RandProducer::default();
-                let collector = Collector::new(p, i);
-                let _res = collector.await;
-                //println!("Data from RandProducer: {:#?}", _res);
-            } else {
-                // TCPProducer
-                let addr = format!("127.0.0.1:{}", 7800 + i);
-                let p: TCPProducer<u64> = TCPProducer::new(addr);
-                let collector = Collector::new(p, i);
-                let _res = collector.await;
-                //println!("Data from TCPProducer: {:#?}", _res);
-            }
-        });
-        tasks.push(task);
+    // Prometheus 指标: curl http://127.0.0.1:9464/metrics
+    let registry = MetricsRegistry::new();
+    match tokio::net::TcpListener::bind("127.0.0.1:9464").await {
+        Ok(listener) => {
+            tokio::spawn(registry.clone().serve(listener, shutdown.clone()));
+        }
+        Err(e) => eprintln!("metrics endpoint disabled: {}", e),
     }
-    for task in tasks {
-        let _ = task.await;
+
+    // 设备配置: iot [devices.toml|devices.json], 没有参数时使用内置的 500 个设备
+    let devices = match std::env::args().nth(1) {
+        Some(path) => match DeviceRegistry::load(&path) {
+            Ok(devices) => devices,
+            Err(e) => {
+                eprintln!("failed to load {}: {}", path, e);
+                return;
+            }
+        },
+        None => DeviceRegistry::legacy(500, 7800),
+    };
+
+    let reports = Supervisor::new(devices)
+        .with_shutdown(&shutdown)
+        .with_metrics(&registry)
+        .run()
+        .await;
+    for report in reports.iter().filter(|report| report.failed()) {
+        eprintln!("device {} failed after {} restarts", report.device_id, report.restarts);
     }
+
+    let _ = tokio::task::spawn_blocking(move || shutdown.join_threads()).await;
     println!("All tasks completed");
 
 
@@ -0,0 +1,482 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Wake, Waker};
+use std::thread::ThreadId;
+use log::{debug, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time::Instant;
+use crate::shutdown::Shutdown;
+
+/// 单调递增的计数器, 克隆后共享同一个值
+#[derive(Clone, Debug, Default)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// 可以任意设置的值, 按 `f64` 的位模式保存
+#[derive(Clone, Debug, Default)]
+pub struct Gauge(Arc<AtomicU64>);
+
+impl Gauge {
+    pub fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+#[derive(Debug)]
+struct HistogramInner {
+    bounds: Vec<f64>,
+    /// 每个桶的计数(不累加), 最后一个是 `+Inf`
+    buckets: Vec<AtomicU64>,
+    sum: Mutex<f64>,
+    count: AtomicU64,
+}
+
+/// 按上界分桶统计观测值
+#[derive(Clone, Debug)]
+pub struct Histogram(Arc<HistogramInner>);
+
+impl Histogram {
+    pub fn new(bounds: &[f64]) -> Self {
+        Self(Arc::new(HistogramInner {
+            bounds: bounds.to_vec(),
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: Mutex::new(0.0),
+            count: AtomicU64::new(0),
+        }))
+    }
+
+    pub fn observe(&self, value: f64) {
+        let bucket = self.0.bounds.iter().position(|bound| value <= *bound).unwrap_or(self.0.bounds.len());
+        self.0.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        *self.0.sum.lock().unwrap() += value;
+        self.0.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.0.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> f64 {
+        *self.0.sum.lock().unwrap()
+    }
+}
+
+/// 默认的耗时分桶, 单位为秒
+pub const LATENCY_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0];
+
+/// 指标注册表, 同名同标签的指标返回同一个句柄
+///
+/// 生产者和收集器只依赖这个 trait, 可以换成其他实现, 例如 `NoopRegistry`.
+pub trait Registry: Send + Sync {
+    fn counter(&self, name: &str, help: &str, labels: &[(&str, &str)]) -> Counter;
+    fn gauge(&self, name: &str, help: &str, labels: &[(&str, &str)]) -> Gauge;
+    fn histogram(&self, name: &str, help: &str, labels: &[(&str, &str)], bounds: &[f64]) -> Histogram;
+}
+
+/// 不导出的注册表, 返回的指标照常计数但不会被任何人读取
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopRegistry;
+
+impl Registry for NoopRegistry {
+    fn counter(&self, _name: &str, _help: &str, _labels: &[(&str, &str)]) -> Counter {
+        Counter::default()
+    }
+
+    fn gauge(&self, _name: &str, _help: &str, _labels: &[(&str, &str)]) -> Gauge {
+        Gauge::default()
+    }
+
+    fn histogram(&self, _name: &str, _help: &str, _labels: &[(&str, &str)], bounds: &[f64]) -> Histogram {
+        Histogram::new(bounds)
+    }
+}
+
+#[derive(Clone)]
+enum Metric {
+    Counter(Counter),
+    Gauge(Gauge),
+    Histogram(Histogram),
+}
+
+impl Metric {
+    fn kind(&self) -> &'static str {
+        match self {
+            Metric::Counter(_) => "counter",
+            Metric::Gauge(_) => "gauge",
+            Metric::Histogram(_) => "histogram",
+        }
+    }
+}
+
+type Labels = Vec<(String, String)>;
+
+struct Family {
+    help: String,
+    kind: &'static str,
+    series: BTreeMap<Labels, Metric>,
+}
+
+/// 保存在内存中的注册表, 可以导出为 Prometheus 文本格式
+#[derive(Clone, Default)]
+pub struct MetricsRegistry {
+    families: Arc<Mutex<BTreeMap<String, Family>>>,
+}
+
+impl MetricsRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn register(&self, name: &str, help: &str, labels: &[(&str, &str)], metric: Metric) -> Metric {
+        let mut families = self.families.lock().unwrap();
+        let family = families.entry(name.to_string()).or_insert_with(|| Family {
+            help: help.to_string(),
+            kind: metric.kind(),
+            series: BTreeMap::new(),
+        });
+        if family.kind != metric.kind() {
+            warn!("metric {} is already registered as a {}, not exported", name, family.kind);
+            return metric;
+        }
+        let labels = labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        family.series.entry(labels).or_insert(metric).clone()
+    }
+
+    /// Prometheus 文本格式(0.0.4)
+    pub fn render(&self) -> String {
+        let families = self.families.lock().unwrap();
+        let mut out = String::new();
+        for (name, family) in families.iter() {
+            let _ = writeln!(out, "# HELP {} {}", name, family.help.replace('\\', "\\\\").replace('\n', "\\n"));
+            let _ = writeln!(out, "# TYPE {} {}", name, family.kind);
+            for (labels, metric) in &family.series {
+                match metric {
+                    Metric::Counter(counter) => {
+                        let _ = writeln!(out, "{}{} {}", name, format_labels(labels, None), counter.get());
+                    }
+                    Metric::Gauge(gauge) => {
+                        let _ = writeln!(out, "{}{} {}", name, format_labels(labels, None), gauge.get());
+                    }
+                    Metric::Histogram(histogram) => {
+                        let inner = &histogram.0;
+                        let mut cumulative = 0;
+                        for (i, bucket) in inner.buckets.iter().enumerate() {
+                            cumulative += bucket.load(Ordering::Relaxed);
+                            let le = inner.bounds.get(i).map_or("+Inf".to_string(), f64::to_string);
+                            let _ = writeln!(out, "{}_bucket{} {}", name, format_labels(labels, Some(&le)), cumulative);
+                        }
+                        let _ = writeln!(out, "{}_sum{} {}", name, format_labels(labels, None), histogram.sum());
+                        let _ = writeln!(out, "{}_count{} {}", name, format_labels(labels, None), histogram.count());
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// 在 `GET /metrics` 上提供 Prometheus 文本, 直到停止信号触发
+    pub async fn serve(self, listener: TcpListener, shutdown: Shutdown) -> std::io::Result<()> {
+        loop {
+            let (mut stream, addr) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                _ = shutdown.triggered() => return Ok(()),
+            };
+            let registry = self.clone();
+            tokio::spawn(async move {
+                // 只需要请求行, 读到请求头结束或缓冲区满为止
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let request_line = String::from_utf8_lossy(&request).lines().next().unwrap_or_default().to_string();
+                let mut parts = request_line.split_whitespace();
+                let response = match (parts.next(), parts.next()) {
+                    (Some("GET"), Some("/metrics")) => {
+                        let body = registry.render();
+                        format!(
+                            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                            body.len(),
+                            body
+                        )
+                    }
+                    _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+                };
+                if let Err(e) = stream.write_all(response.as_bytes()).await {
+                    debug!("failed to write metrics to {}: {}", addr, e);
+                }
+                let _ = stream.shutdown().await;
+            });
+        }
+    }
+}
+
+fn format_labels(labels: &Labels, le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+impl Registry for MetricsRegistry {
+    fn counter(&self, name: &str, help: &str, labels: &[(&str, &str)]) -> Counter {
+        match self.register(name, help, labels, Metric::Counter(Counter::default())) {
+            Metric::Counter(counter) => counter,
+            _ => unreachable!("kind checked on registration"),
+        }
+    }
+
+    fn gauge(&self, name: &str, help: &str, labels: &[(&str, &str)]) -> Gauge {
+        match self.register(name, help, labels, Metric::Gauge(Gauge::default())) {
+            Metric::Gauge(gauge) => gauge,
+            _ => unreachable!("kind checked on registration"),
+        }
+    }
+
+    fn histogram(&self, name: &str, help: &str, labels: &[(&str, &str)], bounds: &[f64]) -> Histogram {
+        match self.register(name, help, labels, Metric::Histogram(Histogram::new(bounds))) {
+            Metric::Histogram(histogram) => histogram,
+            _ => unreachable!("kind checked on registration"),
+        }
+    }
+}
+
+
+/// 统计唤醒次数的 waker, 包装 poll 时收到的 waker
+struct CountingWaker {
+    inner: Waker,
+    wakes: Counter,
+}
+
+impl Wake for CountingWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wakes.inc();
+        self.inner.wake_by_ref();
+    }
+}
+
+/// 收集器和生产者共用的 poll 计数: poll 次数, 唤醒次数, 线程迁移, 读数速率
+pub struct PollMetrics {
+    pub polls: Counter,
+    pub wakes: Counter,
+    pub readings: Counter,
+    pub readings_per_second: Gauge,
+    pub thread_migrations: Counter,
+    thread_id: Option<ThreadId>,
+    waker: Option<(Waker, Waker)>,
+    started: Option<Instant>,
+}
+
+impl PollMetrics {
+    fn new(registry: &dyn Registry, prefix: &str, labels: &[(&str, &str)]) -> Self {
+        let name = |suffix: &str| format!("{}_{}", prefix, suffix);
+        Self {
+            polls: registry.counter(&name("polls_total"), "Number of times the future or stream was polled", labels),
+            wakes: registry.counter(&name("wakes_total"), "Number of wake-ups delivered to the task", labels),
+            readings: registry.counter(&name("readings_total"), "Number of readings passed through", labels),
+            readings_per_second: registry.gauge(&name("readings_per_second"), "Average readings per second since the first poll", labels),
+            thread_migrations: registry.counter(&name("thread_migrations_total"), "Number of polls on a different thread than the previous poll", labels),
+            thread_id: None,
+            waker: None,
+            started: None,
+        }
+    }
+
+    /// 在每次 poll 开始时调用, 返回应该交给内部 future/stream 的 waker
+    pub fn on_poll(&mut self, waker: &Waker) -> Waker {
+        self.on_blocking_poll();
+        match &self.waker {
+            Some((inner, counting)) if inner.will_wake(waker) => counting.clone(),
+            _ => {
+                let counting = Waker::from(Arc::new(CountingWaker { inner: waker.clone(), wakes: self.wakes.clone() }));
+                self.waker = Some((waker.clone(), counting.clone()));
+                counting
+            }
+        }
+    }
+
+    /// 在阻塞线程中同步调用生产者时使用, 没有 waker 需要包装
+    pub fn on_blocking_poll(&mut self) {
+        self.polls.inc();
+        self.started.get_or_insert_with(Instant::now);
+        let current = std::thread::current().id();
+        if self.thread_id.replace(current).is_some_and(|previous| previous != current) {
+            self.thread_migrations.inc();
+        }
+    }
+
+    pub fn on_reading(&self) {
+        self.readings.inc();
+        if let Some(elapsed) = self.started.map(|started| started.elapsed().as_secs_f64()) {
+            if elapsed > 0.0 {
+                self.readings_per_second.set(self.readings.get() as f64 / elapsed);
+            }
+        }
+    }
+
+    /// 从第一次 poll 开始经过的时间
+    pub fn elapsed(&self) -> std::time::Duration {
+        self.started.map(|started| started.elapsed()).unwrap_or_default()
+    }
+}
+
+/// 一个收集器的指标, 以 `collector` 标签区分
+pub struct CollectorMetrics {
+    pub poll: PollMetrics,
+    /// 从第一次 poll 到完成的耗时(秒)
+    pub time_to_ready: Histogram,
+    pub waker_changes: Counter,
+}
+
+impl CollectorMetrics {
+    pub fn new(registry: &dyn Registry, collector: &str) -> Self {
+        let labels = [("collector", collector)];
+        Self {
+            poll: PollMetrics::new(registry, "iot_collector", &labels),
+            time_to_ready: registry.histogram(
+                "iot_collector_time_to_ready_seconds",
+                "Time from the first poll until the collector completed",
+                &labels,
+                LATENCY_BUCKETS,
+            ),
+            waker_changes: registry.counter(
+                "iot_collector_waker_changes_total",
+                "Number of times the producer was handed a different waker",
+                &labels,
+            ),
+        }
+    }
+
+    pub fn on_ready(&self) {
+        self.time_to_ready.observe(self.poll.elapsed().as_secs_f64());
+    }
+}
+
+/// 一个生产者的指标, 以 `producer` 标签区分
+pub struct ProducerMetrics {
+    pub poll: PollMetrics,
+    /// 通道中等待读取的读数个数
+    pub channel_depth: Gauge,
+}
+
+impl ProducerMetrics {
+    pub fn new(registry: &dyn Registry, producer: &str) -> Self {
+        let labels = [("producer", producer)];
+        Self {
+            poll: PollMetrics::new(registry, "iot_producer", &labels),
+            channel_depth: registry.gauge("iot_producer_channel_depth", "Readings buffered in the producer channel", &labels),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use super::*;
+
+    #[test]
+    fn test_render_prometheus_text() {
+        let registry = MetricsRegistry::new();
+        registry.counter("requests_total", "Requests", &[("path", "/a\"b")]).add(3);
+        // 同名同标签返回同一个计数器
+        registry.counter("requests_total", "Requests", &[("path", "/a\"b")]).inc();
+        registry.gauge("depth", "Depth", &[]).set(2.5);
+        let histogram = registry.histogram("latency_seconds", "Latency", &[], &[0.1, 1.0]);
+        histogram.observe(0.05);
+        histogram.observe(0.5);
+        histogram.observe(3.0);
+
+        let text = registry.render();
+        let expected = "\
+# HELP depth Depth
+# TYPE depth gauge
+depth 2.5
+# HELP latency_seconds Latency
+# TYPE latency_seconds histogram
+latency_seconds_bucket{le=\"0.1\"} 1
+latency_seconds_bucket{le=\"1\"} 2
+latency_seconds_bucket{le=\"+Inf\"} 3
+latency_seconds_sum 3.55
+latency_seconds_count 3
+# HELP requests_total Requests
+# TYPE requests_total counter
+requests_total{path=\"/a\\\"b\"} 4
+";
+        assert_eq!(text, expected);
+    }
+
+    #[test]
+    fn test_producer_metrics() {
+        let registry = MetricsRegistry::new();
+        let mut metrics = ProducerMetrics::new(&registry, "7");
+        for _ in 0..3 {
+            metrics.poll.on_blocking_poll();
+        }
+        metrics.poll.on_reading();
+        metrics.poll.on_reading();
+        metrics.channel_depth.set(1.0);
+
+        let text = registry.render();
+        assert!(text.contains("# TYPE iot_producer_channel_depth gauge\niot_producer_channel_depth{producer=\"7\"} 1\n"));
+        assert!(text.contains("iot_producer_polls_total{producer=\"7\"} 3\n"));
+        assert!(text.contains("iot_producer_readings_total{producer=\"7\"} 2\n"));
+        assert!(text.contains("iot_producer_thread_migrations_total{producer=\"7\"} 0\n"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint() {
+        let registry = MetricsRegistry::new();
+        registry.counter("iot_up", "Up", &[]).inc();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = Shutdown::new();
+        let server = tokio::spawn(registry.serve(listener, shutdown.clone()));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("# TYPE iot_up counter\niot_up 1\n"));
+
+        shutdown.trigger();
+        server.await.unwrap().unwrap();
+    }
+}
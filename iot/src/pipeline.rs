@@ -0,0 +1,423 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use futures::stream::FuturesOrdered;
+use num_traits::ToPrimitive;
+use pin_project::pin_project;
+use serde::{Deserialize, Serialize};
+use tokio_stream::Stream;
+use crate::base_producer::Producer;
+
+/// 单个处理阶段的计数器
+#[derive(Debug, Default)]
+pub struct StageMetrics {
+    pub name: String,
+    /// 从上游收到的数据个数
+    pub received: AtomicU64,
+    /// 向下游输出的数据个数
+    pub emitted: AtomicU64,
+    /// 被过滤或去重丢弃的数据个数
+    pub dropped: AtomicU64,
+    /// 正在执行的异步任务个数
+    pub in_flight: AtomicU64,
+}
+
+impl StageMetrics {
+    fn new(name: impl Into<String>) -> Arc<Self> {
+        Arc::new(Self { name: name.into(), ..Default::default() })
+    }
+
+    pub fn snapshot(&self) -> StageSnapshot {
+        StageSnapshot {
+            name: self.name.clone(),
+            received: self.received.load(Ordering::Relaxed),
+            emitted: self.emitted.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// `StageMetrics` 在某一时刻的值
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StageSnapshot {
+    pub name: String,
+    pub received: u64,
+    pub emitted: u64,
+    pub dropped: u64,
+    pub in_flight: u64,
+}
+
+/// 线性校准 `value * scale + offset`, 也用于单位换算, 默认不做变换
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Calibration {
+    pub scale: f64,
+    pub offset: f64,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self::linear(1.0, 0.0)
+    }
+}
+
+impl Calibration {
+    pub fn linear(scale: f64, offset: f64) -> Self {
+        Self { scale, offset }
+    }
+
+    pub fn celsius_to_fahrenheit() -> Self {
+        Self::linear(1.8, 32.0)
+    }
+
+    pub fn fahrenheit_to_celsius() -> Self {
+        Self::linear(5.0 / 9.0, -32.0 * 5.0 / 9.0)
+    }
+
+    pub fn celsius_to_kelvin() -> Self {
+        Self::linear(1.0, 273.15)
+    }
+
+    /// 乘以固定系数, 例如 mV -> V 为 `factor(0.001)`
+    pub fn factor(factor: f64) -> Self {
+        Self::linear(factor, 0.0)
+    }
+
+    /// 先应用 `self` 再应用 `next`
+    pub fn then(self, next: Calibration) -> Self {
+        Self::linear(self.scale * next.scale, self.offset * next.scale + next.offset)
+    }
+
+    pub fn apply(&self, value: f64) -> f64 {
+        value * self.scale + self.offset
+    }
+}
+
+type BoxStream<T> = Pin<Box<dyn Stream<Item = T> + Send>>;
+
+/// 在接收端和消费者之间串联的处理阶段
+///
+/// 每个阶段只在下游拉取时才向上游拉取数据, 所以消费慢时数据会积压在
+/// `Producer` 的 mpsc 通道里, 发送端的 `send` 会等待, 形成背压.
+pub struct Pipeline<T> {
+    stream: BoxStream<T>,
+    metrics: Vec<Arc<StageMetrics>>,
+}
+
+impl<T: Send + 'static> Producer<T> {
+    pub fn into_pipeline(self) -> Pipeline<T> {
+        Pipeline::new(self)
+    }
+}
+
+impl<T: Send + 'static> Pipeline<T> {
+    pub fn new(stream: impl Stream<Item = T> + Send + 'static) -> Self {
+        Self { stream: Box::pin(stream), metrics: Vec::new() }
+    }
+
+    /// 各阶段的计数器, 按添加顺序排列
+    pub fn metrics(&self) -> Vec<Arc<StageMetrics>> {
+        self.metrics.clone()
+    }
+
+    fn stage<U, F>(mut self, name: &str, f: F) -> Pipeline<U>
+    where
+        U: Send + 'static,
+        F: FnMut(T) -> Option<U> + Send + 'static,
+    {
+        let metrics = StageMetrics::new(name);
+        self.metrics.push(metrics.clone());
+        Pipeline {
+            stream: Box::pin(Stage { stream: self.stream, f, metrics }),
+            metrics: self.metrics,
+        }
+    }
+
+    pub fn map<U, F>(self, name: &str, mut f: F) -> Pipeline<U>
+    where
+        U: Send + 'static,
+        F: FnMut(T) -> U + Send + 'static,
+    {
+        self.stage(name, move |value| Some(f(value)))
+    }
+
+    pub fn filter<F>(self, name: &str, mut predicate: F) -> Pipeline<T>
+    where
+        F: FnMut(&T) -> bool + Send + 'static,
+    {
+        self.stage(name, move |value| predicate(&value).then_some(value))
+    }
+
+    /// 丢弃与上一个数据相同的数据
+    pub fn dedup(self, name: &str) -> Pipeline<T>
+    where
+        T: PartialEq + Clone,
+    {
+        let mut last: Option<T> = None;
+        self.stage(name, move |value| {
+            if last.as_ref() == Some(&value) {
+                return None;
+            }
+            last = Some(value.clone());
+            Some(value)
+        })
+    }
+
+    pub fn calibrate(self, name: &str, calibration: Calibration) -> Pipeline<f64>
+    where
+        T: ToPrimitive,
+    {
+        self.stage(name, move |value| value.to_f64().map(|v| calibration.apply(v)))
+    }
+
+    /// 相邻两个数据之间至少间隔 `interval`, 等待期间不向上游拉取数据
+    pub fn rate_limit(mut self, name: &str, interval: Duration) -> Pipeline<T> {
+        let metrics = StageMetrics::new(name);
+        self.metrics.push(metrics.clone());
+        Pipeline {
+            stream: Box::pin(RateLimit { stream: self.stream, interval, delay: None, metrics }),
+            metrics: self.metrics,
+        }
+    }
+
+    /// 异步处理数据, 最多同时执行 `concurrency` 个任务, 输出保持输入顺序
+    pub fn map_async<U, F, Fut>(mut self, name: &str, concurrency: usize, f: F) -> Pipeline<U>
+    where
+        U: Send + 'static,
+        F: FnMut(T) -> Fut + Send + 'static,
+        Fut: Future<Output = U> + Send + 'static,
+    {
+        let metrics = StageMetrics::new(name);
+        self.metrics.push(metrics.clone());
+        Pipeline {
+            stream: Box::pin(AsyncMap {
+                stream: self.stream,
+                f,
+                in_flight: FuturesOrdered::new(),
+                concurrency: concurrency.max(1),
+                upstream_done: false,
+                metrics,
+            }),
+            metrics: self.metrics,
+        }
+    }
+}
+
+impl<T> Stream for Pipeline<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.stream.as_mut().poll_next(cx)
+    }
+}
+
+
+/// 同步阶段, `f` 返回 `None` 表示丢弃该数据
+#[pin_project]
+struct Stage<S, F> {
+    #[pin]
+    stream: S,
+    f: F,
+    metrics: Arc<StageMetrics>,
+}
+
+impl<S, F, U> Stream for Stage<S, F>
+where
+    S: Stream,
+    F: FnMut(S::Item) -> Option<U>,
+{
+    type Item = U;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            match this.stream.as_mut().poll_next(cx) {
+                Poll::Ready(Some(value)) => {
+                    this.metrics.received.fetch_add(1, Ordering::Relaxed);
+                    match (this.f)(value) {
+                        Some(output) => {
+                            this.metrics.emitted.fetch_add(1, Ordering::Relaxed);
+                            return Poll::Ready(Some(output));
+                        }
+                        None => {
+                            this.metrics.dropped.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+
+#[pin_project]
+struct RateLimit<S> {
+    #[pin]
+    stream: S,
+    interval: Duration,
+    delay: Option<Pin<Box<tokio::time::Sleep>>>,
+    metrics: Arc<StageMetrics>,
+}
+
+impl<S: Stream> Stream for RateLimit<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        if let Some(delay) = this.delay.as_mut() {
+            if delay.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+        match this.stream.poll_next(cx) {
+            Poll::Ready(Some(value)) => {
+                this.metrics.received.fetch_add(1, Ordering::Relaxed);
+                this.metrics.emitted.fetch_add(1, Ordering::Relaxed);
+                let deadline = tokio::time::Instant::now() + *this.interval;
+                match this.delay.as_mut() {
+                    Some(delay) => delay.as_mut().reset(deadline),
+                    None => *this.delay = Some(Box::pin(tokio::time::sleep_until(deadline))),
+                }
+                Poll::Ready(Some(value))
+            }
+            other => other,
+        }
+    }
+}
+
+
+#[pin_project]
+struct AsyncMap<S, F, Fut: Future> {
+    #[pin]
+    stream: S,
+    f: F,
+    in_flight: FuturesOrdered<Fut>,
+    concurrency: usize,
+    upstream_done: bool,
+    metrics: Arc<StageMetrics>,
+}
+
+impl<S, F, Fut> Stream for AsyncMap<S, F, Fut>
+where
+    S: Stream,
+    F: FnMut(S::Item) -> Fut,
+    Fut: Future,
+{
+    type Item = Fut::Output;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        // 只有在并发数未满时才向上游拉取数据
+        while !*this.upstream_done && this.in_flight.len() < *this.concurrency {
+            match this.stream.as_mut().poll_next(cx) {
+                Poll::Ready(Some(value)) => {
+                    this.metrics.received.fetch_add(1, Ordering::Relaxed);
+                    this.in_flight.push_back((this.f)(value));
+                }
+                Poll::Ready(None) => *this.upstream_done = true,
+                Poll::Pending => break,
+            }
+        }
+
+        let poll = match Pin::new(&mut *this.in_flight).poll_next(cx) {
+            Poll::Ready(Some(output)) => {
+                this.metrics.emitted.fetch_add(1, Ordering::Relaxed);
+                Poll::Ready(Some(output))
+            }
+            Poll::Ready(None) if *this.upstream_done => Poll::Ready(None),
+            Poll::Ready(None) | Poll::Pending => Poll::Pending,
+        };
+        this.metrics.in_flight.store(this.in_flight.len() as u64, Ordering::Relaxed);
+        poll
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc::channel;
+    use tokio_stream::StreamExt;
+    use crate::stream::{DataAvailable, Pacing};
+    use super::*;
+
+    #[test]
+    fn test_calibration() {
+        assert_eq!(Calibration::celsius_to_fahrenheit().apply(100.0), 212.0);
+        let roundtrip = Calibration::celsius_to_fahrenheit().then(Calibration::fahrenheit_to_celsius());
+        assert!((roundtrip.apply(37.0) - 37.0).abs() < 1e-9);
+        assert_eq!(Calibration::factor(0.001).then(Calibration::linear(2.0, 1.0)).apply(500.0), 2.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pipeline_stages() {
+        let (tx, rx) = channel(4);
+        let producer = Producer::new(DataAvailable::with_pacing(Pacing::Fixed(Duration::ZERO)), rx, Box::new(|x: i32| x));
+        tokio::spawn(async move {
+            for value in [1, 1, 2, -3, 4, 4, 5] {
+                tx.send(Ok(value)).await.unwrap();
+            }
+        });
+
+        let pipeline = producer
+            .into_pipeline()
+            .dedup("dedup")
+            .filter("positive", |v| *v > 0)
+            .map_async("double", 2, |v| async move {
+                tokio::time::sleep(Duration::from_millis(10 * (5 - v as u64))).await;
+                v * 2
+            })
+            .calibrate("celsius", Calibration::linear(0.5, 1.0));
+        let metrics = pipeline.metrics();
+        let output: Vec<f64> = pipeline.collect().await;
+
+        assert_eq!(output, vec![2.0, 3.0, 5.0, 6.0]);
+        let snapshots: Vec<(String, u64, u64, u64)> = metrics
+            .iter()
+            .map(|m| m.snapshot())
+            .map(|s| (s.name, s.received, s.emitted, s.dropped))
+            .collect();
+        assert_eq!(
+            snapshots,
+            vec![
+                ("dedup".to_string(), 7, 5, 2),
+                ("positive".to_string(), 5, 4, 1),
+                ("double".to_string(), 4, 4, 0),
+                ("celsius".to_string(), 4, 4, 0),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_backpressure() {
+        let (tx, rx) = channel(2);
+        let producer = Producer::new(DataAvailable::with_pacing(Pacing::Fixed(Duration::ZERO)), rx, Box::new(|x: u32| x));
+        let sender = tokio::spawn(async move {
+            let mut sent = 0;
+            for value in 0..100u32 {
+                if tx.send(Ok(value)).await.is_err() {
+                    break;
+                }
+                sent += 1;
+            }
+            sent
+        });
+
+        let start = tokio::time::Instant::now();
+        let mut pipeline = producer.into_pipeline().rate_limit("limit", Duration::from_millis(100));
+        let first: Vec<u32> = (&mut pipeline).take(3).collect().await;
+        assert_eq!(first, vec![0, 1, 2]);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        // 消费者停止拉取后, 发送端被通道容量挡住
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!sender.is_finished());
+        drop(pipeline);
+        assert!(sender.await.unwrap() <= 3 + 2 + 1);
+    }
+}
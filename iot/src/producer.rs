@@ -1,61 +1,164 @@
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::sync::mpsc::{sync_channel, Receiver, SendError, SyncSender};
-use std::task::Waker;
+use std::collections::{HashMap, VecDeque};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::task::{Poll, Waker};
 use std::thread::{sleep, spawn};
 use std::time::Duration;
 use rand::distributions::uniform::SampleUniform;
-use rand::{thread_rng, Rng};
+use log::{debug, warn};
+use rand::rngs::StdRng;
+use rand::Rng;
+use crate::deterministic::{Determinism, Throttle, STREAM_SESSION, STREAM_VALUES};
+use crate::protocol::{
+    check_seq, decode_block_frame_header, decode_push, decode_request, decode_response, encode_request, encode_response,
+    push_frame_len, response_len, Backoff, DeviceSession, SeqCheck, SeqEvent, SeqTracker, BLOCK_FRAME_HEADER_LEN,
+    HELLO_LEN, REQUEST_LEN,
+};
+use crate::codec::decode_block;
+use crate::shutdown::Shutdown;
 
 
+/// 数值在线路上的字节序
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Endian {
+    #[default]
+    Little,
+    Big,
+}
+
 // Define a trait for types that can be converted to and from bytes
 pub trait ToBytes: Sized {
+    /// 编码后的字节数, 结构体为各字段之和, 不包含内存对齐的填充
+    const SIZE: usize;
+
     fn to_le_bytes(&self) -> Vec<u8>;
     fn from_le_bytes(bytes: &[u8]) -> Self;
+    fn to_be_bytes(&self) -> Vec<u8>;
+    fn from_be_bytes(bytes: &[u8]) -> Self;
+
+    fn to_bytes(&self, endian: Endian) -> Vec<u8> {
+        match endian {
+            Endian::Little => self.to_le_bytes(),
+            Endian::Big => self.to_be_bytes(),
+        }
+    }
+
+    /// `bytes` 的长度必须等于 `SIZE`
+    fn from_bytes(bytes: &[u8], endian: Endian) -> Self {
+        match endian {
+            Endian::Little => Self::from_le_bytes(bytes),
+            Endian::Big => Self::from_be_bytes(bytes),
+        }
+    }
 }
 
 
 macro_rules! impl_tobytes_for {
-    ($t:ty) => {
-        impl ToBytes for $t {
+    ($($t:ty),*) => {
+        $(
+            impl ToBytes for $t {
+                const SIZE: usize = std::mem::size_of::<Self>();
+
+                fn to_le_bytes(&self) -> Vec<u8> {
+                    Self::to_le_bytes(*self).to_vec()
+                }
+
+                fn from_le_bytes(bytes: &[u8]) -> Self {
+                    let array: [u8; std::mem::size_of::<Self>()] =
+                        bytes.try_into().expect("slice with incorrect length");
+                    Self::from_le_bytes(array)
+                }
+
+                fn to_be_bytes(&self) -> Vec<u8> {
+                    Self::to_be_bytes(*self).to_vec()
+                }
+
+                fn from_be_bytes(bytes: &[u8]) -> Self {
+                    let array: [u8; std::mem::size_of::<Self>()] =
+                        bytes.try_into().expect("slice with incorrect length");
+                    Self::from_be_bytes(array)
+                }
+            }
+        )*
+    };
+}
+
+// Implement ToBytes for all primitive numeric types
+impl_tobytes_for!(i8, i16, i32, i64, i128, u8, u16, u32, u64, u128, f32, f64);
+
+
+/// 为由 `ToBytes` 字段组成的结构体实现 `ToBytes`, 字段按声明顺序紧密排列
+///
+/// ```ignore
+/// struct Telemetry { device_id: u32, ts: i64, value: f32 }
+/// impl_tobytes_for_struct!(Telemetry { device_id: u32, ts: i64, value: f32 });
+/// ```
+#[macro_export]
+macro_rules! impl_tobytes_for_struct {
+    ($name:ident { $($field:ident: $t:ty),* $(,)? }) => {
+        impl $crate::producer::ToBytes for $name {
+            const SIZE: usize = 0 $(+ <$t as $crate::producer::ToBytes>::SIZE)*;
+
             fn to_le_bytes(&self) -> Vec<u8> {
-                Self::to_le_bytes(*self).to_vec()
+                <Self as $crate::producer::ToBytes>::to_bytes(self, $crate::producer::Endian::Little)
             }
 
             fn from_le_bytes(bytes: &[u8]) -> Self {
-                let array: [u8; std::mem::size_of::<Self>()] =
-                    bytes.try_into().expect("slice with incorrect length");
-                Self::from_le_bytes(array)
+                <Self as $crate::producer::ToBytes>::from_bytes(bytes, $crate::producer::Endian::Little)
+            }
+
+            fn to_be_bytes(&self) -> Vec<u8> {
+                <Self as $crate::producer::ToBytes>::to_bytes(self, $crate::producer::Endian::Big)
+            }
+
+            fn from_be_bytes(bytes: &[u8]) -> Self {
+                <Self as $crate::producer::ToBytes>::from_bytes(bytes, $crate::producer::Endian::Big)
+            }
+
+            fn to_bytes(&self, endian: $crate::producer::Endian) -> Vec<u8> {
+                let mut bytes = Vec::with_capacity(<Self as $crate::producer::ToBytes>::SIZE);
+                $(bytes.extend($crate::producer::ToBytes::to_bytes(&self.$field, endian));)*
+                bytes
+            }
+
+            fn from_bytes(bytes: &[u8], endian: $crate::producer::Endian) -> Self {
+                assert_eq!(bytes.len(), <Self as $crate::producer::ToBytes>::SIZE, "slice with incorrect length");
+                let mut offset = 0;
+                $(
+                    let size = <$t as $crate::producer::ToBytes>::SIZE;
+                    let $field = <$t as $crate::producer::ToBytes>::from_bytes(&bytes[offset..offset + size], endian);
+                    offset += size;
+                )*
+                let _ = offset;
+                Self { $($field),* }
             }
         }
     };
 }
 
-// Implement ToBytes for some integer types
-impl_tobytes_for!(i8);
-impl_tobytes_for!(u64);
-impl_tobytes_for!(i32);
-impl_tobytes_for!(i16);
-impl_tobytes_for!(u16);
-
 pub trait Producer<T> {
-    /// 产生一个新数据
-    fn produce(&mut self) -> T;
+    /// 产生一个新数据, 生产者已经无法再产生数据时返回 `None`
+    fn produce(&mut self) -> Option<T>;
 
-    /// 校验生产者是否还有新数据
-    fn data_available(&self) -> bool;
+    /// 校验生产者是否还有新数据, 返回 `false` 时不阻塞, 可以继续时唤醒存储的唤醒器
+    fn data_available(&mut self) -> bool;
 
-    /// 存储future的当前唤醒器
-    fn store_waker(&mut self, waker: &Waker) {
+    /// 存储future的当前唤醒器, 返回是否替换了之前存储的唤醒器
+    fn store_waker(&mut self, waker: &Waker) -> bool {
         match self.get_waker() {
             None => {
-                self.set_waker(Some(waker.clone()))
+                self.set_waker(Some(waker.clone()));
+                false
             },
             Some(old_waker) => {
-                if !waker.will_wake(old_waker) {
-                    println!("---------------------> Waker Changed");
+                let changed = !waker.will_wake(old_waker);
+                if changed {
                     self.set_waker(Some(waker.clone()))
                 }
+                changed
             }
         }
     }
@@ -63,6 +166,11 @@ pub trait Producer<T> {
     /// 给生产者发送停止信号
     fn stop(&mut self) {}
 
+    /// 停止后取出已经到达但还没有被 `produce` 读取的数据, 不等待新数据
+    fn drain(&mut self) -> Vec<T> {
+        Vec::new()
+    }
+
     fn set_waker(&mut self, waker: Option<Waker>);
 
     fn get_waker(&self) -> Option<&Waker>;
@@ -79,23 +187,47 @@ macro_rules! impl_waker_methods {
         }
     };
 }
+pub(crate) use impl_waker_methods;
 
 
 /// 给异步收集器产生随机数
-#[derive(Default)]
 pub struct RandProducer<T> {
     /// Waker for waking up async tasks
     waker: Option<Waker>,
+    rng: StdRng,
+    throttle: Throttle,
     /// Marker for generic type T
     _marker: std::marker::PhantomData<T>,
 }
 
+impl<T> Default for RandProducer<T> {
+    fn default() -> Self {
+        Self::with_determinism(Determinism::default())
+    }
+}
+
+impl<T> RandProducer<T> {
+    /// 读数和等待时间都由 `determinism` 决定
+    pub fn with_determinism(determinism: Determinism) -> Self {
+        Self {
+            waker: None,
+            rng: determinism.rng(STREAM_VALUES),
+            throttle: Throttle::new(&determinism, 100..=1000),
+            _marker: std::marker::PhantomData,
+        }
+    }
+
+    /// `Clock::Virtual` 下 `data_available` 累计的等待时间
+    pub fn waited(&self) -> Duration {
+        self.throttle.waited()
+    }
+}
+
 impl<T> Producer<T> for RandProducer<T>
 where
     T: PartialOrd + From<u8> + SampleUniform
 {
-    fn produce(&mut self) -> T {
-        let mut rng = thread_rng();
+    fn produce(&mut self) -> Option<T> {
         if let Some(waker) = &self.waker {
             waker.wake_by_ref();
         }
@@ -104,12 +236,11 @@ where
             start: T::from(1),
             end: T::from(10)
         };
-        rng.gen_range(r)
+        Some(self.rng.gen_range(r))
     }
 
-    fn data_available(&self) -> bool {
-        let mut rng = thread_rng();
-        sleep(Duration::from_millis(rng.gen_range(100..=1000)));
+    fn data_available(&mut self) -> bool {
+        self.throttle.wait();
         true
     }
 
@@ -124,17 +255,12 @@ pub struct ChannelProducer<T> {
     sender: SyncSender<T>,
     /// 从其他线程接受数据
     receiver: Receiver<T>,
+    throttle: Throttle,
 }
 
 impl<T> Default for ChannelProducer<T> {
     fn default() -> Self {
-        // bound为0, 表示发送方会阻塞, 直到接收方获取数据
-        let (sender, receiver) = sync_channel::<T>(0);
-        ChannelProducer {
-            waker: None,
-            sender,
-            receiver,
-        }
+        Self::unstarted(&Determinism::default())
     }
 }
 
@@ -144,37 +270,78 @@ where
 {
     #[allow(dead_code)]
     pub fn new() -> Self {
-        let prod = Self::default();
+        Self::with_options(Determinism::default(), None)
+    }
+
+    /// 发送线程在停止信号触发或生产者被丢弃后退出, 由 `Shutdown::join_threads` 等待
+    pub fn with_shutdown(shutdown: &Shutdown) -> Self {
+        Self::with_options(Determinism::default(), Some(shutdown))
+    }
+
+    /// 发送线程的读数和 `data_available` 的等待时间由 `determinism` 决定,
+    /// 通道没有缓冲, 所以收到的读数序列只取决于种子
+    pub fn with_options(determinism: Determinism, shutdown: Option<&Shutdown>) -> Self {
+        let prod = Self::unstarted(&determinism);
         let sender = prod.sender.clone();
-        spawn(move || loop {
-            let mut rng = thread_rng();
+        let rng = determinism.rng(STREAM_VALUES);
+        match shutdown {
+            Some(shutdown) => {
+                let thread_shutdown = shutdown.clone();
+                shutdown.spawn_thread(move || Self::send_loop(sender, rng, Some(thread_shutdown)));
+            }
+            None => {
+                spawn(move || Self::send_loop(sender, rng, None));
+            }
+        }
+        prod
+    }
+
+    fn send_loop(sender: SyncSender<T>, mut rng: StdRng, shutdown: Option<Shutdown>) {
+        while !shutdown.as_ref().is_some_and(Shutdown::is_triggered) {
             let r = std::ops::Range::<T> {
                 start: T::from(1),
                 end: T::from(100),
             };
             let val = rng.gen_range(r);
-            match sender.send(val) {
-                Ok(_) => continue,
-                Err(_) => break
+            // 接收端被丢弃时发送失败
+            if sender.send(val).is_err() {
+                break;
             }
-        });
-        prod
+        }
     }
 }
 
 
+impl<T> ChannelProducer<T> {
+    fn unstarted(determinism: &Determinism) -> Self {
+        // bound为0, 表示发送方会阻塞, 直到接收方获取数据
+        let (sender, receiver) = sync_channel::<T>(0);
+        ChannelProducer {
+            waker: None,
+            sender,
+            receiver,
+            throttle: Throttle::new(determinism, 100..=1000),
+        }
+    }
+
+    /// `Clock::Virtual` 下 `data_available` 累计的等待时间
+    pub fn waited(&self) -> Duration {
+        self.throttle.waited()
+    }
+}
+
 impl<T> Producer<T> for ChannelProducer<T> {
-    fn produce(&mut self) -> T {
-        let data = self.receiver.recv().unwrap();
+    fn produce(&mut self) -> Option<T> {
+        // 发送线程退出后没有新数据
+        let data = self.receiver.recv().ok()?;
         if let Some(waker) = &self.waker {
             waker.wake_by_ref();
         }
-        data
+        Some(data)
     }
 
-    fn data_available(&self) -> bool {
-        let mut rng = thread_rng();
-        sleep(Duration::from_millis(rng.gen_range(100..=1000)));
+    fn data_available(&mut self) -> bool {
+        self.throttle.wait();
         true
     }
 
@@ -182,129 +349,1061 @@ impl<T> Producer<T> for ChannelProducer<T> {
 }
 
 
-pub struct TCPProducer<T> {
-    waker: Option<Waker>,
-    stream: TcpStream,
-    _marker: std::marker::PhantomData<T>,
+/// TCP 线路协议的命令: `ACK` 请求一个数据, `STOP` 通知设备停止, 帧格式见 `protocol`
+pub const ACK: i8 = 0;
+pub const STOP: i8 = -1;
+
+/// 设备断开后等待重连的配置
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    /// 第一次检查新连接前的等待时间, 之后每次翻倍
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// 超过该次数仍没有设备连接时放弃, 数据流结束
+    pub max_attempts: u32,
 }
 
-impl<T> TCPProducer<T>
-where
-    T: ToBytes + PartialOrd + From<u8> + SampleUniform
-{
-    const STOP: i8 = -1;
-    const ACK: i8 = 0;
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+            max_attempts: 10,
+        }
+    }
+}
 
-    #[allow(dead_code)]
-    pub fn new(addr: impl Into<String>) -> Self {
-        let addr: String = addr.into();
-        let listener = TcpListener::bind(addr.clone()).expect("Build TCP listener");
-        let server_handle = spawn(move || listener.accept().expect("Failed to accept connection"));
+/// 链路统计
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    /// 设备重新连接的次数
+    pub reconnects: u64,
+    /// 被丢弃的重复读数
+    pub duplicates: u64,
+    /// 序号不连续导致丢失的读数, 迟到的读数会从中扣除
+    pub lost: u64,
+    /// 迟到(乱序)但仍被接受的读数
+    pub reordered: u64,
+    /// 长度或序号不合法而被丢弃的数据帧
+    pub malformed: u64,
+}
 
-        // 生成一个线程通过 TCP 发送数据
-        spawn({
-            move || {
-                if let Err(_e) = TCPProducer::<T>::send_data(addr) {}
+impl LinkStats {
+    /// 按推送模式的序号判断更新统计, 返回读数是否应该被接受
+    fn record(&mut self, event: SeqEvent) -> bool {
+        match event {
+            SeqEvent::InOrder => true,
+            SeqEvent::Gap(lost) => {
+                self.lost += lost;
+                true
             }
-        });
+            SeqEvent::Reordered => {
+                self.lost = self.lost.saturating_sub(1);
+                self.reordered += 1;
+                true
+            }
+            SeqEvent::Duplicate => {
+                self.duplicates += 1;
+                false
+            }
+            SeqEvent::Invalid => {
+                self.malformed += 1;
+                false
+            }
+        }
+    }
+}
+
+/// 设备断开后以非阻塞方式检查监听器上的新连接, 两次检查之间按指数退避等待.
+/// 等待期间不阻塞线程, 到下一次检查的时间后唤醒收集器.
+struct Reconnect {
+    backoff: Backoff,
+    max_attempts: u32,
+    /// 下一次检查新连接的时间
+    retry_at: tokio::time::Instant,
+}
 
-        let (stream, _addr) = server_handle.join().unwrap();
+impl Reconnect {
+    fn new(policy: &ReconnectPolicy) -> Self {
         Self {
+            backoff: Backoff::new(policy.initial_backoff, policy.max_backoff),
+            max_attempts: policy.max_attempts,
+            retry_at: tokio::time::Instant::now(),
+        }
+    }
+
+    /// 距离下一次检查的时间
+    fn remaining(&self) -> Duration {
+        self.retry_at.saturating_duration_since(tokio::time::Instant::now())
+    }
+
+    /// 到了检查时间时尝试一次 `accept`, 没有新连接时返回 `Pending` 并在下一次检查时唤醒 `waker`,
+    /// 超过 `max_attempts` 次后超时
+    fn poll_accept<S>(
+        &mut self,
+        waker: Option<&Waker>,
+        set_nonblocking: impl Fn(bool) -> std::io::Result<()>,
+        accept: impl FnOnce() -> std::io::Result<S>,
+    ) -> Poll<std::io::Result<S>> {
+        if self.remaining() > Duration::ZERO {
+            wake_at(self.retry_at, waker);
+            return Poll::Pending;
+        }
+        set_nonblocking(true)?;
+        let accepted = accept();
+        set_nonblocking(false)?;
+        match accepted {
+            Ok(stream) => Poll::Ready(Ok(stream)),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                if self.backoff.attempts() >= self.max_attempts {
+                    return Poll::Ready(Err(std::io::Error::new(ErrorKind::TimedOut, "device did not reconnect")));
+                }
+                self.retry_at = tokio::time::Instant::now() + self.backoff.next_delay();
+                wake_at(self.retry_at, waker);
+                Poll::Pending
+            }
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+/// 唤醒当前线程的唤醒器, 在阻塞线程中驱动生产者时配合 `std::thread::park` 使用
+pub(crate) fn thread_waker() -> Waker {
+    struct ThreadWaker(std::thread::Thread);
+
+    impl std::task::Wake for ThreadWaker {
+        fn wake(self: std::sync::Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    Waker::from(std::sync::Arc::new(ThreadWaker(std::thread::current())))
+}
+
+/// 在 `at` 时唤醒 `waker`: 在 tokio 运行时中使用定时器, 否则使用一个等待线程
+fn wake_at(at: tokio::time::Instant, waker: Option<&Waker>) {
+    let Some(waker) = waker.cloned() else {
+        return;
+    };
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) => {
+            handle.spawn(async move {
+                tokio::time::sleep_until(at).await;
+                waker.wake();
+            });
+        }
+        Err(_) => {
+            spawn(move || {
+                sleep(at.saturating_duration_since(tokio::time::Instant::now()));
+                waker.wake();
+            });
+        }
+    }
+}
+
+pub struct TCPProducer<T> {
+    waker: Option<Waker>,
+    listener: TcpListener,
+    /// 设备断开后为 `None`, 下一次读取时重新等待连接
+    stream: Option<TcpStream>,
+    policy: ReconnectPolicy,
+    /// 等待设备重连的状态
+    reconnect: Option<Reconnect>,
+    /// 设备当前的会话号, 变化说明设备重启过, 序号重新从 0 开始
+    session: Option<u64>,
+    /// 下一个期望收到的序号
+    expected: u64,
+    /// 已经收到但还没有产出的读数
+    ready: Option<T>,
+    /// 设备没有在重连策略内回来, 数据流结束
+    ended: bool,
+    stats: LinkStats,
+    endian: Endian,
+    throttle: Throttle,
+}
+
+impl<T: ToBytes> TCPProducer<T> {
+    /// 只监听地址, 等待外部设备(例如 `iot-sim`)连接, 不启动内置的发送线程
+    pub fn listen(addr: impl Into<String>) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr.into())?;
+        Self::from_listener(listener)
+    }
+
+    /// 从已绑定的监听器上接受一个设备连接, 设备断开后也在该监听器上等待重连
+    pub fn accept(listener: &TcpListener) -> std::io::Result<Self> {
+        Self::from_listener(listener.try_clone()?)
+    }
+
+    fn from_listener(listener: TcpListener) -> std::io::Result<Self> {
+        let (stream, _addr) = listener.accept()?;
+        let mut producer = Self {
             waker: None,
-            stream,
-            _marker: std::marker::PhantomData,
+            listener,
+            stream: None,
+            policy: ReconnectPolicy::default(),
+            reconnect: None,
+            session: None,
+            expected: 0,
+            ready: None,
+            ended: false,
+            stats: LinkStats::default(),
+            endian: Endian::default(),
+            throttle: Throttle::new(&Determinism::default(), 100..=1000),
+        };
+        producer.handshake(stream)?;
+        Ok(producer)
+    }
+
+    pub fn with_reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// 读数的字节序, 需要与设备一致, 默认小端
+    pub fn with_endian(mut self, endian: Endian) -> Self {
+        self.endian = endian;
+        self
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    /// `data_available` 的等待时间由 `determinism` 决定
+    pub fn with_determinism(mut self, determinism: Determinism) -> Self {
+        self.throttle = Throttle::new(&determinism, 100..=1000);
+        self
+    }
+
+    /// `Clock::Virtual` 下 `data_available` 累计的等待时间
+    pub fn waited(&self) -> Duration {
+        self.throttle.waited()
+    }
+
+    /// 读取设备的 `HELLO`, 会话号变化时从 0 开始重新计数
+    fn handshake(&mut self, mut stream: TcpStream) -> std::io::Result<()> {
+        let mut hello = [0u8; HELLO_LEN];
+        stream.read_exact(&mut hello)?;
+        let session = u64::from_le_bytes(hello);
+        match self.session.replace(session) {
+            Some(old) if old != session => {
+                warn!("device restarted (session {} -> {}), unacknowledged readings are lost", old, session);
+                self.expected = 0;
+            }
+            Some(_) => debug!("device resumed session {} at seq {}", session, self.expected),
+            None => {}
         }
+        self.stream = Some(stream);
+        Ok(())
     }
 
-    // Read data from the TCP stream
-    fn read_data(&mut self) -> std::result::Result<T, std::io::Error> {
-        write_number(&mut self.stream, TCPProducer::<T>::ACK)?; // Send ACK
+    /// 按退避间隔检查监听器上是否有设备重新连接, 设备还没有回来时返回 `Pending`
+    fn poll_reconnect(&mut self) -> Poll<std::io::Result<()>> {
+        let listener = &self.listener;
+        let reconnect = self.reconnect.get_or_insert_with(|| Reconnect::new(&self.policy));
+        let accepted = reconnect.poll_accept(
+            self.waker.as_ref(),
+            |nonblocking| listener.set_nonblocking(nonblocking),
+            || listener.accept().map(|(stream, _addr)| stream),
+        );
+        let stream = match accepted {
+            Poll::Ready(stream) => stream?,
+            Poll::Pending => return Poll::Pending,
+        };
+        self.reconnect = None;
+        stream.set_nonblocking(false)?;
+        self.stats.reconnects += 1;
+        self.handshake(stream)?;
+        Poll::Ready(Ok(()))
+    }
+
+    /// 请求下一个读数放入 `ready`, 等待设备重连时返回 `false`
+    fn poll_data(&mut self) -> bool {
+        while self.ready.is_none() && !self.ended {
+            if self.stream.is_some() {
+                self.ready = self.read_data();
+                continue;
+            }
+            match self.poll_reconnect() {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => {
+                    warn!("device did not come back: {}", e);
+                    self.ended = true;
+                }
+                Poll::Pending => return false,
+            }
+        }
+        true
+    }
 
-        // Read exactly one value from the stream
-        let ret = match read_number(&mut self.stream) {
-            Ok(received_number) => received_number,
+    /// 请求一个读数, 重复的读数返回 `None`; 链路出错时返回 `None` 并断开, 之后等待设备重连
+    fn read_data(&mut self) -> Option<T> {
+        let (seq, value) = match self.request() {
+            Ok(response) => response,
             Err(e) => {
-                write_number(&mut self.stream, TCPProducer::<T>::STOP)?; // Send stop signal on error
-                return Err(e);
+                warn!("device link failed at seq {}: {}", self.expected, e);
+                self.stream = None;
+                return None;
             }
         };
+        match check_seq(self.expected, seq) {
+            SeqCheck::InOrder => {}
+            SeqCheck::Duplicate => {
+                debug!("dropped duplicate reading {} (expected {})", seq, self.expected);
+                self.stats.duplicates += 1;
+                return None;
+            }
+            SeqCheck::Gap(lost) => {
+                warn!("lost {} readings before seq {}", lost, seq);
+                self.stats.lost += lost;
+            }
+            SeqCheck::Invalid => {
+                // 数据帧错位, 断开后由重连重新对齐
+                warn!("invalid seq {} (expected {}), dropping link", seq, self.expected);
+                self.stream = None;
+                return None;
+            }
+        }
+        self.expected = seq + 1;
+        Some(value)
+    }
 
-        Ok(ret)
+    fn request(&mut self) -> std::io::Result<(u64, T)> {
+        let stream = self.stream.as_mut().expect("connected");
+        stream.write_all(&encode_request(ACK, self.expected))?;
+        let mut frame = vec![0u8; response_len::<T>()];
+        stream.read_exact(&mut frame)?;
+        Ok(decode_response(&frame, self.endian))
     }
+}
 
-    // Send data over the TCP stream in a loop
-    fn send_data(addr: impl Into<String>) -> std::io::Result<()> {
+impl<T> TCPProducer<T>
+where
+    T: ToBytes + PartialOrd + From<u8> + SampleUniform + Clone
+{
+    #[allow(dead_code)]
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self::with_options(addr, Determinism::default(), None)
+    }
+
+    /// 内置的发送线程由 `Shutdown::join_threads` 等待, 收集器停止时发送 `STOP` 让它退出
+    pub fn with_shutdown(addr: impl Into<String>, shutdown: &Shutdown) -> Self {
+        Self::with_options(addr, Determinism::default(), Some(shutdown))
+    }
+
+    /// 内置设备的会话号, 读数和 `data_available` 的等待时间由 `determinism` 决定
+    pub fn with_options(addr: impl Into<String>, determinism: Determinism, shutdown: Option<&Shutdown>) -> Self {
         let addr: String = addr.into();
-        let mut stream = TcpStream::connect(addr)?;
-        let mut rng = thread_rng();
+        let listener = TcpListener::bind(addr.clone()).expect("Build TCP listener");
 
-        loop {
-            let r = std::ops::Range::<T> {
-                start: T::from(0),
-                end: T::from(250),
-            };
-            let number_to_send = rng.gen_range(r);
+        // 生成一个线程通过 TCP 发送数据
+        let sender = move || {
+            if let Err(e) = TCPProducer::<T>::send_data(addr, determinism) {
+                debug!("device thread exited: {}", e);
+            }
+        };
+        match shutdown {
+            Some(shutdown) => shutdown.spawn_thread(sender),
+            None => {
+                spawn(sender);
+            }
+        }
+
+        Self::from_listener(listener)
+            .expect("Failed to accept connection")
+            .with_determinism(determinism)
+    }
 
-            let ret: i8 = match read_number(&mut stream) {
-                Ok(received_number) => received_number,
+    /// 内置的模拟设备: 应答请求, 断开后按指数退避重连并重发未确认的读数
+    fn send_data(addr: impl Into<String>, determinism: Determinism) -> std::io::Result<()> {
+        let addr: String = addr.into();
+        let mut rng = determinism.rng(STREAM_VALUES);
+        let mut session = DeviceSession::new(determinism.rng(STREAM_SESSION).gen());
+        let defaults = ReconnectPolicy::default();
+        let mut backoff = Backoff::new(defaults.initial_backoff, defaults.max_backoff);
+
+        loop {
+            let mut stream = match TcpStream::connect(&addr) {
+                Ok(stream) => {
+                    backoff.reset();
+                    stream
+                }
                 Err(e) => {
-                    return Err(e);
+                    if backoff.attempts() >= defaults.max_attempts {
+                        return Err(e);
+                    }
+                    sleep(backoff.next_delay());
+                    continue;
                 }
             };
+            match Self::serve(&mut stream, &mut session, &mut rng) {
+                // 收到停止信号
+                Ok(()) => return Ok(()),
+                Err(e) => debug!("device link failed: {}, reconnecting", e),
+            }
+        }
+    }
+
+    fn serve(stream: &mut TcpStream, session: &mut DeviceSession<T>, rng: &mut impl Rng) -> std::io::Result<()> {
+        stream.write_all(&session.hello())?;
+        let mut frame = [0u8; REQUEST_LEN];
+        loop {
+            stream.read_exact(&mut frame)?;
+            let (cmd, expected) = decode_request(&frame);
 
             // Break the loop if stop signal is received
-            if ret == TCPProducer::<T>::STOP {
-                break;
+            if cmd == STOP {
+                return Ok(());
             }
 
-            // Send the generated number over the stream
-            write_number(&mut stream, number_to_send)?;
+            let (seq, value, _resent) = session.respond(expected, || {
+                let r = std::ops::Range::<T> {
+                    start: T::from(0),
+                    end: T::from(250),
+                };
+                rng.gen_range(r)
+            });
+            stream.write_all(&encode_response(seq, &value, Endian::Little))?;
         }
-        Ok(())
     }
 }
 
 
-impl<T> Producer<T> for TCPProducer<T>
-where
-    T: ToBytes + From<u8> + SampleUniform + PartialOrd,
-{
-    // Produce data by reading from the TCP stream
-    fn produce(&mut self) -> T {
-        let data = self.read_data().unwrap();
+impl<T: ToBytes> Producer<T> for TCPProducer<T> {
+    // Produce data by reading from the TCP stream, `None` once the device is gone for good.
+    // Blocks while waiting for the device to reconnect unless `data_available` returned true first
+    fn produce(&mut self) -> Option<T> {
+        while !self.poll_data() {
+            sleep(self.reconnect.as_ref().map_or(Duration::ZERO, Reconnect::remaining));
+        }
+        let data = self.ready.take()?;
         if let Some(waker) = &self.waker {
             waker.wake_by_ref(); // Wake up the async task if the waker is present
         }
-        data
+        Some(data)
     }
 
-    fn data_available(&self) -> bool {
-        let mut rng = thread_rng();
-        sleep(Duration::from_millis(rng.gen_range(100..=1000))); // Simulate a delay
-        true
+    /// 设备断开时不等待重连, 返回 `false` 并在下一次检查重连时唤醒收集器
+    fn data_available(&mut self) -> bool {
+        if self.ready.is_none() && self.stream.is_some() {
+            self.throttle.wait(); // Simulate a delay
+        }
+        self.poll_data()
     }
 
     fn stop(&mut self) {
-        let _ = write_number(&mut self.stream, TCPProducer::<T>::STOP); // Send stop signal to the client
+        if let Some(stream) = self.stream.as_mut() {
+            let _ = stream.write_all(&encode_request(STOP, self.expected)); // Send stop signal to the client
+        }
     }
 
     impl_waker_methods!();
 }
 
 
-fn write_number<W: Write, T: ToBytes>(stream: &mut W, value: T) -> std::io::Result<()> {
-    let bytes = value.to_le_bytes(); // Get the byte representation of the value
-    stream.write_all(&bytes[..]) // Write all the bytes to the stream
+
+/// 接收设备推送的 UDP 数据报, 每个数据报是一个推送帧(见 `protocol::encode_push`)
+///
+/// 按发送地址分别跟踪序号, 丢失, 乱序和重复的数据报记录在 `stats` 中, 重复的被丢弃.
+pub struct UdpProducer<T> {
+    waker: Option<Waker>,
+    socket: UdpSocket,
+    trackers: HashMap<SocketAddr, SeqTracker>,
+    /// 最近一个读数的发送地址
+    last_source: Option<SocketAddr>,
+    stats: LinkStats,
+    endian: Endian,
+    _marker: std::marker::PhantomData<T>,
+}
+
+impl<T: ToBytes> UdpProducer<T> {
+    pub fn bind(addr: impl ToSocketAddrs) -> std::io::Result<Self> {
+        Ok(Self {
+            waker: None,
+            socket: UdpSocket::bind(addr)?,
+            trackers: HashMap::new(),
+            last_source: None,
+            stats: LinkStats::default(),
+            endian: Endian::default(),
+            _marker: std::marker::PhantomData,
+        })
+    }
+
+    pub fn with_endian(mut self, endian: Endian) -> Self {
+        self.endian = endian;
+        self
+    }
+
+    /// 超过该时间没有收到任何数据报时认为设备已经离线, 数据流结束
+    pub fn with_idle_timeout(self, timeout: Duration) -> std::io::Result<Self> {
+        self.socket.set_read_timeout(Some(timeout))?;
+        Ok(self)
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn last_source(&self) -> Option<SocketAddr> {
+        self.last_source
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    fn read_data(&mut self) -> std::io::Result<T> {
+        // 多出一个字节, 用来识别过长的数据报
+        let mut buf = vec![0u8; push_frame_len::<T>() + 1];
+        loop {
+            let (len, source) = self.socket.recv_from(&mut buf)?;
+            let Some((session, seq, value)) = decode_push::<T>(&buf[..len], self.endian) else {
+                debug!("dropped malformed datagram of {} bytes from {}", len, source);
+                self.stats.malformed += 1;
+                continue;
+            };
+            let event = self.trackers.entry(source).or_default().observe(session, seq);
+            if self.stats.record(event) {
+                self.last_source = Some(source);
+                return Ok(value);
+            }
+            debug!("dropped datagram {} from {}: {:?}", seq, source, event);
+        }
+    }
+}
+
+impl<T: ToBytes> Producer<T> for UdpProducer<T> {
+    fn produce(&mut self) -> Option<T> {
+        let data = match self.read_data() {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("udp producer stopped: {}", e);
+                return None;
+            }
+        };
+        if let Some(waker) = &self.waker {
+            waker.wake_by_ref();
+        }
+        Some(data)
+    }
+
+    fn data_available(&mut self) -> bool {
+        true
+    }
+
+    /// 以非阻塞方式读出 socket 缓冲区中剩余的数据报
+    fn drain(&mut self) -> Vec<T> {
+        let mut drained = Vec::new();
+        if let Err(e) = self.socket.set_nonblocking(true) {
+            warn!("failed to drain udp socket: {}", e);
+            return drained;
+        }
+        while let Ok(data) = self.read_data() {
+            drained.push(data);
+        }
+        if let Err(e) = self.socket.set_nonblocking(false) {
+            warn!("failed to restore blocking udp socket: {}", e);
+        }
+        drained
+    }
+
+    impl_waker_methods!();
 }
 
 
-fn read_number<R: Read, T: ToBytes>(stream: &mut R) -> std::io::Result<T> {
-    let mut buf = vec![0u8; std::mem::size_of::<T>()]; // Create a buffer for the expected size
-    stream.read_exact(&mut buf)?; // Read exactly the number of bytes needed
-    Ok(T::from_le_bytes(&buf)) // Convert the byte array back into the original type
+/// 接收本机代理通过 Unix domain socket 推送的读数, 帧格式与 `UdpProducer` 相同
+///
+/// 代理断开后按 `ReconnectPolicy` 等待重连, 重连后继续按会话号和序号检测丢失与重复.
+#[cfg(unix)]
+pub struct UnixSocketProducer<T> {
+    waker: Option<Waker>,
+    listener: UnixListener,
+    stream: Option<UnixStream>,
+    policy: ReconnectPolicy,
+    reconnect: Option<Reconnect>,
+    tracker: SeqTracker,
+    /// 已经收到但还没有产出的读数
+    ready: Option<T>,
+    ended: bool,
+    stats: LinkStats,
+    endian: Endian,
 }
 
+#[cfg(unix)]
+impl<T: ToBytes> UnixSocketProducer<T> {
+    /// 绑定 socket 文件并等待第一个代理连接
+    pub fn bind(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let listener = UnixListener::bind(path)?;
+        let (stream, _addr) = listener.accept()?;
+        Ok(Self {
+            waker: None,
+            listener,
+            stream: Some(stream),
+            policy: ReconnectPolicy::default(),
+            reconnect: None,
+            tracker: SeqTracker::default(),
+            ready: None,
+            ended: false,
+            stats: LinkStats::default(),
+            endian: Endian::default(),
+        })
+    }
 
+    pub fn with_reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
 
+    pub fn with_endian(mut self, endian: Endian) -> Self {
+        self.endian = endian;
+        self
+    }
 
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    fn poll_reconnect(&mut self) -> Poll<std::io::Result<()>> {
+        let listener = &self.listener;
+        let reconnect = self.reconnect.get_or_insert_with(|| Reconnect::new(&self.policy));
+        let accepted = reconnect.poll_accept(
+            self.waker.as_ref(),
+            |nonblocking| listener.set_nonblocking(nonblocking),
+            || listener.accept().map(|(stream, _addr)| stream),
+        );
+        let stream = match accepted {
+            Poll::Ready(stream) => stream?,
+            Poll::Pending => return Poll::Pending,
+        };
+        self.reconnect = None;
+        stream.set_nonblocking(false)?;
+        self.stats.reconnects += 1;
+        self.stream = Some(stream);
+        Poll::Ready(Ok(()))
+    }
+
+    /// 读取下一个被接受的读数放入 `ready`, 等待代理重连时返回 `false`
+    fn poll_data(&mut self) -> bool {
+        while self.ready.is_none() && !self.ended {
+            if self.stream.is_some() {
+                self.ready = self.read_data();
+                continue;
+            }
+            match self.poll_reconnect() {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => {
+                    warn!("unix socket agent did not come back: {}", e);
+                    self.ended = true;
+                }
+                Poll::Pending => return false,
+            }
+        }
+        true
+    }
+
+    /// 读取一个数据帧, 重复的读数返回 `None`; 连接断开时返回 `None`, 之后等待代理重连
+    fn read_data(&mut self) -> Option<T> {
+        let mut frame = vec![0u8; push_frame_len::<T>()];
+        let stream = self.stream.as_mut()?;
+        if let Err(e) = stream.read_exact(&mut frame) {
+            debug!("unix socket agent disconnected: {}", e);
+            self.stream = None;
+            return None;
+        }
+        let (session, seq, value) = decode_push::<T>(&frame, self.endian).expect("frame has push length");
+        let event = self.tracker.observe(session, seq);
+        if self.stats.record(event) {
+            return Some(value);
+        }
+        if event == SeqEvent::Invalid {
+            // 数据帧错位, 断开后由代理重连重新对齐
+            warn!("invalid seq {} from unix socket agent, dropping connection", seq);
+            self.stream = None;
+        }
+        None
+    }
+}
+
+#[cfg(unix)]
+impl<T: ToBytes> Producer<T> for UnixSocketProducer<T> {
+    /// 没有先调用 `data_available` 时会阻塞等待代理重连
+    fn produce(&mut self) -> Option<T> {
+        while !self.poll_data() {
+            sleep(self.reconnect.as_ref().map_or(Duration::ZERO, Reconnect::remaining));
+        }
+        let data = self.ready.take()?;
+        if let Some(waker) = &self.waker {
+            waker.wake_by_ref();
+        }
+        Some(data)
+    }
 
+    /// 代理断开时不等待重连, 返回 `false` 并在下一次检查重连时唤醒收集器
+    fn data_available(&mut self) -> bool {
+        self.poll_data()
+    }
+
+    impl_waker_methods!();
+}
+
+
+/// 接收设备通过 TCP 推送的压缩块帧(见 `protocol::encode_block_frame`), 产出 `(时间戳, 读数)`
+///
+/// 设备断开后按 `ReconnectPolicy` 等待重连, 重连后块的序号需要接着之前的序号.
+/// 与已收到的读数重叠的部分被丢弃, 序号跳过的读数记为丢失.
+pub struct TcpBlockProducer<T> {
+    waker: Option<Waker>,
+    listener: TcpListener,
+    stream: Option<TcpStream>,
+    policy: ReconnectPolicy,
+    reconnect: Option<Reconnect>,
+    /// 下一个期望收到的读数序号
+    expected: u64,
+    /// 已经解码但还没有产出的读数
+    buffered: VecDeque<(i64, T)>,
+    ended: bool,
+    stats: LinkStats,
+}
+
+impl<T: ToBytes> TcpBlockProducer<T> {
+    /// 从已绑定的监听器上接受一个设备连接, 设备断开后也在该监听器上等待重连
+    pub fn accept(listener: &TcpListener) -> std::io::Result<Self> {
+        let listener = listener.try_clone()?;
+        let (stream, _addr) = listener.accept()?;
+        Ok(Self {
+            waker: None,
+            listener,
+            stream: Some(stream),
+            policy: ReconnectPolicy::default(),
+            reconnect: None,
+            expected: 0,
+            buffered: VecDeque::new(),
+            ended: false,
+            stats: LinkStats::default(),
+        })
+    }
+
+    pub fn with_reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    fn poll_reconnect(&mut self) -> Poll<std::io::Result<()>> {
+        let listener = &self.listener;
+        let reconnect = self.reconnect.get_or_insert_with(|| Reconnect::new(&self.policy));
+        let accepted = reconnect.poll_accept(
+            self.waker.as_ref(),
+            |nonblocking| listener.set_nonblocking(nonblocking),
+            || listener.accept().map(|(stream, _addr)| stream),
+        );
+        let stream = match accepted {
+            Poll::Ready(stream) => stream?,
+            Poll::Pending => return Poll::Pending,
+        };
+        self.reconnect = None;
+        stream.set_nonblocking(false)?;
+        self.stats.reconnects += 1;
+        self.stream = Some(stream);
+        Poll::Ready(Ok(()))
+    }
+
+    /// 读取包含新读数的块放入 `buffered`, 等待设备重连时返回 `false`
+    fn poll_data(&mut self) -> bool {
+        while self.buffered.is_empty() && !self.ended {
+            if self.stream.is_some() {
+                self.read_block();
+                continue;
+            }
+            match self.poll_reconnect() {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => {
+                    warn!("block device did not come back: {}", e);
+                    self.ended = true;
+                }
+                Poll::Pending => return false,
+            }
+        }
+        true
+    }
+
+    fn read_frame(stream: &mut TcpStream) -> std::io::Result<(u64, Vec<(i64, T)>)> {
+        let mut header = [0u8; BLOCK_FRAME_HEADER_LEN];
+        stream.read_exact(&mut header)?;
+        let (seq, len) = decode_block_frame_header(&header)
+            .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidData, "block frame too long"))?;
+        let mut block = vec![0u8; len];
+        stream.read_exact(&mut block)?;
+        let points = decode_block(&block).map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+        Ok((seq, points))
+    }
+
+    /// 读取一个块, 其中的新读数放入 `buffered`; 连接断开或数据帧错位时断开, 之后等待设备重连
+    fn read_block(&mut self) {
+        let Some(stream) = self.stream.as_mut() else {
+            return;
+        };
+        let (seq, mut points) = match Self::read_frame(stream) {
+            Ok(frame) => frame,
+            Err(e) => {
+                if e.kind() == ErrorKind::InvalidData {
+                    self.stats.malformed += 1;
+                }
+                debug!("block device disconnected: {}", e);
+                self.stream = None;
+                return;
+            }
+        };
+        // 块中已经收到过的读数个数
+        let duplicates = match check_seq(self.expected, seq) {
+            SeqCheck::InOrder => 0,
+            SeqCheck::Duplicate => (self.expected - seq).min(points.len() as u64),
+            SeqCheck::Gap(lost) => {
+                warn!("lost {} readings before block {}", lost, seq);
+                self.stats.lost += lost;
+                0
+            }
+            SeqCheck::Invalid => {
+                warn!("invalid block seq {} (expected {}), dropping link", seq, self.expected);
+                self.stats.malformed += 1;
+                self.stream = None;
+                return;
+            }
+        };
+        self.stats.duplicates += duplicates;
+        points.drain(..duplicates as usize);
+        if !points.is_empty() {
+            self.expected = seq + duplicates + points.len() as u64;
+            self.buffered.extend(points);
+        }
+    }
+}
+
+impl<T: ToBytes> Producer<(i64, T)> for TcpBlockProducer<T> {
+    /// 没有先调用 `data_available` 时会阻塞等待设备重连
+    fn produce(&mut self) -> Option<(i64, T)> {
+        while !self.poll_data() {
+            sleep(self.reconnect.as_ref().map_or(Duration::ZERO, Reconnect::remaining));
+        }
+        let data = self.buffered.pop_front()?;
+        if let Some(waker) = &self.waker {
+            waker.wake_by_ref();
+        }
+        Some(data)
+    }
+
+    /// 设备断开时不等待重连, 返回 `false` 并在下一次检查重连时唤醒收集器
+    fn data_available(&mut self) -> bool {
+        self.poll_data()
+    }
+
+    /// 已经解码但还没有产出的读数
+    fn drain(&mut self) -> Vec<(i64, T)> {
+        self.buffered.drain(..).collect()
+    }
+
+    impl_waker_methods!();
+}
+
+#[cfg(test)]
+mod test {
+    use crate::protocol::{encode_block_frame, encode_push};
+    use crate::reading::Telemetry;
+    use super::*;
+
+    fn round_trip<T: ToBytes + PartialEq + std::fmt::Debug + Copy>(value: T) {
+        for endian in [Endian::Little, Endian::Big] {
+            let bytes = value.to_bytes(endian);
+            assert_eq!(bytes.len(), T::SIZE);
+            assert_eq!(T::from_bytes(&bytes, endian), value);
+        }
+    }
+
+    #[test]
+    fn test_to_bytes_numerics() {
+        round_trip(-3i8);
+        round_trip(u32::MAX - 7);
+        round_trip(i64::MIN + 1);
+        round_trip(u128::MAX / 3);
+        round_trip(-1.5f32);
+        round_trip(std::f64::consts::PI);
+        assert_eq!(ToBytes::to_bytes(&0x0102u16, Endian::Big), vec![1, 2]);
+        assert_eq!(ToBytes::to_bytes(&0x0102u16, Endian::Little), vec![2, 1]);
+    }
+
+    #[test]
+    fn test_tcp_producer_carries_telemetry() {
+        let t = Telemetry { device_id: 9, ts: 1_700_000_000_000, value: 21.5 };
+        // 紧密排列, 不包含结构体的对齐填充
+        assert_eq!(Telemetry::SIZE, 16);
+        assert_eq!(&t.to_be_bytes()[..4], &[0, 0, 0, 9]);
+        round_trip(t);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let device = spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut session = DeviceSession::new(1);
+            stream.write_all(&session.hello()).unwrap();
+            let mut frame = [0u8; REQUEST_LEN];
+            loop {
+                stream.read_exact(&mut frame).unwrap();
+                let (cmd, expected) = decode_request(&frame);
+                if cmd == STOP {
+                    break;
+                }
+                let (seq, value, _) = session.respond(expected, || Telemetry { ts: t.ts + expected as i64, ..t });
+                stream.write_all(&encode_response(seq, &value, Endian::Big)).unwrap();
+            }
+        });
+
+        let mut producer: TCPProducer<Telemetry> = TCPProducer::accept(&listener).unwrap().with_endian(Endian::Big);
+        let data: Vec<Telemetry> = (0..3).map(|_| producer.produce().unwrap()).collect();
+        producer.stop();
+        device.join().unwrap();
+        assert_eq!(data.iter().map(|r| r.ts - t.ts).collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert!(data.iter().all(|r| r.device_id == 9 && r.value == 21.5));
+    }
+
+    #[test]
+    fn test_tcp_producer_waits_for_reconnect_without_blocking() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (reconnect, reconnect_rx) = std::sync::mpsc::channel();
+        let device = spawn(move || {
+            let mut session = DeviceSession::new(3);
+            let mut serve = |requests: Option<usize>| {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                stream.write_all(&session.hello()).unwrap();
+                let mut frame = [0u8; REQUEST_LEN];
+                for _ in 0..requests.unwrap_or(usize::MAX) {
+                    if stream.read_exact(&mut frame).is_err() {
+                        return;
+                    }
+                    let (cmd, expected) = decode_request(&frame);
+                    if cmd == STOP {
+                        return;
+                    }
+                    let (seq, value, _) = session.respond(expected, || expected as u32 * 10);
+                    stream.write_all(&encode_response(seq, &value, Endian::Little)).unwrap();
+                }
+            };
+            // 应答一个请求后断开, 收到通知后再重连
+            serve(Some(1));
+            reconnect_rx.recv().unwrap();
+            serve(None);
+        });
+
+        let policy = ReconnectPolicy {
+            initial_backoff: Duration::from_millis(20),
+            max_backoff: Duration::from_millis(20),
+            max_attempts: 1000,
+        };
+        let mut producer: TCPProducer<u32> = TCPProducer::accept(&listener)
+            .unwrap()
+            .with_determinism(Determinism::seeded(1))
+            .with_reconnect_policy(policy);
+        producer.store_waker(&thread_waker());
+        assert!(producer.data_available());
+        assert_eq!(producer.produce(), Some(0));
+
+        // 设备断开后不等待重连, 立即返回
+        assert!(!producer.data_available());
+        reconnect.send(()).unwrap();
+        while !producer.data_available() {
+            std::thread::park_timeout(Duration::from_secs(1));
+        }
+        assert_eq!(producer.produce(), Some(10));
+        assert_eq!(producer.stats().reconnects, 1);
+        producer.stop();
+        device.join().unwrap();
+    }
+
+    #[test]
+    fn test_udp_producer_detects_loss_and_reordering() {
+        let mut producer: UdpProducer<u16> = UdpProducer::bind("127.0.0.1:0")
+            .unwrap()
+            .with_idle_timeout(Duration::from_millis(200))
+            .unwrap();
+        let device = UdpSocket::bind("127.0.0.1:0").unwrap();
+        device.connect(producer.local_addr().unwrap()).unwrap();
+
+        // 2 迟到, 3 丢失, 1 重复, 最后一个数据报长度错误
+        for seq in [0u64, 1, 4, 2, 1] {
+            device.send(&encode_push(5, seq, &(seq as u16 * 10), Endian::Little)).unwrap();
+        }
+        device.send(&[0u8; 3]).unwrap();
+
+        let mut data = Vec::new();
+        while let Some(value) = producer.produce() {
+            data.push(value);
+        }
+        assert_eq!(data, vec![0, 10, 40, 20]);
+        assert_eq!(producer.last_source(), Some(device.local_addr().unwrap()));
+        let stats = producer.stats();
+        assert_eq!((stats.lost, stats.reordered, stats.duplicates, stats.malformed), (1, 1, 1, 1));
+    }
+
+    #[test]
+    fn test_tcp_block_producer_round_trip() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let points: Vec<(i64, f64)> = (0..6).map(|i| (1_700_000_000_000 + i * 1000, 20.0 + i as f64 / 4.0)).collect();
+        let sent = points.clone();
+        let device = spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(&encode_block_frame(0, &sent[..3])).unwrap();
+            drop(stream);
+            // 重连后重发了 2, 然后继续
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(&encode_block_frame(2, &sent[2..5])).unwrap();
+            // 5 丢失
+            stream.write_all(&encode_block_frame(6, &[(sent[5].0 + 1000, 1.0)])).unwrap();
+        });
+
+        let mut producer: TcpBlockProducer<f64> = TcpBlockProducer::accept(&listener).unwrap();
+        let data: Vec<(i64, f64)> = (0..6).map(|_| producer.produce().unwrap()).collect();
+        device.join().unwrap();
+        assert_eq!(&data[..5], &points[..5]);
+        assert_eq!(data[5], (points[5].0 + 1000, 1.0));
+        let stats = producer.stats();
+        assert_eq!((stats.reconnects, stats.duplicates, stats.lost), (1, 1, 1));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket_producer_resumes_after_reconnect() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.sock");
+        let agent_path = path.clone();
+        let agent = spawn(move || {
+            // 等待收集端绑定 socket 文件
+            let connect = || loop {
+                if let Ok(stream) = UnixStream::connect(&agent_path) {
+                    return stream;
+                }
+                sleep(Duration::from_millis(1));
+            };
+            let mut stream = connect();
+            for seq in [0u64, 1] {
+                stream.write_all(&encode_push(1, seq, &(seq as i32), Endian::Big)).unwrap();
+            }
+            drop(stream);
+            // 重连后重发了 1, 然后继续
+            let mut stream = connect();
+            for seq in [1u64, 2, 3] {
+                stream.write_all(&encode_push(1, seq, &(seq as i32), Endian::Big)).unwrap();
+            }
+        });
+
+        let mut producer: UnixSocketProducer<i32> = UnixSocketProducer::bind(&path).unwrap().with_endian(Endian::Big);
+        let data: Vec<i32> = (0..4).map(|_| producer.produce().unwrap()).collect();
+        agent.join().unwrap();
+        assert_eq!(data, vec![0, 1, 2, 3]);
+        let stats = producer.stats();
+        assert_eq!((stats.reconnects, stats.duplicates, stats.lost), (1, 1, 0));
+    }
+}
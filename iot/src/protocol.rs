@@ -0,0 +1,330 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::time::Duration;
+use crate::codec::{decode_block, encode_block, CodecError};
+use crate::producer::{Endian, ToBytes};
+
+/// TCP 线路协议
+///
+/// 1. 设备连接后先发送 `HELLO`: `session: u64`, 设备每次重启使用新的会话号,
+///    重连时沿用原来的会话号
+/// 2. 收集端发送请求: `cmd: i8` + `expected: u64`, `cmd` 为 `ACK` 时请求序号为
+///    `expected` 的读数, 同时确认所有更小序号的读数; 为 `STOP` 时通知设备停止
+/// 3. 设备应答: `seq: u64` + 读数, 未确认的读数在重连后重发
+///
+/// 协议头部均为小端字节序, 读数按双方约定的 `Endian` 编码.
+pub const HELLO_LEN: usize = 8;
+pub const REQUEST_LEN: usize = 9;
+
+/// 收到的序号比期望的序号大出这么多时, 认为数据帧已经错位
+pub const MAX_SEQ_GAP: u64 = 1 << 20;
+
+/// 推送帧(UDP 数据报 / Unix socket): `session: u64` + `seq: u64` + 读数,
+/// 设备不等待请求也不重发, 收集端用 `SeqTracker` 检测丢失和乱序
+pub fn push_frame_len<T: ToBytes>() -> usize {
+    16 + T::SIZE
+}
+
+pub fn encode_push<T: ToBytes>(session: u64, seq: u64, value: &T, endian: Endian) -> Vec<u8> {
+    let mut frame = session.to_le_bytes().to_vec();
+    frame.extend(encode_response(seq, value, endian));
+    frame
+}
+
+/// 长度不对时返回 `None`
+pub fn decode_push<T: ToBytes>(frame: &[u8], endian: Endian) -> Option<(u64, u64, T)> {
+    if frame.len() != push_frame_len::<T>() {
+        return None;
+    }
+    let (session, rest) = frame.split_at(8);
+    let (seq, value) = decode_response(rest, endian);
+    Some((u64::from_le_bytes(session.try_into().expect("8 bytes")), seq, value))
+}
+
+pub fn encode_request(cmd: i8, expected: u64) -> [u8; REQUEST_LEN] {
+    let mut frame = [0u8; REQUEST_LEN];
+    frame[0] = cmd as u8;
+    frame[1..].copy_from_slice(&expected.to_le_bytes());
+    frame
+}
+
+pub fn decode_request(frame: &[u8; REQUEST_LEN]) -> (i8, u64) {
+    let expected = u64::from_le_bytes(frame[1..].try_into().expect("8 bytes"));
+    (frame[0] as i8, expected)
+}
+
+pub fn response_len<T: ToBytes>() -> usize {
+    8 + T::SIZE
+}
+
+pub fn encode_response<T: ToBytes>(seq: u64, value: &T, endian: Endian) -> Vec<u8> {
+    let mut frame = seq.to_le_bytes().to_vec();
+    frame.extend(value.to_bytes(endian));
+    frame
+}
+
+pub fn decode_response<T: ToBytes>(frame: &[u8], endian: Endian) -> (u64, T) {
+    let (seq, value) = frame.split_at(8);
+    (u64::from_le_bytes(seq.try_into().expect("8 bytes")), T::from_bytes(value, endian))
+}
+
+/// 压缩块帧: `seq: u64` + `len: u32` + `len` 字节的 `codec::encode_block`, 设备一次
+/// 上传多个带时间戳的读数时使用, 由 `TcpBlockProducer` 接收. `seq` 为块中第一个读数的
+/// 序号, 之后依次加一; 块内编码固定为小端, 与 `Endian` 无关
+pub const BLOCK_FRAME_HEADER_LEN: usize = 12;
+/// 块长度超过它时认为数据帧已经错位
+pub const MAX_BLOCK_LEN: usize = 1 << 20;
+
+pub fn encode_block_frame<T: ToBytes>(seq: u64, points: &[(i64, T)]) -> Vec<u8> {
+    let block = encode_block(points);
+    let mut frame = Vec::with_capacity(BLOCK_FRAME_HEADER_LEN + block.len());
+    frame.extend_from_slice(&seq.to_le_bytes());
+    frame.extend_from_slice(&(block.len() as u32).to_le_bytes());
+    frame.extend(block);
+    frame
+}
+
+/// 返回 `seq` 和之后块的长度, 长度超过 `MAX_BLOCK_LEN` 时返回 `None`
+pub fn decode_block_frame_header(header: &[u8; BLOCK_FRAME_HEADER_LEN]) -> Option<(u64, usize)> {
+    let seq = u64::from_le_bytes(header[..8].try_into().expect("8 bytes"));
+    let len = u32::from_le_bytes(header[8..].try_into().expect("4 bytes")) as usize;
+    (len <= MAX_BLOCK_LEN).then_some((seq, len))
+}
+
+pub fn decode_block_frame<T: ToBytes>(frame: &[u8]) -> Result<(u64, Vec<(i64, T)>), CodecError> {
+    let header = frame.get(..BLOCK_FRAME_HEADER_LEN).ok_or(CodecError::Truncated)?;
+    let (seq, len) = decode_block_frame_header(header.try_into().expect("12 bytes")).ok_or(CodecError::Corrupt)?;
+    let block = frame.get(BLOCK_FRAME_HEADER_LEN..BLOCK_FRAME_HEADER_LEN + len).ok_or(CodecError::Truncated)?;
+    Ok((seq, decode_block(block)?))
+}
+
+
+/// 指数退避, 每次等待时间翻倍, 不超过 `max`
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self { initial, max, current: initial, attempts: 0 }
+    }
+
+    /// 下一次重试前的等待时间
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        self.attempts += 1;
+        delay
+    }
+
+    /// 已经重试的次数
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// 成功后重新从 `initial` 开始
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+        self.attempts = 0;
+    }
+}
+
+
+/// 设备端的会话状态: 分配序号并保存未确认的读数
+pub struct DeviceSession<T> {
+    session: u64,
+    next_seq: u64,
+    unacked: VecDeque<(u64, T)>,
+}
+
+impl<T: Clone> DeviceSession<T> {
+    pub fn new(session: u64) -> Self {
+        Self { session, next_seq: 0, unacked: VecDeque::new() }
+    }
+
+    pub fn hello(&self) -> [u8; HELLO_LEN] {
+        self.session.to_le_bytes()
+    }
+
+    /// 应答收集端对 `expected` 的请求, 已缓存的读数直接重发, 否则调用 `generate`
+    /// 产生新读数. 返回读数和是否为重发.
+    pub fn respond(&mut self, expected: u64, generate: impl FnOnce() -> T) -> (u64, T, bool) {
+        while self.unacked.front().is_some_and(|(seq, _)| *seq < expected) {
+            self.unacked.pop_front();
+        }
+        if let Some((seq, value)) = self.unacked.front() {
+            return (*seq, value.clone(), true);
+        }
+        let seq = self.next_seq.max(expected);
+        self.next_seq = seq + 1;
+        let value = generate();
+        self.unacked.push_back((seq, value.clone()));
+        (seq, value, false)
+    }
+
+    /// 还没被确认的读数个数
+    pub fn unacked(&self) -> usize {
+        self.unacked.len()
+    }
+}
+
+
+/// 收集端根据序号判断读数是否应该被接受
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeqCheck {
+    /// 正好是期望的读数
+    InOrder,
+    /// 已经收到过, 丢弃
+    Duplicate,
+    /// 中间有读数丢失, 返回丢失的个数, 读数仍然被接受
+    Gap(u64),
+    /// 序号不可能出现, 数据帧已经错位
+    Invalid,
+}
+
+pub fn check_seq(expected: u64, seq: u64) -> SeqCheck {
+    if seq == expected {
+        SeqCheck::InOrder
+    } else if seq < expected {
+        SeqCheck::Duplicate
+    } else if seq - expected > MAX_SEQ_GAP {
+        SeqCheck::Invalid
+    } else {
+        SeqCheck::Gap(seq - expected)
+    }
+}
+
+
+/// `SeqTracker` 对一个推送读数的判断
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeqEvent {
+    InOrder,
+    /// 跳过了若干序号, 读数被接受, 跳过的序号记为丢失直到它们迟到
+    Gap(u64),
+    /// 之前记为丢失的序号迟到了, 读数被接受
+    Reordered,
+    /// 已经收到过, 丢弃
+    Duplicate,
+    /// 序号不可能出现, 丢弃
+    Invalid,
+}
+
+/// 推送模式下跟踪一个设备的序号, 记住最近 `window` 个缺失的序号以识别乱序
+#[derive(Clone, Debug)]
+pub struct SeqTracker {
+    session: Option<u64>,
+    expected: u64,
+    missing: BTreeSet<u64>,
+    window: usize,
+}
+
+impl Default for SeqTracker {
+    fn default() -> Self {
+        Self::new(1024)
+    }
+}
+
+impl SeqTracker {
+    pub fn new(window: usize) -> Self {
+        Self { session: None, expected: 0, missing: BTreeSet::new(), window }
+    }
+
+    /// 会话号变化说明设备重启, 重新从 0 开始跟踪
+    pub fn observe(&mut self, session: u64, seq: u64) -> SeqEvent {
+        if self.session.replace(session).is_some_and(|old| old != session) {
+            self.expected = 0;
+            self.missing.clear();
+        }
+        match check_seq(self.expected, seq) {
+            SeqCheck::InOrder => {
+                self.expected = seq + 1;
+                SeqEvent::InOrder
+            }
+            SeqCheck::Gap(lost) => {
+                let from = self.expected.max(seq.saturating_sub(self.window as u64));
+                self.missing.extend(from..seq);
+                while self.missing.len() > self.window {
+                    self.missing.pop_first();
+                }
+                self.expected = seq + 1;
+                SeqEvent::Gap(lost)
+            }
+            SeqCheck::Duplicate if self.missing.remove(&seq) => SeqEvent::Reordered,
+            SeqCheck::Duplicate => SeqEvent::Duplicate,
+            SeqCheck::Invalid => SeqEvent::Invalid,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_device_session_resends_unacked() {
+        let mut session: DeviceSession<u16> = DeviceSession::new(7);
+        let mut next = 10u16;
+        let mut gen = || {
+            next += 1;
+            next
+        };
+        assert_eq!(session.respond(0, &mut gen), (0, 11, false));
+        // 收集端没有收到 0 号读数, 重连后再次请求 0
+        assert_eq!(session.respond(0, &mut gen), (0, 11, true));
+        assert_eq!(session.respond(1, &mut gen), (1, 12, false));
+        assert_eq!(session.unacked(), 1);
+
+        let (cmd, expected) = decode_request(&encode_request(-1, 42));
+        assert_eq!((cmd, expected), (-1, 42));
+        assert_eq!(decode_response::<u16>(&encode_response(5, &300u16, Endian::Big), Endian::Big), (5, 300));
+
+        assert_eq!(check_seq(3, 3), SeqCheck::InOrder);
+        assert_eq!(check_seq(3, 2), SeqCheck::Duplicate);
+        assert_eq!(check_seq(3, 5), SeqCheck::Gap(2));
+        assert_eq!(check_seq(3, u64::MAX), SeqCheck::Invalid);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_millis(10), Duration::from_millis(35));
+        let delays: Vec<u64> = (0..4).map(|_| backoff.next_delay().as_millis() as u64).collect();
+        assert_eq!(delays, vec![10, 20, 35, 35]);
+        assert_eq!(backoff.attempts(), 4);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_seq_tracker() {
+        let mut tracker = SeqTracker::new(2);
+        assert_eq!(tracker.observe(1, 0), SeqEvent::InOrder);
+        assert_eq!(tracker.observe(1, 4), SeqEvent::Gap(3));
+        // 窗口只记住 2 和 3
+        assert_eq!(tracker.observe(1, 3), SeqEvent::Reordered);
+        assert_eq!(tracker.observe(1, 3), SeqEvent::Duplicate);
+        assert_eq!(tracker.observe(1, 1), SeqEvent::Duplicate);
+        assert_eq!(tracker.observe(1, 5), SeqEvent::InOrder);
+        // 设备重启
+        assert_eq!(tracker.observe(2, 0), SeqEvent::InOrder);
+
+        let frame = encode_push(7, 3, &-2i32, Endian::Big);
+        assert_eq!(frame.len(), push_frame_len::<i32>());
+        assert_eq!(decode_push::<i32>(&frame, Endian::Big), Some((7, 3, -2)));
+        assert_eq!(decode_push::<i32>(&frame[1..], Endian::Big), None);
+    }
+
+    #[test]
+    fn test_block_frame() {
+        let points: Vec<(i64, u32)> = (0..50).map(|i| (1000 + i * 250, 400 + i as u32 % 3)).collect();
+        let frame = encode_block_frame(9, &points);
+        let (seq, len) = decode_block_frame_header(frame[..BLOCK_FRAME_HEADER_LEN].try_into().unwrap()).unwrap();
+        assert_eq!((seq, len), (9, frame.len() - BLOCK_FRAME_HEADER_LEN));
+        assert!(frame.len() < points.len() * response_len::<u32>() / 2);
+        assert_eq!(decode_block_frame::<u32>(&frame), Ok((9, points)));
+        assert_eq!(decode_block_frame::<u32>(&frame[..20]), Err(CodecError::Truncated));
+    }
+}
@@ -0,0 +1,78 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_stream::{Stream, StreamExt};
+use crate::aggregate::AggregateValue;
+
+/// 一条带设备和时间信息的读数
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Reading {
+    pub device_id: u32,
+    /// 毫秒时间戳
+    pub timestamp: i64,
+    pub value: f64,
+}
+
+impl Reading {
+    pub fn new(device_id: u32, timestamp: i64, value: f64) -> Self {
+        Self { device_id, timestamp, value }
+    }
+
+    /// 编码为 `device_id`(u32, 小端) 加 `value`(f64, 小端), 时间戳单独存放
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(12);
+        data.extend_from_slice(&self.device_id.to_le_bytes());
+        data.extend_from_slice(&self.value.to_le_bytes());
+        data
+    }
+
+    /// `encode` 的逆过程, 长度不对时返回 `None`
+    pub fn decode(data: &[u8], timestamp: i64) -> Option<Self> {
+        let device_id = u32::from_le_bytes(data.get(0..4)?.try_into().ok()?);
+        let value = f64::from_le_bytes(data.get(4..12)?.try_into().ok()?);
+        (data.len() == 12).then_some(Self { device_id, timestamp, value })
+    }
+}
+
+/// 设备上报的遥测记录, 可以直接作为 `TCPProducer` 的读数类型在线路上传输
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Telemetry {
+    pub device_id: u32,
+    /// 毫秒时间戳
+    pub ts: i64,
+    pub value: f32,
+}
+
+crate::impl_tobytes_for_struct!(Telemetry { device_id: u32, ts: i64, value: f32 });
+
+impl From<Telemetry> for Reading {
+    fn from(t: Telemetry) -> Self {
+        Reading::new(t.device_id, t.ts, t.value as f64)
+    }
+}
+
+impl AggregateValue for Telemetry {
+    fn aggregate_value(&self) -> Option<f64> {
+        Some(self.value as f64)
+    }
+}
+
+impl AggregateValue for Reading {
+    fn aggregate_value(&self) -> Option<f64> {
+        Some(self.value)
+    }
+}
+
+/// 把设备的原始数据流(例如 `Producer`)转换为读数流, 时间戳取接收时的系统时间,
+/// 无法转换为 `f64` 的数据被丢弃
+pub fn readings<S, T>(device_id: u32, stream: S) -> impl Stream<Item = Reading>
+where
+    S: Stream<Item = T>,
+    T: AggregateValue,
+{
+    stream.filter_map(move |value| {
+        let value = value.aggregate_value()?;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as i64);
+        Some(Reading::new(device_id, timestamp, value))
+    })
+}
@@ -0,0 +1,368 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use crate::condition::{AnyOf, CountReached, ReadyCondition, TimeElapsed};
+use crate::pipeline::Calibration;
+use crate::reading::Reading;
+
+#[derive(Error, Debug)]
+pub enum RegistryError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid toml: {0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("invalid json: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("unsupported config format: {0}, expected .toml or .json")]
+    UnsupportedFormat(String),
+    #[error("device id {0} is used more than once")]
+    DuplicateId(u32),
+    #[error("device {id}: {reason}")]
+    Invalid { id: u32, reason: String },
+}
+
+/// 设备的连接方式
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Transport {
+    /// `ChannelProducer`
+    Channel,
+    /// `RandProducer`
+    Rand,
+    /// `TCPProducer` 监听 `addr`, `simulated` 时同时启动内置的模拟设备
+    Tcp {
+        addr: String,
+        #[serde(default)]
+        simulated: bool,
+    },
+    /// `UdpProducer` 绑定 `addr`, 超过 `idle_timeout_ms` 没有数据时视为设备断开
+    Udp {
+        addr: String,
+        #[serde(default = "default_idle_timeout_ms")]
+        idle_timeout_ms: u64,
+    },
+}
+
+fn default_idle_timeout_ms() -> u64 {
+    5000
+}
+
+/// 线路上的数值类型
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueType {
+    U8,
+    #[default]
+    U16,
+    U32,
+    U64,
+    I16,
+    I32,
+    I64,
+    F32,
+    F64,
+}
+
+/// 完成条件的配置, 作用于校准后的读数
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ReadySpec {
+    /// 只在数据流结束时完成
+    Never,
+    Count { count: usize },
+    /// 读数累加和达到阈值
+    SumReached { threshold: f64 },
+    /// 读数累加和是 `divisor` 的整数倍
+    SumMultipleOf { divisor: f64 },
+    /// 读数大于 `threshold`
+    Above { threshold: f64 },
+    /// 读数小于 `threshold`
+    Below { threshold: f64 },
+    /// 从第一个读数开始经过的秒数
+    Elapsed { secs: f64 },
+    /// 任一子条件满足
+    AnyOf { of: Vec<ReadySpec> },
+}
+
+impl Default for ReadySpec {
+    fn default() -> Self {
+        ReadySpec::Count { count: 108 }
+    }
+}
+
+impl ReadySpec {
+    pub fn build(&self) -> Box<dyn ReadyCondition<Reading>> {
+        match self.clone() {
+            ReadySpec::Never => Box::new(|_: &Reading, _: &[Reading]| false),
+            ReadySpec::Count { count } => Box::new(CountReached(count)),
+            ReadySpec::SumReached { threshold } => {
+                let mut sum = 0.0;
+                Box::new(move |reading: &Reading, _: &[Reading]| {
+                    sum += reading.value;
+                    sum >= threshold
+                })
+            }
+            ReadySpec::SumMultipleOf { divisor } => {
+                let mut sum = 0.0;
+                Box::new(move |reading: &Reading, _: &[Reading]| {
+                    sum += reading.value;
+                    sum % divisor == 0.0
+                })
+            }
+            ReadySpec::Above { threshold } => Box::new(move |reading: &Reading, _: &[Reading]| reading.value > threshold),
+            ReadySpec::Below { threshold } => Box::new(move |reading: &Reading, _: &[Reading]| reading.value < threshold),
+            ReadySpec::Elapsed { secs } => Box::new(TimeElapsed::new(Duration::from_secs_f64(secs))),
+            ReadySpec::AnyOf { of } => Box::new(AnyOf(of.iter().map(ReadySpec::build).collect())),
+        }
+    }
+
+    /// 检查 `build` 时会 panic 的配置, 包括 `AnyOf` 中的子条件
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            ReadySpec::Elapsed { secs } if Duration::try_from_secs_f64(*secs).is_err() => {
+                Err(format!("elapsed secs must be a non-negative duration, got {}", secs))
+            }
+            ReadySpec::SumMultipleOf { divisor } if *divisor == 0.0 || !divisor.is_finite() => {
+                Err(format!("sum_multiple_of divisor must be finite and non-zero, got {}", divisor))
+            }
+            ReadySpec::AnyOf { of } => of.iter().try_for_each(ReadySpec::validate),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// 采集失败(数据流在完成条件满足前结束)后的处理方式
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RestartPolicy {
+    Never,
+    /// 最多重启 `max_restarts` 次, 第一次重启前等待 `backoff_ms`, 之后每次翻倍
+    OnFailure { max_restarts: u32, backoff_ms: u64 },
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy::OnFailure { max_restarts: 3, backoff_ms: 1000 }
+    }
+}
+
+/// 注册表中的一个设备
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeviceEntry {
+    pub id: u32,
+    pub transport: Transport,
+    #[serde(default)]
+    pub value_type: ValueType,
+    /// 每秒最多采集的读数, 不设置时按生产者的速度采集
+    #[serde(default)]
+    pub sampling_rate: Option<f64>,
+    #[serde(default)]
+    pub calibration: Calibration,
+    #[serde(default)]
+    pub ready: ReadySpec,
+    /// 覆盖注册表的默认重启策略
+    #[serde(default)]
+    pub restart: Option<RestartPolicy>,
+}
+
+impl DeviceEntry {
+    pub fn new(id: u32, transport: Transport) -> Self {
+        Self {
+            id,
+            transport,
+            value_type: ValueType::default(),
+            sampling_rate: None,
+            calibration: Calibration::default(),
+            ready: ReadySpec::default(),
+            restart: None,
+        }
+    }
+
+    /// 两次采集之间的最小间隔
+    pub fn sampling_interval(&self) -> Option<Duration> {
+        self.sampling_rate.map(|rate| Duration::from_secs_f64(1.0 / rate))
+    }
+}
+
+/// 设备注册表, 从 TOML 或 JSON 配置加载
+///
+/// ```toml
+/// [restart]
+/// type = "on_failure"
+/// max_restarts = 5
+/// backoff_ms = 500
+///
+/// [[device]]
+/// id = 1
+/// transport = { type = "tcp", addr = "127.0.0.1:7801", simulated = true }
+/// value_type = "u64"
+/// sampling_rate = 2.0
+/// calibration = { scale = 0.1, offset = -40.0 }
+/// ready = { type = "count", count = 100 }
+/// ```
+///
+/// JSON 使用相同的结构, 设备列表的键可以是 `device` 或 `devices`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DeviceRegistry {
+    /// 设备没有单独配置时使用的重启策略
+    #[serde(default)]
+    pub restart: RestartPolicy,
+    #[serde(default, rename = "device", alias = "devices")]
+    pub devices: Vec<DeviceEntry>,
+}
+
+impl DeviceRegistry {
+    /// 按扩展名选择格式
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, RegistryError> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Self::from_toml(&content),
+            Some("json") => Self::from_json(&content),
+            _ => Err(RegistryError::UnsupportedFormat(path.display().to_string())),
+        }
+    }
+
+    pub fn from_toml(content: &str) -> Result<Self, RegistryError> {
+        let registry: Self = toml::from_str(content)?;
+        registry.validate()?;
+        Ok(registry)
+    }
+
+    pub fn from_json(content: &str) -> Result<Self, RegistryError> {
+        let registry: Self = serde_json::from_str(content)?;
+        registry.validate()?;
+        Ok(registry)
+    }
+
+    /// 原先 `main` 中的 `n` 个匿名收集器: 按 `i % 3` 轮流使用 channel(u16), rand(i16)
+    /// 和端口为 `base_port + i` 的模拟 TCP 设备(u64)
+    pub fn legacy(n: u32, base_port: u16) -> Self {
+        let ready = ReadySpec::AnyOf {
+            of: vec![ReadySpec::SumMultipleOf { divisor: 17.0 }, ReadySpec::Count { count: 108 }],
+        };
+        let devices = (0..n)
+            .map(|i| {
+                let (transport, value_type) = match i % 3 {
+                    0 => (Transport::Channel, ValueType::U16),
+                    1 => (Transport::Rand, ValueType::I16),
+                    _ => {
+                        let addr = format!("127.0.0.1:{}", base_port as u32 + i);
+                        (Transport::Tcp { addr, simulated: true }, ValueType::U64)
+                    }
+                };
+                DeviceEntry { value_type, ready: ready.clone(), ..DeviceEntry::new(i, transport) }
+            })
+            .collect();
+        Self { restart: RestartPolicy::Never, devices }
+    }
+
+    pub fn get(&self, id: u32) -> Option<&DeviceEntry> {
+        self.devices.iter().find(|device| device.id == id)
+    }
+
+    pub fn restart_policy(&self, device: &DeviceEntry) -> RestartPolicy {
+        device.restart.unwrap_or(self.restart)
+    }
+
+    pub fn validate(&self) -> Result<(), RegistryError> {
+        let mut ids = HashSet::new();
+        for device in &self.devices {
+            if !ids.insert(device.id) {
+                return Err(RegistryError::DuplicateId(device.id));
+            }
+            let invalid = |reason: &str| RegistryError::Invalid { id: device.id, reason: reason.to_string() };
+            // 采样间隔为 1 / rate, 过小的 rate 使间隔超出 Duration 的范围
+            if device
+                .sampling_rate
+                .is_some_and(|rate| !(rate.is_finite() && rate > 0.0) || Duration::try_from_secs_f64(1.0 / rate).is_err())
+            {
+                return Err(invalid("sampling_rate must be positive and its interval must fit in a Duration"));
+            }
+            device.ready.validate().map_err(|reason| invalid(&reason))?;
+            if !(device.calibration.scale.is_finite() && device.calibration.offset.is_finite()) {
+                return Err(invalid("calibration must be finite"));
+            }
+            if let Transport::Tcp { addr, .. } | Transport::Udp { addr, .. } = &device.transport {
+                if addr.parse::<std::net::SocketAddr>().is_err() {
+                    return Err(invalid(&format!("invalid address {:?}", addr)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_load_toml_and_json() {
+        let toml = r#"
+            [restart]
+            type = "never"
+
+            [[device]]
+            id = 1
+            transport = { type = "tcp", addr = "127.0.0.1:7801", simulated = true }
+            value_type = "u64"
+            sampling_rate = 2.0
+            calibration = { scale = 0.5 }
+            ready = { type = "any_of", of = [{ type = "count", count = 10 }, { type = "above", threshold = 100.0 }] }
+            restart = { type = "on_failure", max_restarts = 2, backoff_ms = 10 }
+
+            [[device]]
+            id = 2
+            transport = { type = "rand" }
+        "#;
+        let registry = DeviceRegistry::from_toml(toml).unwrap();
+        assert_eq!(registry.devices.len(), 2);
+        let tcp = registry.get(1).unwrap();
+        assert_eq!(tcp.value_type, ValueType::U64);
+        assert_eq!(tcp.calibration, Calibration::linear(0.5, 0.0));
+        assert_eq!(tcp.sampling_interval(), Some(Duration::from_millis(500)));
+        assert_eq!(registry.restart_policy(tcp), RestartPolicy::OnFailure { max_restarts: 2, backoff_ms: 10 });
+        let rand = registry.get(2).unwrap();
+        assert_eq!((rand.value_type, &rand.ready), (ValueType::U16, &ReadySpec::default()));
+        assert_eq!(registry.restart_policy(rand), RestartPolicy::Never);
+
+        let json = serde_json::to_string(&registry).unwrap();
+        assert_eq!(DeviceRegistry::from_json(&json).unwrap(), registry);
+        let json = r#"{"devices": [{"id": 7, "transport": {"type": "udp", "addr": "127.0.0.1:9000"}, "value_type": "f32"}]}"#;
+        assert_eq!(DeviceRegistry::from_json(json).unwrap().get(7).unwrap().value_type, ValueType::F32);
+
+        let duplicate = "[[device]]\nid = 1\ntransport = { type = \"rand\" }\n[[device]]\nid = 1\ntransport = { type = \"channel\" }";
+        assert!(matches!(DeviceRegistry::from_toml(duplicate), Err(RegistryError::DuplicateId(1))));
+        let bad_rate = "[[device]]\nid = 3\ntransport = { type = \"rand\" }\nsampling_rate = 0.0";
+        assert!(matches!(DeviceRegistry::from_toml(bad_rate), Err(RegistryError::Invalid { id: 3, .. })));
+        let tiny_rate = "[[device]]\nid = 4\ntransport = { type = \"rand\" }\nsampling_rate = 1e-300";
+        assert!(matches!(DeviceRegistry::from_toml(tiny_rate), Err(RegistryError::Invalid { id: 4, .. })));
+        let bad_elapsed = r#"{"devices": [{"id": 5, "transport": {"type": "rand"},
+            "ready": {"type": "any_of", "of": [{"type": "count", "count": 3}, {"type": "elapsed", "secs": -1.0}]}}]}"#;
+        assert!(matches!(DeviceRegistry::from_json(bad_elapsed), Err(RegistryError::Invalid { id: 5, .. })));
+        let zero_divisor = r#"{"devices": [{"id": 6, "transport": {"type": "rand"},
+            "ready": {"type": "sum_multiple_of", "divisor": 0.0}}]}"#;
+        assert!(matches!(DeviceRegistry::from_json(zero_divisor), Err(RegistryError::Invalid { id: 6, .. })));
+    }
+
+    #[test]
+    fn test_ready_spec() {
+        let readings: Vec<Reading> = [5.0, 6.0, 6.0, 10.0].iter().map(|v| Reading::new(1, 0, *v)).collect();
+        let first_ready = |spec: ReadySpec| {
+            let mut cond = spec.build();
+            (1..=readings.len()).find(|n| cond.is_ready(&readings[n - 1], &readings[..*n]))
+        };
+        assert_eq!(first_ready(ReadySpec::SumMultipleOf { divisor: 17.0 }), Some(3));
+        assert_eq!(first_ready(ReadySpec::SumReached { threshold: 20.0 }), Some(4));
+        assert_eq!(first_ready(ReadySpec::Above { threshold: 5.5 }), Some(2));
+        assert_eq!(first_ready(ReadySpec::Never), None);
+        assert_eq!(
+            first_ready(ReadySpec::AnyOf { of: vec![ReadySpec::Count { count: 2 }, ReadySpec::Below { threshold: 0.0 }] }),
+            Some(2)
+        );
+    }
+}
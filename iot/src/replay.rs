@@ -0,0 +1,463 @@
+use std::cell::Cell;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::task::Waker;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::warn;
+use thiserror::Error;
+use crate::deterministic::Clock;
+use crate::producer::{impl_waker_methods, Producer, ToBytes};
+
+/// 日志文件头
+const MAGIC: &[u8; 8] = b"IOTREC01";
+/// 每条记录的 时间戳(i64, 微秒) + 数据长度(u32)
+const RECORD_HEADER_LEN: usize = 12;
+/// 单条记录数据的最大长度, 超过时认为日志损坏, 避免按错误的长度分配内存
+const MAX_RECORD_LEN: usize = 1 << 20;
+
+#[derive(Error, Debug)]
+pub enum ReplayError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("not a recording: bad header")]
+    InvalidHeader,
+}
+
+/// 当前系统时间, 微秒
+fn now_micros() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_micros() as i64)
+}
+
+/// 追加写入的原始读数日志
+///
+/// 文件头 `IOTREC01` 之后每条记录依次为时间戳(i64, 小端, 微秒), 数据长度(u32, 小端)
+/// 和 `ToBytes::to_le_bytes` 的结果. 进程崩溃时最后一条记录可能不完整,
+/// 读取时忽略, `Recorder::append` 会先截掉它再继续追加.
+pub struct Recorder<W: Write = BufWriter<File>> {
+    writer: W,
+    records: u64,
+}
+
+impl Recorder {
+    /// 打开 `path` 追加记录, 文件不存在或为空时写入文件头
+    pub fn append(path: impl AsRef<Path>) -> Result<Self, ReplayError> {
+        let mut file = OpenOptions::new().create(true).read(true).append(true).open(path)?;
+        if file.metadata()?.len() == 0 {
+            return Recorder::new(BufWriter::new(file));
+        }
+
+        let mut reader = RecordReader::new(BufReader::new(&mut file))?;
+        let mut records = 0;
+        while reader.next_raw()?.is_some() {
+            records += 1;
+        }
+        let valid = reader.offset;
+        drop(reader);
+        file.set_len(valid)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(Self { writer: BufWriter::new(file), records })
+    }
+}
+
+impl<W: Write> Recorder<W> {
+    /// 写入文件头
+    pub fn new(mut writer: W) -> Result<Self, ReplayError> {
+        writer.write_all(MAGIC)?;
+        Ok(Self { writer, records: 0 })
+    }
+
+    /// 记录一个在 `timestamp`(微秒)收到的数据
+    pub fn record<T: ToBytes>(&mut self, timestamp: i64, value: &T) -> std::io::Result<()> {
+        let data = value.to_le_bytes();
+        self.writer.write_all(&timestamp.to_le_bytes())?;
+        self.writer.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&data)?;
+        self.records += 1;
+        Ok(())
+    }
+
+    /// 已经记录的数据个数, 包括追加前文件中的记录
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// 顺序读取 `Recorder` 写入的日志
+pub struct RecordReader<R> {
+    reader: R,
+    /// 最后一条完整记录之后的位置
+    offset: u64,
+}
+
+impl<R: Read> RecordReader<R> {
+    /// 读取并校验文件头
+    pub fn new(mut reader: R) -> Result<Self, ReplayError> {
+        let mut magic = [0u8; MAGIC.len()];
+        match reader.read_exact(&mut magic) {
+            Ok(()) if &magic == MAGIC => Ok(Self { reader, offset: MAGIC.len() as u64 }),
+            Ok(()) => Err(ReplayError::InvalidHeader),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Err(ReplayError::InvalidHeader),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// 下一条记录的时间戳(微秒)和数据, 日志结束或最后一条记录不完整时返回 `None`.
+    /// 记录长度与 `T` 不符(录制时使用了其他类型)时返回 `InvalidData`
+    pub fn next_record<T: ToBytes>(&mut self) -> std::io::Result<Option<(i64, T)>> {
+        let offset = self.offset;
+        let Some((timestamp, data)) = self.next_raw()? else {
+            return Ok(None);
+        };
+        if data.len() != T::SIZE {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("record at offset {} has {} bytes, expected {}", offset, data.len(), T::SIZE),
+            ));
+        }
+        Ok(Some((timestamp, T::from_le_bytes(&data))))
+    }
+
+    fn next_raw(&mut self) -> std::io::Result<Option<(i64, Vec<u8>)>> {
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        match self.read_full(&mut header)? {
+            0 => return Ok(None),
+            RECORD_HEADER_LEN => {}
+            _ => return Ok(self.truncated()),
+        }
+        let timestamp = i64::from_le_bytes(header[0..8].try_into().unwrap());
+        let len = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+        if len > MAX_RECORD_LEN {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("record at offset {} is {} bytes long", self.offset, len),
+            ));
+        }
+        let mut data = vec![0u8; len];
+        if self.read_full(&mut data)? < len {
+            return Ok(self.truncated());
+        }
+        self.offset += (RECORD_HEADER_LEN + len) as u64;
+        Ok(Some((timestamp, data)))
+    }
+
+    fn truncated<V>(&self) -> Option<V> {
+        warn!("ignoring truncated record at offset {}", self.offset);
+        None
+    }
+
+    /// 尽量读满 `buf`, 返回读到的字节数
+    fn read_full(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.reader.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(filled)
+    }
+}
+
+/// 包装任意生产者, 把产生的每个数据连同接收时间写入日志
+///
+/// 写入失败后停止记录, 生产者照常工作. 缓冲的记录在 `stop` 和丢弃时写入文件.
+pub struct RecordingProducer<P, W: Write = BufWriter<File>> {
+    inner: P,
+    recorder: Option<Recorder<W>>,
+}
+
+impl<P, W: Write> RecordingProducer<P, W> {
+    pub fn new(inner: P, recorder: Recorder<W>) -> Self {
+        Self { inner, recorder: Some(recorder) }
+    }
+
+    /// 写入失败后返回 `None`
+    pub fn recorder(&self) -> Option<&Recorder<W>> {
+        self.recorder.as_ref()
+    }
+
+    pub fn into_parts(self) -> (P, Option<Recorder<W>>) {
+        (self.inner, self.recorder)
+    }
+
+    fn record<T: ToBytes>(&mut self, value: &T) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(e) = recorder.record(now_micros(), value) {
+                warn!("recording stopped: {}", e);
+                self.recorder = None;
+            }
+        }
+    }
+}
+
+impl<T, P, W> Producer<T> for RecordingProducer<P, W>
+where
+    T: ToBytes,
+    P: Producer<T>,
+    W: Write,
+{
+    fn produce(&mut self) -> Option<T> {
+        let value = self.inner.produce()?;
+        self.record(&value);
+        Some(value)
+    }
+
+    fn data_available(&mut self) -> bool {
+        self.inner.data_available()
+    }
+
+    fn store_waker(&mut self, waker: &Waker) -> bool {
+        self.inner.store_waker(waker)
+    }
+
+    fn stop(&mut self) {
+        self.inner.stop();
+        if let Some(Err(e)) = self.recorder.as_mut().map(Recorder::flush) {
+            warn!("failed to flush recording: {}", e);
+        }
+    }
+
+    fn drain(&mut self) -> Vec<T> {
+        let drained = self.inner.drain();
+        for value in &drained {
+            self.record(value);
+        }
+        drained
+    }
+
+    fn set_waker(&mut self, waker: Option<Waker>) {
+        self.inner.set_waker(waker)
+    }
+
+    fn get_waker(&self) -> Option<&Waker> {
+        self.inner.get_waker()
+    }
+}
+
+/// 回放的速度
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ReplaySpeed {
+    /// 按记录的时间间隔
+    #[default]
+    Original,
+    /// 时间间隔除以倍数, 例如 `Scaled(2.0)` 以两倍速回放
+    Scaled(f64),
+    /// 不等待
+    AsFastAsPossible,
+}
+
+/// 回放 `Recorder` 写入的日志, 日志结束后 `produce` 返回 `None`
+///
+/// 第一条记录在第一次 `data_available` 时立即到达, 之后每条记录按它和第一条记录的
+/// 时间差(按 `ReplaySpeed` 缩放)到达. 和其他同步生产者一样在 `data_available`
+/// 中等待, `Clock::Virtual` 下只累加等待时间.
+pub struct ReplayProducer<T, R = BufReader<File>> {
+    waker: Option<Waker>,
+    reader: RecordReader<R>,
+    next: Option<(i64, T)>,
+    first_timestamp: Option<i64>,
+    speed: ReplaySpeed,
+    clock: Clock,
+    started: Cell<Option<Instant>>,
+    /// 虚拟时钟下从开始回放经过的时间
+    waited: Cell<Duration>,
+    replayed: u64,
+}
+
+impl<T: ToBytes> ReplayProducer<T> {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, ReplayError> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<T: ToBytes, R: Read> ReplayProducer<T, R> {
+    pub fn from_reader(reader: R) -> Result<Self, ReplayError> {
+        let mut reader = RecordReader::new(reader)?;
+        let next = reader.next_record()?;
+        Ok(Self {
+            waker: None,
+            first_timestamp: next.as_ref().map(|(timestamp, _)| *timestamp),
+            reader,
+            next,
+            speed: ReplaySpeed::default(),
+            clock: Clock::default(),
+            started: Cell::new(None),
+            waited: Cell::new(Duration::ZERO),
+            replayed: 0,
+        })
+    }
+
+    pub fn with_speed(mut self, speed: ReplaySpeed) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// 已经回放的数据个数
+    pub fn replayed(&self) -> u64 {
+        self.replayed
+    }
+
+    /// 下一条记录的时间戳(微秒)
+    pub fn next_timestamp(&self) -> Option<i64> {
+        self.next.as_ref().map(|(timestamp, _)| *timestamp)
+    }
+
+    /// `Clock::Virtual` 下 `data_available` 累计的等待时间
+    pub fn waited(&self) -> Duration {
+        self.waited.get()
+    }
+
+    /// 下一条记录相对回放开始的到达时间, 时间戳倒退的记录立即到达
+    fn due(&self, timestamp: i64) -> Duration {
+        let offset = Duration::from_micros(timestamp.saturating_sub(self.first_timestamp.unwrap_or(timestamp)).max(0) as u64);
+        match self.speed {
+            ReplaySpeed::Original => offset,
+            ReplaySpeed::Scaled(factor) if factor > 0.0 => offset.div_f64(factor),
+            ReplaySpeed::Scaled(_) | ReplaySpeed::AsFastAsPossible => Duration::ZERO,
+        }
+    }
+}
+
+impl<T: ToBytes, R: Read> Producer<T> for ReplayProducer<T, R> {
+    fn produce(&mut self) -> Option<T> {
+        let (_, value) = self.next.take()?;
+        self.next = self.reader.next_record().unwrap_or_else(|e| {
+            warn!("replay stopped: {}", e);
+            None
+        });
+        self.replayed += 1;
+        if let Some(waker) = &self.waker {
+            waker.wake_by_ref();
+        }
+        Some(value)
+    }
+
+    fn data_available(&mut self) -> bool {
+        let Some(timestamp) = self.next_timestamp() else {
+            // 让 `produce` 返回 `None` 结束收集
+            return true;
+        };
+        let due = self.due(timestamp);
+        match self.clock {
+            Clock::Real => {
+                let started = self.started.get().unwrap_or_else(Instant::now);
+                self.started.set(Some(started));
+                let elapsed = started.elapsed();
+                if due > elapsed {
+                    std::thread::sleep(due - elapsed);
+                }
+            }
+            Clock::Virtual => self.waited.set(self.waited.get().max(due)),
+        }
+        true
+    }
+
+    impl_waker_methods!();
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use crate::collector::Collector;
+    use crate::condition::CountReached;
+    use crate::deterministic::Determinism;
+    use crate::producer::RandProducer;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let _ = env_logger::try_init();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rand.rec");
+
+        let producer = RandProducer::<u32>::with_determinism(Determinism::seeded(3));
+        let recording = RecordingProducer::new(producer, Recorder::append(&path).unwrap());
+        let recorded = Collector::with_condition(recording, 0, CountReached(108)).await;
+        assert_eq!(recorded.len(), 108);
+
+        // 追加时忽略被截断的最后一条记录
+        let len = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 2).unwrap();
+        let mut recorder = Recorder::append(&path).unwrap();
+        assert_eq!(recorder.records(), 107);
+        recorder.record(now_micros(), &7u32).unwrap();
+        drop(recorder);
+
+        let replay = ReplayProducer::<u32>::open(&path).unwrap().with_speed(ReplaySpeed::AsFastAsPossible);
+        let replayed = Collector::with_condition(replay, 1, |_: &u32, _: &[u32]| false).await;
+        assert_eq!(&replayed[..107], &recorded[..107]);
+        assert_eq!(replayed[107], 7);
+        assert!(matches!(ReplayProducer::<u32, _>::from_reader(Cursor::new(b"IOTREC0")), Err(ReplayError::InvalidHeader)));
+    }
+
+    #[test]
+    fn test_invalid_records() {
+        let mut recorder = Recorder::new(Vec::new()).unwrap();
+        recorder.record(1, &7u32).unwrap();
+        let log = recorder.into_inner();
+
+        // 用其他类型打开时返回错误而不是 panic
+        match ReplayProducer::<u16, _>::from_reader(Cursor::new(log.clone())) {
+            Err(ReplayError::Io(e)) => assert_eq!(e.kind(), ErrorKind::InvalidData),
+            _ => panic!("expected InvalidData"),
+        }
+
+        // 损坏的长度字段不会按该长度分配内存
+        let mut log = log[..MAGIC.len()].to_vec();
+        log.extend_from_slice(&1i64.to_le_bytes());
+        log.extend_from_slice(&u32::MAX.to_le_bytes());
+        let mut reader = RecordReader::new(Cursor::new(log)).unwrap();
+        assert_eq!(reader.next_record::<u32>().unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_replay_timing() {
+        let mut recorder = Recorder::new(Vec::new()).unwrap();
+        for (timestamp, value) in [(1_000_000, 1u16), (1_250_000, 2), (1_200_000, 3), (3_000_000, 4)] {
+            recorder.record(timestamp, &value).unwrap();
+        }
+        let log = recorder.into_inner();
+
+        for (speed, total) in [
+            (ReplaySpeed::Original, Duration::from_secs(2)),
+            (ReplaySpeed::Scaled(4.0), Duration::from_millis(500)),
+            (ReplaySpeed::AsFastAsPossible, Duration::ZERO),
+        ] {
+            let mut replay = ReplayProducer::<u16, _>::from_reader(Cursor::new(log.clone()))
+                .unwrap()
+                .with_speed(speed)
+                .with_clock(Clock::Virtual);
+            let mut waits = Vec::new();
+            let mut values = Vec::new();
+            while replay.data_available() {
+                let Some(value) = replay.produce() else { break };
+                waits.push(replay.waited());
+                values.push(value);
+            }
+            assert_eq!(values, [1, 2, 3, 4]);
+            assert_eq!(replay.replayed(), 4);
+            // 时间戳倒退的第三条记录立即到达
+            assert_eq!(waits[1], waits[2]);
+            assert_eq!(replay.waited(), total, "{:?}", speed);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::thread::JoinHandle;
+use log::{debug, info, warn};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFutureOwned};
+
+/// 进程级的停止信号, 可以克隆后传给生产者, 收集器和它们启动的线程/任务
+///
+/// 触发后:
+/// - `Collector` / `CollectorBuilder` 停止收集, 通知生产者停止并返回已收集的数据
+/// - `base_producer::Producer` 关闭通道, 把缓冲中的数据全部产出后结束
+/// - 通过 `spawn_thread` 启动的线程应检查 `is_triggered` 并尽快退出,
+///   最后由 `join_threads` 等待它们结束
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    token: CancellationToken,
+    threads: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn trigger(&self) {
+        self.token.cancel();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// 等待停止信号
+    pub async fn triggered(&self) {
+        self.token.cancelled().await
+    }
+
+    /// 可以保存在结构体中手动 poll 的等待 future
+    pub fn signal(&self) -> Signal {
+        Signal(Box::pin(self.token.clone().cancelled_owned()))
+    }
+
+    /// 收到 Ctrl-C 时触发停止信号, 需要在 tokio 运行时中调用
+    pub fn trigger_on_ctrl_c(&self) {
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                res = tokio::signal::ctrl_c() => {
+                    match res {
+                        Ok(()) => info!("received Ctrl-C, shutting down"),
+                        Err(e) => warn!("failed to listen for Ctrl-C: {}", e),
+                    }
+                    shutdown.trigger();
+                }
+                _ = shutdown.triggered() => {}
+            }
+        });
+    }
+
+    /// 启动一个由 `join_threads` 统一等待的线程
+    pub fn spawn_thread<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let handle = std::thread::spawn(f);
+        self.threads.lock().unwrap().push(handle);
+    }
+
+    /// 等待所有通过 `spawn_thread` 启动的线程结束, 会阻塞当前线程
+    pub fn join_threads(&self) {
+        let threads = std::mem::take(&mut *self.threads.lock().unwrap());
+        debug!("joining {} threads", threads.len());
+        for handle in threads {
+            if handle.join().is_err() {
+                warn!("thread panicked before shutdown");
+            }
+        }
+    }
+}
+
+/// `Shutdown::signal` 返回的 future, 停止信号触发后完成
+pub struct Signal(Pin<Box<WaitForCancellationFutureOwned>>);
+
+impl Future for Signal {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.0.as_mut().poll(cx)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown_joins_threads() {
+        let shutdown = Shutdown::new();
+        let exited = Arc::new(AtomicBool::new(false));
+        {
+            let shutdown = shutdown.clone();
+            let exited = exited.clone();
+            shutdown.clone().spawn_thread(move || {
+                while !shutdown.is_triggered() {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                exited.store(true, Ordering::SeqCst);
+            });
+        }
+
+        let signal = shutdown.signal();
+        shutdown.trigger();
+        signal.await;
+        shutdown.join_threads();
+        assert!(exited.load(Ordering::SeqCst));
+    }
+}
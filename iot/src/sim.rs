@@ -0,0 +1,372 @@
+use std::f64::consts::PI;
+use std::io::ErrorKind;
+use std::time::Duration;
+use log::{debug, warn};
+use num_traits::{Bounded, NumCast, ToPrimitive};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Distribution, Normal};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use crate::producer::{Endian, ToBytes, STOP};
+use crate::protocol::{decode_request, encode_response, response_len, Backoff, DeviceSession, REQUEST_LEN};
+
+/// 模拟设备产生数值的分布
+#[derive(Clone, Debug, PartialEq)]
+pub enum ValueDistribution {
+    /// 与 `RandProducer` 相同的均匀分布, 区间为 `[low, high)`
+    Uniform { low: f64, high: f64 },
+    /// 正态分布
+    Gaussian { mean: f64, std_dev: f64 },
+    /// 正弦波, `period` 为一个周期的采样数, `noise` 为叠加噪声的标准差
+    Sine { amplitude: f64, period: u32, offset: f64, noise: f64 },
+    /// 阶跃变化, 每 `every` 个采样切换到下一个电平
+    Step { levels: Vec<f64>, every: u32 },
+}
+
+impl Default for ValueDistribution {
+    fn default() -> Self {
+        ValueDistribution::Uniform { low: 0.0, high: 250.0 }
+    }
+}
+
+impl ValueDistribution {
+    /// 产生第 `step` 个采样值
+    pub fn sample<R: Rng>(&self, step: u64, rng: &mut R) -> f64 {
+        match self {
+            ValueDistribution::Uniform { low, high } => {
+                if high > low {
+                    rng.gen_range(*low..*high)
+                } else {
+                    *low
+                }
+            }
+            ValueDistribution::Gaussian { mean, std_dev } => gaussian(*mean, *std_dev, rng),
+            ValueDistribution::Sine { amplitude, period, offset, noise } => {
+                let phase = (step % (*period).max(1) as u64) as f64 / (*period).max(1) as f64;
+                offset + amplitude * (2.0 * PI * phase).sin() + gaussian(0.0, *noise, rng)
+            }
+            ValueDistribution::Step { levels, every } => {
+                if levels.is_empty() {
+                    return 0.0;
+                }
+                let idx = (step / (*every).max(1) as u64) as usize % levels.len();
+                levels[idx]
+            }
+        }
+    }
+}
+
+fn gaussian<R: Rng>(mean: f64, std_dev: f64, rng: &mut R) -> f64 {
+    match Normal::new(mean, std_dev) {
+        Ok(normal) if std_dev > 0.0 => normal.sample(rng),
+        _ => mean,
+    }
+}
+
+/// 将采样值转换为线路上的数值类型, 超出范围时取边界值, 整数类型四舍五入
+fn saturating_cast<T: NumCast + Bounded + ToPrimitive>(value: f64) -> T {
+    let min = T::min_value().to_f64().unwrap_or(f64::MIN);
+    let max = T::max_value().to_f64().unwrap_or(f64::MAX);
+    let is_float = T::from(0.5).and_then(|half| half.to_f64()) == Some(0.5);
+    if value.is_nan() || value <= min {
+        T::min_value()
+    } else if value >= max {
+        T::max_value()
+    } else if is_float {
+        T::from(value).unwrap_or_else(T::max_value)
+    } else {
+        T::from(value.round()).unwrap_or_else(T::max_value)
+    }
+}
+
+/// 单个模拟设备的配置
+#[derive(Clone, Debug)]
+pub struct DeviceConfig {
+    /// 收集端(`TCPProducer::listen`)的监听地址
+    pub addr: String,
+    pub distribution: ValueDistribution,
+    /// 收到 ACK 后到发送数据之间的基础间隔
+    pub interval: Duration,
+    /// 在 `interval` 上叠加的最大随机抖动
+    pub jitter: Duration,
+    /// 每次应答前主动断开连接的概率
+    pub disconnect_probability: f64,
+    /// 断开或连接失败后第一次重连前的等待时间, 之后每次翻倍
+    pub reconnect_delay: Duration,
+    /// 重连等待时间的上限
+    pub max_reconnect_delay: Duration,
+    /// 最多重连次数, 超过后设备退出
+    pub max_reconnects: u32,
+    /// 发送长度错误的数据帧的概率
+    pub malformed_probability: f64,
+    /// 读数的字节序, 需要与收集端 `TCPProducer::with_endian` 一致
+    pub endian: Endian,
+    /// 随机数种子, `None` 时使用系统熵
+    pub seed: Option<u64>,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self {
+            addr: "127.0.0.1:7800".to_string(),
+            distribution: ValueDistribution::default(),
+            interval: Duration::from_millis(100),
+            jitter: Duration::ZERO,
+            disconnect_probability: 0.0,
+            reconnect_delay: Duration::from_millis(500),
+            max_reconnect_delay: Duration::from_secs(10),
+            max_reconnects: 10,
+            malformed_probability: 0.0,
+            endian: Endian::default(),
+            seed: None,
+        }
+    }
+}
+
+/// 模拟设备的运行统计
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DeviceStats {
+    /// 发送的正常数据帧数
+    pub sent: u64,
+    /// 发送的错误数据帧数
+    pub malformed: u64,
+    /// 主动断开的次数
+    pub disconnects: u64,
+    /// 重新连接的次数
+    pub reconnects: u64,
+    /// 重连后重发的未确认读数
+    pub resent: u64,
+}
+
+enum Session {
+    /// 收集端发送了 STOP
+    Stopped,
+    /// 连接断开(主动或被动)
+    Dropped,
+}
+
+/// 讲 `TCPProducer` 线路协议的模拟设备
+pub struct SimDevice<T> {
+    id: u32,
+    config: DeviceConfig,
+    rng: StdRng,
+    step: u64,
+    session: DeviceSession<T>,
+    stats: DeviceStats,
+    _marker: std::marker::PhantomData<T>,
+}
+
+impl<T> SimDevice<T>
+where
+    T: ToBytes + NumCast + Bounded + ToPrimitive + Clone,
+{
+    pub fn new(id: u32, config: DeviceConfig) -> Self {
+        let mut rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Self {
+            id,
+            config,
+            session: DeviceSession::new(rng.gen()),
+            rng,
+            step: 0,
+            stats: DeviceStats::default(),
+            _marker: std::marker::PhantomData,
+        }
+    }
+
+    /// 连接收集端并应答数据请求, 直到收到 STOP 或重连次数耗尽
+    ///
+    /// 断开后按指数退避重连, 沿用原来的会话号, 重发未被确认的读数.
+    pub async fn run(mut self) -> std::io::Result<DeviceStats> {
+        let mut attempts = 0;
+        let mut backoff = Backoff::new(self.config.reconnect_delay, self.config.max_reconnect_delay);
+        loop {
+            let mut stream = match TcpStream::connect(&self.config.addr).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    if attempts >= self.config.max_reconnects {
+                        return Err(e);
+                    }
+                    attempts += 1;
+                    let delay = backoff.next_delay();
+                    debug!("device {} connect failed: {}, retrying in {:?}", self.id, e, delay);
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+            };
+            backoff.reset();
+            if self.step > 0 {
+                self.stats.reconnects += 1;
+            }
+
+            match self.serve(&mut stream).await {
+                Session::Stopped => return Ok(self.stats),
+                Session::Dropped => {
+                    if attempts >= self.config.max_reconnects {
+                        return Ok(self.stats);
+                    }
+                    attempts += 1;
+                    tokio::time::sleep(backoff.next_delay()).await;
+                }
+            }
+        }
+    }
+
+    async fn serve(&mut self, stream: &mut TcpStream) -> Session {
+        if let Err(e) = stream.write_all(&self.session.hello()).await {
+            warn!("device {} hello failed: {}", self.id, e);
+            return Session::Dropped;
+        }
+        let mut request = [0u8; REQUEST_LEN];
+        loop {
+            if let Err(e) = stream.read_exact(&mut request).await {
+                if e.kind() != ErrorKind::UnexpectedEof {
+                    warn!("device {} read failed: {}", self.id, e);
+                }
+                return Session::Dropped;
+            }
+            let (cmd, expected) = decode_request(&request);
+            if cmd == STOP {
+                return Session::Stopped;
+            }
+
+            tokio::time::sleep(self.next_delay()).await;
+
+            let (seq, value, resent) = self.session.respond(expected, || {
+                let value = self.config.distribution.sample(self.step, &mut self.rng);
+                self.step += 1;
+                saturating_cast::<T>(value)
+            });
+            if resent {
+                self.stats.resent += 1;
+            }
+
+            // 读数已经产生但还没送达, 重连后会被重发
+            if self.chance(self.config.disconnect_probability) {
+                self.stats.disconnects += 1;
+                return Session::Dropped;
+            }
+
+            let frame = if self.chance(self.config.malformed_probability) {
+                self.stats.malformed += 1;
+                self.malformed_frame()
+            } else {
+                self.stats.sent += 1;
+                encode_response(seq, &value, self.config.endian)
+            };
+
+            if let Err(e) = stream.write_all(&frame).await {
+                warn!("device {} write failed: {}", self.id, e);
+                return Session::Dropped;
+            }
+        }
+    }
+
+    fn next_delay(&mut self) -> Duration {
+        let jitter = self.config.jitter.as_micros() as u64;
+        if jitter == 0 {
+            self.config.interval
+        } else {
+            self.config.interval + Duration::from_micros(self.rng.gen_range(0..=jitter))
+        }
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.rng.gen_bool(probability.min(1.0))
+    }
+
+    /// 长度比正常帧多出 1~3 个字节的随机数据
+    fn malformed_frame(&mut self) -> Vec<u8> {
+        let len = response_len::<T>() + self.rng.gen_range(1..=3);
+        (0..len).map(|_| self.rng.gen()).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+    use crate::producer::{Producer, TCPProducer};
+    use super::*;
+
+    #[test]
+    fn test_distribution_sample() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let step = ValueDistribution::Step { levels: vec![1.0, 5.0], every: 2 };
+        let values: Vec<f64> = (0..6).map(|i| step.sample(i, &mut rng)).collect();
+        assert_eq!(values, vec![1.0, 1.0, 5.0, 5.0, 1.0, 1.0]);
+
+        let sine = ValueDistribution::Sine { amplitude: 10.0, period: 4, offset: 20.0, noise: 0.0 };
+        assert!((sine.sample(1, &mut rng) - 30.0).abs() < 1e-9);
+        assert!((sine.sample(3, &mut rng) - 10.0).abs() < 1e-9);
+
+        assert_eq!(saturating_cast::<u16>(-3.0), 0);
+        assert_eq!(saturating_cast::<i8>(1000.0), i8::MAX);
+        assert_eq!(saturating_cast::<f32>(2.25), 2.25);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_sim_device_speaks_tcp_protocol() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = DeviceConfig {
+            addr: listener.local_addr().unwrap().to_string(),
+            distribution: ValueDistribution::Step { levels: vec![3.0, 9.0], every: 1 },
+            interval: Duration::ZERO,
+            seed: Some(1),
+            ..Default::default()
+        };
+        let device = tokio::spawn(SimDevice::<u64>::new(0, config).run());
+
+        let collected = tokio::task::spawn_blocking(move || {
+            let mut producer: TCPProducer<u64> = TCPProducer::accept(&listener).unwrap();
+            let data: Vec<u64> = (0..4).map(|_| producer.produce().unwrap()).collect();
+            producer.stop();
+            data
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(collected, vec![3, 9, 3, 9]);
+        let stats = device.await.unwrap().unwrap();
+        assert_eq!(stats.sent, 4);
+        assert_eq!(stats.malformed, 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_flaky_link_loses_nothing() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = DeviceConfig {
+            addr: listener.local_addr().unwrap().to_string(),
+            distribution: ValueDistribution::Step { levels: (0..30).map(|i| i as f64).collect(), every: 1 },
+            interval: Duration::ZERO,
+            disconnect_probability: 0.3,
+            malformed_probability: 0.1,
+            reconnect_delay: Duration::from_millis(1),
+            max_reconnect_delay: Duration::from_millis(4),
+            max_reconnects: 1000,
+            seed: Some(3),
+            ..Default::default()
+        };
+        let device = tokio::spawn(SimDevice::<u16>::new(0, config).run());
+
+        let (collected, link) = tokio::task::spawn_blocking(move || {
+            let mut producer: TCPProducer<u16> = TCPProducer::accept(&listener).unwrap();
+            let data: Vec<u16> = (0..30).map(|_| producer.produce().unwrap()).collect();
+            producer.stop();
+            (data, producer.stats())
+        })
+        .await
+        .unwrap();
+
+        // 断开和错误帧都触发重连, 未确认的读数被重发, 没有丢失也没有重复
+        assert_eq!(collected, (0..30).collect::<Vec<u16>>());
+        assert_eq!(link.lost, 0);
+        let stats = device.await.unwrap().unwrap();
+        assert!(stats.disconnects + stats.malformed > 0);
+        assert_eq!(link.reconnects, stats.reconnects);
+        assert!(stats.resent > 0);
+    }
+}
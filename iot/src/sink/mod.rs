@@ -0,0 +1,33 @@
+use arrow_schema::ArrowError;
+use ::parquet::errors::ParquetError;
+use std::path::PathBuf;
+use thiserror::Error;
+
+pub mod parquet;
+pub mod store;
+
+#[derive(Error, Debug)]
+pub enum SinkError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("parquet error: {0}")]
+    Parquet(#[from] ParquetError),
+    #[error("arrow error: {0}")]
+    Arrow(#[from] ArrowError),
+    #[error("transport error: {0}")]
+    Transport(#[from] tonic::transport::Error),
+    /// 重试次数用完后仍然失败的 rpc
+    #[error("rpc failed: {0}")]
+    Rpc(Box<tonic::Status>),
+    #[error("upstream error: {0}")]
+    Upstream(String),
+    /// 输出中途失败, `files` 为失败前已经写完的文件
+    #[error("{source} ({} files completed)", files.len())]
+    Incomplete { source: Box<SinkError>, files: Vec<PathBuf> },
+}
+
+impl From<tonic::Status> for SinkError {
+    fn from(status: tonic::Status) -> Self {
+        SinkError::Rpc(Box::new(status))
+    }
+}
@@ -0,0 +1,413 @@
+use std::convert::Infallible;
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use arrow_array::{Float64Array, RecordBatch, TimestampMillisecondArray, UInt32Array};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use log::{debug, warn};
+use parquet::arrow::ArrowWriter;
+use parquet::file::properties::WriterProperties;
+use tokio_stream::{Stream, StreamExt};
+use crate::reading::Reading;
+use crate::sink::SinkError;
+
+pub use parquet::basic::Compression;
+
+/// Parquet 文件输出配置
+#[derive(Clone, Debug)]
+pub struct ParquetSinkConfig {
+    /// 输出目录, 不存在时自动创建
+    pub dir: PathBuf,
+    /// 文件名前缀, 文件名为 `{prefix}-{序号}.parquet`
+    pub prefix: String,
+    /// 当前文件(已写入加上缓冲)达到该大小后切换新文件
+    pub max_file_size: Option<u64>,
+    /// 当前文件打开超过该时间后切换新文件, `consume` 时没有新数据也会按时切换
+    pub max_file_age: Option<Duration>,
+    pub compression: Compression,
+    /// 攒够多少条读数写一个 RecordBatch
+    pub batch_size: usize,
+}
+
+impl Default for ParquetSinkConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("data"),
+            prefix: "readings".to_string(),
+            max_file_size: Some(128 * 1024 * 1024),
+            max_file_age: None,
+            compression: Compression::SNAPPY,
+            batch_size: 1024,
+        }
+    }
+}
+
+struct ActiveFile {
+    writer: ArrowWriter<File>,
+    /// 写入中的文件名, 关闭后重命名为 `path`
+    tmp_path: PathBuf,
+    path: PathBuf,
+    opened: tokio::time::Instant,
+}
+
+impl ActiveFile {
+    fn size(&self) -> u64 {
+        let flushed: i64 = self
+            .writer
+            .flushed_row_groups()
+            .iter()
+            .map(|rg| rg.compressed_size())
+            .sum();
+        flushed as u64 + self.writer.in_progress_size() as u64
+    }
+
+    /// 写入 footer 失败时删除文件, 不留下无法读取的 `.inprogress` 文件
+    fn close(self) -> Result<PathBuf, SinkError> {
+        if let Err(e) = self.writer.close() {
+            let _ = std::fs::remove_file(&self.tmp_path);
+            return Err(e.into());
+        }
+        std::fs::rename(&self.tmp_path, &self.path)?;
+        Ok(self.path)
+    }
+}
+
+/// 把读数 `(device_id, timestamp, value)` 写入按大小或时间滚动的 Parquet 文件
+///
+/// 写入中的文件以 `.parquet.inprogress` 结尾, 只有关闭后的文件才会被重命名为
+/// `.parquet`, 所以读取方不会看到写了一半的文件.
+pub struct ParquetSink {
+    config: ParquetSinkConfig,
+    schema: SchemaRef,
+    buffer: Vec<Reading>,
+    active: Option<ActiveFile>,
+    /// 下一个文件的序号
+    seq: u64,
+    /// 已经关闭的文件
+    files: Vec<PathBuf>,
+}
+
+impl ParquetSink {
+    pub fn new(config: ParquetSinkConfig) -> Result<Self, SinkError> {
+        std::fs::create_dir_all(&config.dir)?;
+        Ok(Self {
+            buffer: Vec::with_capacity(config.batch_size),
+            config,
+            schema: Self::schema(),
+            active: None,
+            seq: 0,
+            files: Vec::new(),
+        })
+    }
+
+    pub fn schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("device_id", DataType::UInt32, false),
+            Field::new("timestamp", DataType::Timestamp(TimeUnit::Millisecond, None), false),
+            Field::new("value", DataType::Float64, false),
+        ]))
+    }
+
+    pub fn write(&mut self, reading: Reading) -> Result<(), SinkError> {
+        self.buffer.push(reading);
+        if self.buffer.len() >= self.config.batch_size.max(1) {
+            self.write_buffer()?;
+        }
+        Ok(())
+    }
+
+    /// 把缓冲的读数写入当前文件并刷出 row group
+    pub fn flush(&mut self) -> Result<(), SinkError> {
+        self.write_buffer()?;
+        if let Some(active) = self.active.as_mut() {
+            active.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// 刷出所有数据并关闭当前文件, 返回所有写完的文件.
+    /// 失败时返回 `SinkError::Incomplete`, 其中带有已经写完的文件
+    pub fn close(mut self) -> Result<Vec<PathBuf>, SinkError> {
+        if let Err(e) = self.write_buffer().and_then(|_| self.roll()) {
+            return Err(self.fail(e));
+        }
+        Ok(std::mem::take(&mut self.files))
+    }
+
+    /// 写入数据流中的所有读数, 数据流结束后关闭文件.
+    /// 写入失败时关闭当前文件, 返回 `SinkError::Incomplete`
+    pub async fn consume<S>(self, stream: S) -> Result<Vec<PathBuf>, SinkError>
+    where
+        S: Stream<Item = Reading>,
+    {
+        self.consume_results(stream.map(Ok::<_, Infallible>)).await
+    }
+
+    /// 与 `consume` 相同, 但上游出错时先写入缓冲的读数并关闭文件,
+    /// 再返回包含 `SinkError::Upstream` 的 `SinkError::Incomplete`
+    pub async fn consume_results<S, E>(mut self, stream: S) -> Result<Vec<PathBuf>, SinkError>
+    where
+        S: Stream<Item = Result<Reading, E>>,
+        E: std::fmt::Display,
+    {
+        tokio::pin!(stream);
+        loop {
+            // 没有新数据时也按 `max_file_age` 关闭当前文件
+            let deadline = self.roll_deadline();
+            let reading = tokio::select! {
+                reading = stream.next() => reading,
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(tokio::time::Instant::now)), if deadline.is_some() => {
+                    if let Err(e) = self.roll_if_old() {
+                        return Err(self.fail(e));
+                    }
+                    continue;
+                }
+            };
+            let Some(reading) = reading else {
+                break;
+            };
+            match reading {
+                Ok(reading) => {
+                    if let Err(e) = self.write(reading) {
+                        return Err(self.fail(e));
+                    }
+                }
+                Err(e) => {
+                    warn!("upstream failed, closing parquet sink: {}", e);
+                    if let Err(write) = self.write_buffer() {
+                        warn!("failed to write buffered readings: {}", write);
+                    }
+                    return Err(self.fail(SinkError::Upstream(e.to_string())));
+                }
+            }
+        }
+        self.close()
+    }
+
+    /// 出错后关闭当前文件, 丢弃没有写入的缓冲, 把已经写完的文件放进错误里
+    fn fail(mut self, error: SinkError) -> SinkError {
+        if let Err(e) = self.roll() {
+            warn!("failed to close parquet file: {}", e);
+        }
+        debug!("parquet sink failed after {} files", self.files.len());
+        SinkError::Incomplete { source: Box::new(error), files: std::mem::take(&mut self.files) }
+    }
+
+    fn write_buffer(&mut self) -> Result<(), SinkError> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let batch = self.batch()?;
+        self.buffer.clear();
+
+        // 文件在空闲期间超时, 新数据写到新文件里
+        if self.active.as_ref().is_some_and(|active| self.too_old(active)) {
+            self.roll()?;
+        }
+        if self.active.is_none() {
+            self.open()?;
+        }
+        let active = self.active.as_mut().expect("file opened");
+        active.writer.write(&batch)?;
+
+        let active = self.active.as_ref().expect("file opened");
+        let too_big = self.config.max_file_size.is_some_and(|max| active.size() >= max);
+        if too_big || self.too_old(active) {
+            self.roll()?;
+        }
+        Ok(())
+    }
+
+    /// 当前文件按 `max_file_age` 应该关闭的时间
+    fn roll_deadline(&self) -> Option<tokio::time::Instant> {
+        let max = self.config.max_file_age?;
+        self.active.as_ref().map(|active| active.opened + max)
+    }
+
+    /// 当前文件超时后写入缓冲的读数并关闭
+    fn roll_if_old(&mut self) -> Result<(), SinkError> {
+        let Some(active) = self.active.as_ref() else {
+            return Ok(());
+        };
+        if !self.too_old(active) {
+            return Ok(());
+        }
+        if !self.buffer.is_empty() {
+            let batch = self.batch()?;
+            self.buffer.clear();
+            self.active.as_mut().expect("file opened").writer.write(&batch)?;
+        }
+        self.roll()
+    }
+
+    fn too_old(&self, active: &ActiveFile) -> bool {
+        self.config.max_file_age.is_some_and(|max| active.opened.elapsed() >= max)
+    }
+
+    fn batch(&self) -> Result<RecordBatch, SinkError> {
+        let device_ids: UInt32Array = self.buffer.iter().map(|r| r.device_id).collect::<Vec<_>>().into();
+        let timestamps: TimestampMillisecondArray = self.buffer.iter().map(|r| r.timestamp).collect::<Vec<_>>().into();
+        let values: Float64Array = self.buffer.iter().map(|r| r.value).collect::<Vec<_>>().into();
+        Ok(RecordBatch::try_new(
+            self.schema.clone(),
+            vec![Arc::new(device_ids), Arc::new(timestamps), Arc::new(values)],
+        )?)
+    }
+
+    fn open(&mut self) -> Result<(), SinkError> {
+        let name = format!("{}-{:05}.parquet", self.config.prefix, self.seq);
+        self.seq += 1;
+        let path = self.config.dir.join(&name);
+        let tmp_path = self.config.dir.join(format!("{}.inprogress", name));
+        let props = WriterProperties::builder()
+            .set_compression(self.config.compression)
+            .build();
+        let writer = ArrowWriter::try_new(File::create(&tmp_path)?, self.schema.clone(), Some(props))?;
+        self.active = Some(ActiveFile {
+            writer,
+            tmp_path,
+            path,
+            opened: tokio::time::Instant::now(),
+        });
+        Ok(())
+    }
+
+    /// 关闭当前文件, 下一次写入时打开新文件
+    fn roll(&mut self) -> Result<(), SinkError> {
+        if let Some(active) = self.active.take() {
+            let path = active.close()?;
+            debug!("rolled parquet file {}", path.display());
+            self.files.push(path);
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use parquet::basic::ZstdLevel;
+    use tokio_stream::wrappers::ReceiverStream;
+    use crate::base_producer::ProducerError;
+    use super::*;
+
+    fn read_rows(files: &[PathBuf]) -> Vec<(u32, i64, f64)> {
+        let mut rows = Vec::new();
+        for path in files {
+            let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(path).unwrap())
+                .unwrap()
+                .build()
+                .unwrap();
+            for batch in reader {
+                let batch = batch.unwrap();
+                let ids = batch.column(0).as_any().downcast_ref::<UInt32Array>().unwrap();
+                let ts = batch.column(1).as_any().downcast_ref::<TimestampMillisecondArray>().unwrap();
+                let values = batch.column(2).as_any().downcast_ref::<Float64Array>().unwrap();
+                for i in 0..batch.num_rows() {
+                    rows.push((ids.value(i), ts.value(i), values.value(i)));
+                }
+            }
+        }
+        rows
+    }
+
+    #[tokio::test]
+    async fn test_parquet_sink_rolls_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ParquetSinkConfig {
+            dir: dir.path().to_path_buf(),
+            max_file_size: Some(1),
+            compression: Compression::ZSTD(ZstdLevel::default()),
+            batch_size: 10,
+            ..Default::default()
+        };
+        let readings: Vec<Reading> = (0..25).map(|i| Reading::new(i % 3, 1000 + i as i64, i as f64 / 2.0)).collect();
+        let files = ParquetSink::new(config).unwrap().consume(tokio_stream::iter(readings.clone())).await.unwrap();
+
+        // 每个 batch 写完都超过 1 字节, 所以每 10 条切换一次文件
+        assert_eq!(files.len(), 3);
+        assert!(files.iter().all(|f| f.extension().unwrap() == "parquet"));
+        let expected: Vec<(u32, i64, f64)> = readings.iter().map(|r| (r.device_id, r.timestamp, r.value)).collect();
+        assert_eq!(read_rows(&files), expected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_parquet_sink_rolls_by_time_and_closes_on_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ParquetSinkConfig {
+            dir: dir.path().to_path_buf(),
+            max_file_size: None,
+            max_file_age: Some(Duration::from_secs(60)),
+            batch_size: 1,
+            ..Default::default()
+        };
+        let stream = tokio_stream::iter(vec![
+            Ok(Reading::new(1, 0, 1.0)),
+            Ok(Reading::new(1, 1, 2.0)),
+            Err(ProducerError::Timeout),
+            Ok(Reading::new(1, 2, 3.0)),
+        ])
+        .throttle(Duration::from_secs(70));
+
+        let result = ParquetSink::new(config).unwrap().consume_results(stream).await;
+        let Err(SinkError::Incomplete { source, files }) = result else {
+            panic!("expected incomplete output");
+        };
+        assert!(matches!(*source, SinkError::Upstream(_)));
+
+        // 第二条读数到达时文件已打开超过 60s, 所以两条读数分别在两个文件里
+        let mut on_disk: Vec<PathBuf> = std::fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().path()).collect();
+        on_disk.sort();
+        assert_eq!(files, on_disk);
+        assert_eq!(read_rows(&files), vec![(1, 0, 1.0), (1, 1, 2.0)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_parquet_sink_rolls_idle_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ParquetSinkConfig {
+            dir: dir.path().to_path_buf(),
+            max_file_size: None,
+            max_file_age: Some(Duration::from_secs(60)),
+            batch_size: 2,
+            ..Default::default()
+        };
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        let sink = tokio::spawn(ParquetSink::new(config).unwrap().consume(ReceiverStream::new(rx)));
+        for i in 0..3 {
+            tx.send(Reading::new(1, i, i as f64)).await.unwrap();
+        }
+
+        // 没有新数据, 文件到期后仍然被关闭, 缓冲中的第三条读数也写进去
+        tokio::time::sleep(Duration::from_secs(61)).await;
+        let path = dir.path().join("readings-00000.parquet");
+        assert!(path.exists());
+        assert_eq!(read_rows(std::slice::from_ref(&path)), vec![(1, 0, 0.0), (1, 1, 1.0), (1, 2, 2.0)]);
+
+        drop(tx);
+        assert_eq!(sink.await.unwrap().unwrap(), vec![path]);
+    }
+
+    #[tokio::test]
+    async fn test_parquet_sink_write_error_returns_completed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        // 第二个文件的位置被目录占用, 无法创建
+        std::fs::create_dir(dir.path().join("readings-00001.parquet.inprogress")).unwrap();
+        let config = ParquetSinkConfig {
+            dir: dir.path().to_path_buf(),
+            max_file_size: Some(1),
+            batch_size: 1,
+            ..Default::default()
+        };
+        let readings = (0..3).map(|i| Reading::new(1, i, i as f64));
+        let result = ParquetSink::new(config).unwrap().consume(tokio_stream::iter(readings)).await;
+        let Err(SinkError::Incomplete { source, files }) = result else {
+            panic!("expected incomplete output");
+        };
+        assert!(matches!(*source, SinkError::Io(_)));
+        assert_eq!(files, vec![dir.path().join("readings-00000.parquet")]);
+        assert_eq!(read_rows(&files), vec![(1, 0, 0.0)]);
+    }
+}
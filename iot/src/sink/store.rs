@@ -0,0 +1,361 @@
+use std::convert::Infallible;
+use std::time::Duration;
+use futures::{stream, Stream, StreamExt};
+use log::{debug, warn};
+use thrid_lib::grpc::pb::Msg;
+use thrid_lib::grpc::pb::store_service_client::StoreServiceClient;
+use tonic::transport::{Channel, Endpoint};
+use tonic::Code;
+use crate::codec::{decode_readings, encode_readings, CodecError};
+use crate::reading::Reading;
+use crate::sink::SinkError;
+
+/// gRPC `StoreService` 输出配置
+#[derive(Clone, Debug)]
+pub struct StoreSinkConfig {
+    /// 每批最多包含的读数
+    pub batch_size: usize,
+    /// 批次没有攒满时最多等待的时间
+    pub linger: Duration,
+    /// 一批内同时进行中的 `send` 请求上限, 批次之间不重叠
+    pub max_in_flight: usize,
+    /// 单条消息暂时失败(如 `Unavailable`)后的最大重试次数, 其他错误不重试
+    pub max_retries: usize,
+    /// 第一次重试前的等待时间, 之后每次翻倍
+    pub retry_backoff: Duration,
+    /// 第一条消息的 id, 之后依次加一, 见 `StoreSinkConfig::new`
+    pub first_id: i64,
+    pub encoding: MsgEncoding,
+}
+
+impl StoreSinkConfig {
+    /// 消息 id 从 `first_id` 开始连续分配, 存储按 id 覆盖已有的消息.
+    ///
+    /// 写入同一个存储的多个 sink 需要使用互不重叠的 id 区间; 进程重启后应从上次的
+    /// `StoreSinkStats::next_id` 继续, 否则会覆盖之前写入的消息.
+    pub fn new(first_id: i64) -> Self {
+        Self {
+            batch_size: 64,
+            linger: Duration::from_millis(100),
+            max_in_flight: 8,
+            max_retries: 3,
+            retry_backoff: Duration::from_millis(50),
+            first_id,
+            encoding: MsgEncoding::default(),
+        }
+    }
+}
+
+/// `Msg.data` 的编码方式
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MsgEncoding {
+    /// 每条读数一个 `Msg`, `data` 为 `Reading::encode`
+    #[default]
+    Reading,
+    /// 每批读数一个 `Msg`, `data` 为 `codec::encode_readings`, `timestamp` 为第一条读数的时间戳
+    Block,
+}
+
+impl MsgEncoding {
+    /// 还原从存储中读出的 `Msg` 中的读数
+    pub fn decode(&self, msg: &Msg) -> Result<Vec<Reading>, CodecError> {
+        match self {
+            MsgEncoding::Reading => {
+                let reading = Reading::decode(&msg.data, msg.timestamp.unwrap_or_default());
+                reading.map(|reading| vec![reading]).ok_or(CodecError::Corrupt)
+            }
+            MsgEncoding::Block => decode_readings(&msg.data),
+        }
+    }
+}
+
+/// 发送统计
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StoreSinkStats {
+    pub batches: u64,
+    /// 发送成功的读数个数
+    pub sent: u64,
+    pub retries: u64,
+    /// 下一条消息的 id, 重启后作为新的 `first_id` 不会覆盖已经写入的消息
+    pub next_id: i64,
+}
+
+/// 把读数作为 `Msg` 通过 `StoreServiceClient::send` 写入消息存储
+///
+/// 读数按 `encoding` 编码为 `Msg`, `id` 从 `first_id` 开始递增. 读数按批发送,
+/// 一批全部完成后才发送下一批. `MsgEncoding::Reading` 时批内的请求并发执行,
+/// 同时进行中的请求不超过 `max_in_flight`; `MsgEncoding::Block` 时整批压缩为一个 `Msg`.
+pub struct StoreSink {
+    client: StoreServiceClient<Channel>,
+    config: StoreSinkConfig,
+    stats: StoreSinkStats,
+}
+
+impl StoreSink {
+    pub fn new(client: StoreServiceClient<Channel>, config: StoreSinkConfig) -> Self {
+        Self {
+            client,
+            stats: StoreSinkStats { next_id: config.first_id, ..Default::default() },
+            config,
+        }
+    }
+
+    pub async fn connect(dst: impl Into<String>, config: StoreSinkConfig) -> Result<Self, SinkError> {
+        let channel = Endpoint::from_shared(dst.into())?.connect().await?;
+        Ok(Self::new(StoreServiceClient::new(channel), config))
+    }
+
+    pub fn stats(&self) -> &StoreSinkStats {
+        &self.stats
+    }
+
+    /// 发送一批读数, 任一消息重试用完后仍失败则返回错误
+    pub async fn send_batch(&mut self, batch: Vec<Reading>) -> Result<(), SinkError> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        // 每个消息和其中的读数个数
+        let msgs: Vec<(Msg, u64)> = match self.config.encoding {
+            MsgEncoding::Reading => batch.iter().map(|reading| (self.next_msg(reading.encode(), reading), 1)).collect(),
+            MsgEncoding::Block => vec![(self.next_msg(encode_readings(&batch), &batch[0]), batch.len() as u64)],
+        };
+        let count = batch.len();
+        let client = &self.client;
+        let config = &self.config;
+        let mut results = stream::iter(msgs)
+            .map(|(msg, readings)| async move { (readings, send_with_retry(client.clone(), msg, config).await) })
+            .buffer_unordered(config.max_in_flight.max(1));
+
+        let mut failed = None;
+        while let Some((readings, (retries, result))) = results.next().await {
+            self.stats.retries += retries;
+            match result {
+                Ok(()) => self.stats.sent += readings,
+                Err(status) => {
+                    failed.get_or_insert(status);
+                }
+            }
+        }
+        self.stats.batches += 1;
+        match failed {
+            Some(status) => Err(status.into()),
+            None => {
+                debug!("sent batch of {} readings to store", count);
+                Ok(())
+            }
+        }
+    }
+
+    /// 发送数据流中的所有读数, 返回发送统计
+    pub async fn consume<S>(self, stream: S) -> Result<StoreSinkStats, SinkError>
+    where
+        S: Stream<Item = Reading>,
+    {
+        self.consume_results(stream.map(Ok::<_, Infallible>)).await
+    }
+
+    /// 与 `consume` 相同, 但上游出错时先发送出错前的读数再返回错误
+    pub async fn consume_results<S, E>(mut self, stream: S) -> Result<StoreSinkStats, SinkError>
+    where
+        S: Stream<Item = Result<Reading, E>>,
+        E: std::fmt::Display,
+    {
+        let batches = tokio_stream::StreamExt::chunks_timeout(
+            stream,
+            self.config.batch_size.max(1),
+            self.config.linger,
+        );
+        tokio::pin!(batches);
+        while let Some(batch) = batches.next().await {
+            let mut readings = Vec::with_capacity(batch.len());
+            let mut upstream = None;
+            for item in batch {
+                match item {
+                    Ok(reading) => readings.push(reading),
+                    Err(e) => {
+                        upstream = Some(e.to_string());
+                        break;
+                    }
+                }
+            }
+            self.send_batch(readings).await?;
+            if let Some(e) = upstream {
+                warn!("upstream failed, stop sending to store: {}", e);
+                return Err(SinkError::Upstream(e));
+            }
+        }
+        Ok(self.stats)
+    }
+
+    /// 以 `first` 的时间戳作为消息的时间戳
+    fn next_msg(&mut self, data: Vec<u8>, first: &Reading) -> Msg {
+        let id = self.stats.next_id;
+        self.stats.next_id += 1;
+        Msg {
+            id,
+            data,
+            timestamp: Some(first.timestamp),
+        }
+    }
+}
+
+/// 服务暂时不可用一类的错误才重试, 参数错误或权限错误重试也不会成功
+fn is_retryable(status: &tonic::Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unavailable | Code::DeadlineExceeded | Code::ResourceExhausted | Code::Aborted | Code::Internal
+    )
+}
+
+/// 返回重试次数和最终结果
+async fn send_with_retry(
+    mut client: StoreServiceClient<Channel>,
+    msg: Msg,
+    config: &StoreSinkConfig,
+) -> (u64, Result<(), tonic::Status>) {
+    let mut backoff = config.retry_backoff;
+    let mut retries = 0;
+    loop {
+        match client.send(msg.clone()).await {
+            Ok(_) => return (retries, Ok(())),
+            Err(status) if is_retryable(&status) && (retries as usize) < config.max_retries => {
+                debug!("send msg {} failed, retry in {:?}: {}", msg.id, backoff, status);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                retries += 1;
+            }
+            Err(status) => {
+                warn!("send msg {} failed after {} retries: {}", msg.id, retries, status);
+                return (retries, Err(status));
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use thrid_lib::grpc::pb::MsgId;
+    use thrid_lib::grpc::pb::store_service_server::StoreServiceServer;
+    use thrid_lib::grpc::server::KvStoreService;
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::Server;
+    use tonic::{Code, Status};
+    use super::*;
+
+    /// 启动一个前 `failures` 个请求返回 `Unavailable` 的存储服务
+    async fn serve(failures: usize) -> String {
+        serve_with(failures, Code::Unavailable).await.0
+    }
+
+    /// 前 `failures` 个请求返回 `code`, 同时返回收到的请求个数
+    #[allow(clippy::result_large_err)]
+    async fn serve_with(failures: usize, code: Code) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let seen = Arc::new(AtomicUsize::new(0));
+        let requests = seen.clone();
+        let service = StoreServiceServer::with_interceptor(KvStoreService::default(), move |req| {
+            if seen.fetch_add(1, Ordering::SeqCst) < failures {
+                Err(Status::new(code, "rejected"))
+            } else {
+                Ok(req)
+            }
+        });
+        tokio::spawn(
+            Server::builder()
+                .add_service(service)
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        (format!("http://{}", addr), requests)
+    }
+
+    fn config() -> StoreSinkConfig {
+        StoreSinkConfig {
+            batch_size: 4,
+            max_in_flight: 1,
+            retry_backoff: Duration::from_millis(1),
+            ..StoreSinkConfig::new(100)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_store_sink_retries_and_stores() {
+        let dst = serve(2).await;
+        let readings: Vec<Reading> = (0..10).map(|i| Reading::new(7, 1000 + i, i as f64 * 1.5)).collect();
+        let sink = StoreSink::connect(dst.clone(), config()).await.unwrap();
+        let stats = sink.consume(tokio_stream::iter(readings.clone())).await.unwrap();
+        assert_eq!(stats, StoreSinkStats { batches: 3, sent: 10, retries: 2, next_id: 110 });
+
+        let mut client = StoreServiceClient::connect(dst).await.unwrap();
+        for (i, reading) in readings.iter().enumerate() {
+            let msg = client.get(MsgId { id: 100 + i as i64 }).await.unwrap().into_inner();
+            assert_eq!(msg.timestamp, Some(reading.timestamp));
+            assert_eq!(Reading::decode(&msg.data, reading.timestamp), Some(*reading));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_store_sink_resumes_ids_after_restart() {
+        let dst = serve(0).await;
+        let first: Vec<Reading> = (0..3).map(|i| Reading::new(7, 1000 + i, 1.0)).collect();
+        let second: Vec<Reading> = (0..3).map(|i| Reading::new(7, 2000 + i, 2.0)).collect();
+        let sink = StoreSink::connect(dst.clone(), config()).await.unwrap();
+        let stats = sink.consume(tokio_stream::iter(first.clone())).await.unwrap();
+        assert_eq!(stats.next_id, 103);
+
+        let restarted = StoreSinkConfig { first_id: stats.next_id, ..config() };
+        let sink = StoreSink::connect(dst.clone(), restarted).await.unwrap();
+        sink.consume(tokio_stream::iter(second.clone())).await.unwrap();
+
+        let mut client = StoreServiceClient::connect(dst).await.unwrap();
+        for (id, reading) in (100..).zip(first.iter().chain(&second)) {
+            let msg = client.get(MsgId { id }).await.unwrap().into_inner();
+            assert_eq!(Reading::decode(&msg.data, reading.timestamp), Some(*reading));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_store_sink_block_encoding() {
+        let dst = serve(0).await;
+        let readings: Vec<Reading> = (0..10).map(|i| Reading::new(7, 1000 + i * 100, 20.0 + (i / 4) as f64)).collect();
+        let config = StoreSinkConfig { encoding: MsgEncoding::Block, ..config() };
+        let sink = StoreSink::connect(dst.clone(), config).await.unwrap();
+        let stats = sink.consume(tokio_stream::iter(readings.clone())).await.unwrap();
+        assert_eq!(stats, StoreSinkStats { batches: 3, sent: 10, retries: 0, next_id: 103 });
+
+        let mut client = StoreServiceClient::connect(dst).await.unwrap();
+        let mut stored = Vec::new();
+        for id in 100..103 {
+            let msg = client.get(MsgId { id }).await.unwrap().into_inner();
+            stored.extend(MsgEncoding::Block.decode(&msg).unwrap());
+        }
+        assert_eq!(stored, readings);
+    }
+
+    #[tokio::test]
+    async fn test_store_sink_gives_up() {
+        let dst = serve(usize::MAX).await;
+        let sink = StoreSink::connect(dst, config()).await.unwrap();
+        let result = sink.consume(tokio_stream::iter(vec![Reading::new(1, 0, 1.0)])).await;
+        match result {
+            Err(SinkError::Rpc(status)) => assert_eq!(status.code(), Code::Unavailable),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_store_sink_does_not_retry_invalid_argument() {
+        let (dst, requests) = serve_with(usize::MAX, Code::InvalidArgument).await;
+        let sink = StoreSink::connect(dst, config()).await.unwrap();
+        let result = sink.consume(tokio_stream::iter(vec![Reading::new(1, 0, 1.0)])).await;
+        match result {
+            Err(SinkError::Rpc(status)) => assert_eq!(status.code(), Code::InvalidArgument),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+}
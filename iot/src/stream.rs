@@ -1,9 +1,11 @@
 use std::future::Future;
+use std::path::Path;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use std::time::Duration;
 use pin_project::pin_project;
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use tokio_stream::Stream;
 
 enum State {
@@ -11,27 +13,142 @@ enum State {
     Sleeping,
 }
 
+/// 数据到达的节奏
+#[derive(Clone, Debug, PartialEq)]
+pub enum Pacing {
+    /// 在 `[min, max]` 内均匀随机, 默认 0~1000ms
+    Uniform { min: Duration, max: Duration },
+    /// 固定间隔
+    Fixed(Duration),
+    /// 泊松到达, `rate` 为每秒平均到达次数
+    Poisson { rate: f64 },
+    /// 令牌桶, 以 `rate` 每秒补充令牌, 最多积累 `burst` 个, 有令牌时数据立即到达
+    TokenBucket { rate: f64, burst: u32 },
+    /// 按记录的到达间隔回放, 回放完毕后数据流结束
+    Trace(Vec<Duration>),
+}
+
+impl Default for Pacing {
+    fn default() -> Self {
+        Pacing::Uniform { min: Duration::ZERO, max: Duration::from_millis(1000) }
+    }
+}
+
+impl Pacing {
+    /// 从文件读取记录的到达时间戳(毫秒, 每行一个, 忽略空行和 `#` 开头的注释),
+    /// 回放相邻时间戳之间的间隔
+    pub fn from_trace_file(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let mut timestamps = Vec::new();
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let ts: u64 = line.parse().map_err(|e| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid timestamp `{}`: {}", line, e))
+            })?;
+            timestamps.push(ts);
+        }
+        let delays = timestamps
+            .windows(2)
+            .map(|pair| Duration::from_millis(pair[1].saturating_sub(pair[0])))
+            .collect();
+        Ok(Pacing::Trace(delays))
+    }
+}
+
+/// 按 `Pacing` 计算下一次数据到达前的等待时间
+struct Pacer {
+    pacing: Pacing,
+    rng: StdRng,
+    /// 令牌桶中剩余的令牌
+    tokens: f64,
+    last_refill: Option<tokio::time::Instant>,
+    /// 回放到的位置
+    trace_pos: usize,
+}
+
+impl Pacer {
+    fn new(pacing: Pacing, rng: StdRng) -> Self {
+        let tokens = match pacing {
+            Pacing::TokenBucket { burst, .. } => burst as f64,
+            _ => 0.0,
+        };
+        Self { pacing, rng, tokens, last_refill: None, trace_pos: 0 }
+    }
+
+    /// 返回 `None` 表示不会再有数据
+    fn next_delay(&mut self) -> Option<Duration> {
+        match &self.pacing {
+            Pacing::Uniform { min, max } => {
+                if max > min {
+                    Some(self.rng.gen_range(*min..=*max))
+                } else {
+                    Some(*min)
+                }
+            }
+            Pacing::Fixed(interval) => Some(*interval),
+            Pacing::Poisson { rate } => {
+                // 泊松过程的到达间隔服从指数分布
+                let u: f64 = self.rng.gen_range(f64::EPSILON..1.0);
+                Some(Duration::from_secs_f64(-u.ln() / rate.max(f64::EPSILON)))
+            }
+            Pacing::TokenBucket { rate, burst } => {
+                let (rate, burst) = (rate.max(f64::EPSILON), *burst as f64);
+                let now = tokio::time::Instant::now();
+                if let Some(last) = self.last_refill {
+                    let refill = now.duration_since(last).as_secs_f64() * rate;
+                    self.tokens = (self.tokens + refill).min(burst);
+                }
+                self.last_refill = Some(now);
+                self.tokens -= 1.0;
+                if self.tokens >= 0.0 {
+                    Some(Duration::ZERO)
+                } else {
+                    // 等待补充一个令牌, 这段时间内补充的令牌已经预先扣除
+                    Some(Duration::from_secs_f64(-self.tokens / rate))
+                }
+            }
+            Pacing::Trace(delays) => {
+                let delay = delays.get(self.trace_pos).copied();
+                self.trace_pos += 1;
+                delay
+            }
+        }
+    }
+}
+
 #[pin_project]
 pub struct DataAvailable {
     #[pin]
     sleep_future: Option<Pin<Box<tokio::time::Sleep>>>,
-    state: State
+    state: State,
+    pacer: Pacer,
+}
+
+impl Default for DataAvailable {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl DataAvailable {
     pub fn new() -> Self {
+        Self::with_pacing(Pacing::default())
+    }
+
+    pub fn with_pacing(pacing: Pacing) -> Self {
         Self {
             state: State::Init,
             sleep_future: None,
+            pacer: Pacer::new(pacing, StdRng::from_entropy()),
         }
     }
 
-    fn random_delay() -> Duration {
-        #[allow(unused_mut)]
-        #[allow(unused_variables)]
-        let mut rng = rand::thread_rng();
-        let millis = rng.gen_range(0..=1000);
-        Duration::from_millis(millis)
+    /// 使用固定种子, 配合 `tokio::time::pause` 可以得到可重复的到达时间
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.pacer.rng = StdRng::seed_from_u64(seed);
+        self
     }
 }
 
@@ -44,7 +161,9 @@ impl Stream for DataAvailable {
         loop {
             match *this.state {
                 State::Init => {
-                    let delay = Self::random_delay();
+                    let Some(delay) = this.pacer.next_delay() else {
+                        break Poll::Ready(None);
+                    };
                     if let Some(ref mut sleep_future) = *this.sleep_future {
                         sleep_future
                             .as_mut()
@@ -76,25 +195,67 @@ impl Stream for DataAvailable {
 }
 
 
+#[cfg(test)]
 mod test {
+    use std::io::Write;
     use log::info;
     use tokio_stream::StreamExt;
     use super::*;
 
-    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    /// 收集前 `n` 次数据到达的时间(毫秒)
+    async fn arrivals(data_available: DataAvailable, n: usize) -> Vec<u128> {
+        let start = tokio::time::Instant::now();
+        data_available
+            .take(n)
+            .map(|_| start.elapsed().as_millis())
+            .collect()
+            .await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pacing() {
+        let fixed = DataAvailable::with_pacing(Pacing::Fixed(Duration::from_millis(100)));
+        assert_eq!(arrivals(fixed, 3).await, vec![100, 200, 300]);
+
+        // 桶中有 2 个令牌, 之后每 100ms 补充一个
+        let bucket = DataAvailable::with_pacing(Pacing::TokenBucket { rate: 10.0, burst: 2 });
+        assert_eq!(arrivals(bucket, 4).await, vec![0, 0, 100, 200]);
+
+        let poisson = || DataAvailable::with_pacing(Pacing::Poisson { rate: 5.0 }).with_seed(42);
+        let first = arrivals(poisson(), 50).await;
+        assert_eq!(first, arrivals(poisson(), 50).await);
+        // 平均间隔约 200ms
+        let mean = *first.last().unwrap() as f64 / 50.0;
+        assert!((100.0..300.0).contains(&mean), "mean interval {}", mean);
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "# recorded arrivals\n1000\n1050\n\n1300").unwrap();
+        let trace = DataAvailable::with_pacing(Pacing::from_trace_file(file.path()).unwrap());
+        assert_eq!(arrivals(trace, 10).await, vec![50, 300]);
+    }
+
+    #[tokio::test(start_paused = true)]
     async fn test_data_available() {
-        /// stream example
-        if let None = std::env::var_os("RUST_LOG") {
-            std::env::set_var("RUST_LOG", "info")
-        }
-        env_logger::init();
+        let _ = env_logger::try_init();
         // Create the DataAvailable stream
-        let mut data_available = DataAvailable::new();
-        // Consume the stream and print the result
+        let data_available = DataAvailable::new().with_seed(1);
+        // Consume the first items of the stream, time is virtual so this returns immediately
         let mut i = 0;
-        while let Some(_data) = data_available.next().await {
-            info!("Data available: {}", i);
-            i += 1;
+        let times = arrivals(data_available, 20)
+            .await
+            .into_iter()
+            .inspect(|_| {
+                info!("Data available: {}", i);
+                i += 1;
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(times.len(), 20);
+        // 默认的间隔在 0~1000ms 之间均匀分布
+        let mut previous = 0;
+        for t in &times {
+            assert!(*t - previous <= 1000);
+            previous = *t;
         }
+        assert_eq!(times, arrivals(DataAvailable::new().with_seed(1), 20).await);
     }
 }
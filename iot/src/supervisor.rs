@@ -0,0 +1,320 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::{info, warn};
+use num_traits::ToPrimitive;
+use rand::distributions::uniform::SampleUniform;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use crate::collector::{CollectionResult, CollectorBuilder, StopReason};
+use crate::deterministic::Determinism;
+use crate::metrics::{MetricsRegistry, ProducerMetrics};
+use crate::pipeline::{Calibration, Pipeline};
+use crate::producer::{thread_waker, ChannelProducer, Producer, RandProducer, TCPProducer, ToBytes, UdpProducer};
+use crate::protocol::Backoff;
+use crate::reading::Reading;
+use crate::registry::{DeviceEntry, DeviceRegistry, RestartPolicy, Transport, ValueType};
+use crate::shutdown::Shutdown;
+
+/// 一个设备的采集结果
+#[derive(Debug)]
+pub struct DeviceReport {
+    pub device_id: u32,
+    /// 最后一次采集的结果
+    pub result: CollectionResult<Reading>,
+    /// 失败后重启的次数
+    pub restarts: u32,
+}
+
+impl DeviceReport {
+    /// 采集在完成条件满足前结束, 并且重启次数已用完
+    pub fn failed(&self) -> bool {
+        self.result.stop_reason == StopReason::StreamEnded
+    }
+}
+
+/// 为注册表中的每个设备启动一个生产者和收集器
+///
+/// 生产者在阻塞线程中运行, 读数经过校准后按 `sampling_rate` 交给收集器.
+/// 数据流在完成条件满足前结束(设备断开, 生产者启动失败或崩溃)视为失败,
+/// 按设备的 `RestartPolicy` 重启.
+pub struct Supervisor {
+    registry: DeviceRegistry,
+    shutdown: Shutdown,
+    determinism: Determinism,
+    metrics: Option<MetricsRegistry>,
+}
+
+impl Supervisor {
+    pub fn new(registry: DeviceRegistry) -> Self {
+        Self {
+            registry,
+            shutdown: Shutdown::new(),
+            determinism: Determinism::default(),
+            metrics: None,
+        }
+    }
+
+    /// 停止信号触发后所有设备结束采集, 不再重启
+    pub fn with_shutdown(mut self, shutdown: &Shutdown) -> Self {
+        self.shutdown = shutdown.clone();
+        self
+    }
+
+    /// 固定种子时第 `id` 号设备使用种子 `seed + id`
+    pub fn with_determinism(mut self, determinism: Determinism) -> Self {
+        self.determinism = determinism;
+        self
+    }
+
+    /// 每个设备的收集器和生产者分别以设备 id 作为 `collector` 和 `producer` 标签记录指标
+    pub fn with_metrics(mut self, registry: &MetricsRegistry) -> Self {
+        self.metrics = Some(registry.clone());
+        self
+    }
+
+    /// 等待所有设备完成, 按注册表顺序返回结果
+    pub async fn run(self) -> Vec<DeviceReport> {
+        let tasks: Vec<_> = self
+            .registry
+            .devices
+            .iter()
+            .map(|device| {
+                let policy = self.registry.restart_policy(device);
+                let determinism = Determinism {
+                    seed: self.determinism.seed.map(|seed| seed.wrapping_add(device.id as u64)),
+                    ..self.determinism
+                };
+                tokio::spawn(supervise(
+                    device.clone(),
+                    policy,
+                    determinism,
+                    self.shutdown.clone(),
+                    self.metrics.clone(),
+                ))
+            })
+            .collect();
+
+        let mut reports = Vec::with_capacity(tasks.len());
+        for task in tasks {
+            match task.await {
+                Ok(report) => reports.push(report),
+                Err(e) => warn!("device supervisor task failed: {}", e),
+            }
+        }
+        reports
+    }
+}
+
+async fn supervise(
+    device: DeviceEntry,
+    policy: RestartPolicy,
+    determinism: Determinism,
+    shutdown: Shutdown,
+    metrics: Option<MetricsRegistry>,
+) -> DeviceReport {
+    let mut restarts = 0;
+    let mut backoff = match policy {
+        RestartPolicy::OnFailure { backoff_ms, .. } => {
+            let initial = Duration::from_millis(backoff_ms);
+            Some(Backoff::new(initial, initial * 32))
+        }
+        RestartPolicy::Never => None,
+    };
+    loop {
+        let result = collect_once(&device, determinism, &shutdown, metrics.as_ref()).await;
+        let exhausted = match policy {
+            RestartPolicy::Never => true,
+            RestartPolicy::OnFailure { max_restarts, .. } => restarts >= max_restarts,
+        };
+        if result.stop_reason != StopReason::StreamEnded || exhausted || shutdown.is_triggered() {
+            info!("device {} finished: {:?} after {} restarts", device.id, result.stop_reason, restarts);
+            return DeviceReport { device_id: device.id, result, restarts };
+        }
+
+        let delay = backoff.as_mut().map_or(Duration::ZERO, Backoff::next_delay);
+        warn!("device {} stopped after {} readings, restarting in {:?}", device.id, result.count, delay);
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown.triggered() => {}
+        }
+        restarts += 1;
+    }
+}
+
+async fn collect_once(
+    device: &DeviceEntry,
+    determinism: Determinism,
+    shutdown: &Shutdown,
+    metrics: Option<&MetricsRegistry>,
+) -> CollectionResult<Reading> {
+    let (tx, rx) = mpsc::channel(1);
+    let pump = {
+        let device = device.clone();
+        let shutdown = shutdown.clone();
+        let metrics = metrics.map(|registry| ProducerMetrics::new(registry, &device.id.to_string()));
+        tokio::task::spawn_blocking(move || {
+            macro_rules! start {
+                ($($variant:ident => $t:ty),*) => {
+                    match device.value_type {
+                        $(ValueType::$variant => start::<$t>(&device, determinism, &shutdown, tx, metrics),)*
+                    }
+                };
+            }
+            start!(U8 => u8, U16 => u16, U32 => u32, U64 => u64, I16 => i16, I32 => i32, I64 => i64, F32 => f32, F64 => f64)
+        })
+    };
+
+    let mut stream = Pipeline::new(ReceiverStream::new(rx));
+    if let Some(interval) = device.sampling_interval() {
+        stream = stream.rate_limit("sampling", interval);
+    }
+    let mut builder = CollectorBuilder::new().stop_when(device.ready.build()).shutdown(shutdown);
+    if let Some(registry) = metrics {
+        builder = builder.metrics(registry, &device.id.to_string());
+    }
+    let result = builder.collect(stream).await;
+
+    // 收集器已经丢弃了接收端, 生产者线程在下一次发送时退出
+    match pump.await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => warn!("device {} failed to start: {}", device.id, e),
+        Err(e) => warn!("device {} producer panicked: {}", device.id, e),
+    }
+    result
+}
+
+/// 按设备的连接方式创建生产者并把读数发送到 `tx`, 在阻塞线程中运行
+fn start<T>(
+    device: &DeviceEntry,
+    determinism: Determinism,
+    shutdown: &Shutdown,
+    tx: mpsc::Sender<Reading>,
+    metrics: Option<ProducerMetrics>,
+) -> std::io::Result<()>
+where
+    T: ToBytes + ToPrimitive + PartialOrd + From<u8> + SampleUniform + Clone + Send + 'static,
+{
+    match &device.transport {
+        Transport::Channel => {
+            let producer = ChannelProducer::<T>::with_options(determinism, Some(shutdown));
+            pump(producer, device, shutdown, tx, metrics);
+        }
+        Transport::Rand => pump(RandProducer::<T>::with_determinism(determinism), device, shutdown, tx, metrics),
+        Transport::Tcp { addr, simulated: true } => {
+            let producer = TCPProducer::<T>::with_options(addr.clone(), determinism, Some(shutdown));
+            pump(producer, device, shutdown, tx, metrics);
+        }
+        Transport::Tcp { addr, simulated: false } => {
+            let producer = TCPProducer::<T>::listen(addr.clone())?.with_determinism(determinism);
+            pump(producer, device, shutdown, tx, metrics);
+        }
+        Transport::Udp { addr, idle_timeout_ms } => {
+            let producer = UdpProducer::<T>::bind(addr)?.with_idle_timeout(Duration::from_millis(*idle_timeout_ms))?;
+            pump(producer, device, shutdown, tx, metrics);
+        }
+    }
+    Ok(())
+}
+
+/// 指标中的通道深度为发送后通道中等待收集器读取的读数个数
+fn pump<T, P>(
+    mut producer: P,
+    device: &DeviceEntry,
+    shutdown: &Shutdown,
+    tx: mpsc::Sender<Reading>,
+    mut metrics: Option<ProducerMetrics>,
+) where
+    P: Producer<T>,
+    T: ToPrimitive,
+{
+    let calibration: Calibration = device.calibration;
+    producer.store_waker(&thread_waker());
+    while !shutdown.is_triggered() && !tx.is_closed() {
+        if !producer.data_available() {
+            // 等待生产者唤醒, 超时后重新检查停止信号
+            std::thread::park_timeout(Duration::from_millis(100));
+            continue;
+        }
+        if let Some(metrics) = metrics.as_mut() {
+            metrics.poll.on_blocking_poll();
+        }
+        let Some(value) = producer.produce() else {
+            return;
+        };
+        let Some(value) = value.to_f64() else {
+            continue;
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as i64);
+        if tx.blocking_send(Reading::new(device.id, timestamp, calibration.apply(value))).is_err() {
+            break;
+        }
+        if let Some(metrics) = metrics.as_ref() {
+            metrics.poll.on_reading();
+            metrics.channel_depth.set((tx.max_capacity() - tx.capacity()) as f64);
+        }
+    }
+    producer.stop();
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::registry::ReadySpec;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_supervisor_collects_and_restarts() {
+        let udp_addr = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let registry = DeviceRegistry {
+            restart: RestartPolicy::OnFailure { max_restarts: 2, backoff_ms: 1 },
+            devices: vec![
+                DeviceEntry {
+                    calibration: Calibration::linear(10.0, 0.5),
+                    ready: ReadySpec::Count { count: 5 },
+                    ..DeviceEntry::new(1, Transport::Rand)
+                },
+                DeviceEntry {
+                    value_type: ValueType::U8,
+                    ready: ReadySpec::Count { count: 3 },
+                    ..DeviceEntry::new(2, Transport::Channel)
+                },
+                // 没有设备推送, 空闲超时后数据流结束, 按策略重启两次后放弃
+                DeviceEntry {
+                    restart: Some(RestartPolicy::OnFailure { max_restarts: 2, backoff_ms: 1 }),
+                    ..DeviceEntry::new(3, Transport::Udp { addr: udp_addr.to_string(), idle_timeout_ms: 50 })
+                },
+            ],
+        };
+        let shutdown = Shutdown::new();
+        let metrics = MetricsRegistry::new();
+        let supervisor = Supervisor::new(registry)
+            .with_shutdown(&shutdown)
+            .with_determinism(Determinism::seeded(42))
+            .with_metrics(&metrics);
+        let reports = tokio::time::timeout(Duration::from_secs(60), supervisor.run()).await.unwrap();
+
+        assert_eq!(reports.len(), 3);
+        let rand = &reports[0];
+        assert_eq!((rand.device_id, rand.result.stop_reason, rand.restarts), (1, StopReason::ConditionMet, 0));
+        // RandProducer 的读数在 [1, 10) 之间, 校准后为 10x + 0.5
+        assert!(rand.result.items.iter().all(|r| r.device_id == 1 && (r.value - 0.5) % 10.0 == 0.0));
+        assert_eq!(reports[1].result.items.len(), 3);
+        assert!(!reports[1].failed());
+
+        let udp = &reports[2];
+        assert!(udp.failed());
+        assert_eq!(udp.restarts, 2);
+        let text = metrics.render();
+        assert!(text.contains("iot_collector_readings_total{collector=\"1\"} 5\n"));
+        assert!(text.contains("iot_producer_channel_depth{producer=\"2\"}"));
+        let produced = text
+            .lines()
+            .find_map(|line| line.strip_prefix("iot_producer_readings_total{producer=\"1\"} "))
+            .and_then(|n| n.parse::<u64>().ok())
+            .unwrap();
+        assert!(produced >= 5);
+        shutdown.trigger();
+        tokio::task::spawn_blocking(move || shutdown.join_threads()).await.unwrap();
+    }
+}
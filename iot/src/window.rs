@@ -0,0 +1,507 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use pin_project::pin_project;
+use tokio_stream::Stream;
+use crate::aggregate::{AggregateValue, Aggregates, Aggregation, Aggregator};
+
+/// 窗口类型, 时间单位均为毫秒
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WindowKind {
+    /// 固定大小, 互不重叠
+    Tumbling { size: i64 },
+    /// 固定大小, 每隔 `slide` 开始一个新窗口
+    ///
+    /// `slide` 大于 `size` 时窗口之间有间隙, 落在间隙中的数据不属于任何窗口
+    Sliding { size: i64, slide: i64 },
+    /// 数据间隔不超过 `gap` 的连续数据属于同一个会话
+    Session { gap: i64 },
+}
+
+/// 窗口输出的聚合记录
+#[derive(Clone, Debug, PartialEq)]
+pub struct WindowRecord {
+    /// 窗口开始时间(包含)
+    pub start: i64,
+    /// 窗口结束时间(不包含)
+    pub end: i64,
+    pub count: u64,
+    pub aggregates: Aggregates,
+    /// 窗口已经输出过, 这是迟到数据触发的更新
+    pub update: bool,
+}
+
+/// 按事件时间还是处理时间划分窗口
+pub enum TimeMode<T> {
+    /// 以数据到达算子的时间为准, 从创建算子开始计时
+    Processing,
+    /// 以数据自带的时间戳为准
+    Event {
+        timestamp: Box<dyn Fn(&T) -> i64 + Send>,
+        /// 水位线落后于最大事件时间的毫秒数
+        max_out_of_orderness: i64,
+        /// 窗口触发后继续接受迟到数据的毫秒数
+        allowed_lateness: i64,
+    },
+}
+
+struct WindowState {
+    aggregator: Aggregator,
+    fired: bool,
+}
+
+/// 与流无关的窗口状态机, 负责分配窗口和根据水位线触发窗口
+pub struct Windower {
+    kind: WindowKind,
+    aggregations: Vec<Aggregation>,
+    max_out_of_orderness: i64,
+    allowed_lateness: i64,
+    windows: BTreeMap<(i64, i64), WindowState>,
+    max_timestamp: Option<i64>,
+    dropped: u64,
+    unassigned: u64,
+}
+
+impl Windower {
+    /// 窗口大小, 滑动步长和会话间隔不是正数时 panic
+    pub fn new(kind: WindowKind, aggregations: Vec<Aggregation>, max_out_of_orderness: i64, allowed_lateness: i64) -> Self {
+        match kind {
+            WindowKind::Tumbling { size } => assert!(size > 0, "window size must be positive, got {}", size),
+            WindowKind::Sliding { size, slide } => {
+                assert!(size > 0, "window size must be positive, got {}", size);
+                assert!(slide > 0, "window slide must be positive, got {}", slide);
+            }
+            WindowKind::Session { gap } => assert!(gap > 0, "session gap must be positive, got {}", gap),
+        }
+        Self {
+            kind,
+            aggregations,
+            max_out_of_orderness,
+            allowed_lateness,
+            windows: BTreeMap::new(),
+            max_timestamp: None,
+            dropped: 0,
+            unassigned: 0,
+        }
+    }
+
+    /// 当前水位线, 所有结束时间不大于水位线的窗口都已触发
+    pub fn watermark(&self) -> Option<i64> {
+        self.max_timestamp.map(|ts| ts - self.max_out_of_orderness)
+    }
+
+    /// 因为超过允许迟到时间而被丢弃的数据个数
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// 落在滑动窗口间隙中(`slide` 大于 `size`), 不属于任何窗口的数据个数
+    pub fn unassigned(&self) -> u64 {
+        self.unassigned
+    }
+
+    /// 最早一个尚未触发的窗口的结束时间
+    pub fn next_fire_time(&self) -> Option<i64> {
+        self.windows
+            .iter()
+            .filter(|(_, state)| !state.fired)
+            .map(|((_, end), _)| *end)
+            .min()
+    }
+
+    fn is_expired(&self, end: i64) -> bool {
+        self.watermark().is_some_and(|wm| end + self.allowed_lateness <= wm)
+    }
+
+    fn record(&self, (start, end): (i64, i64), state: &WindowState, update: bool) -> WindowRecord {
+        WindowRecord {
+            start,
+            end,
+            count: state.aggregator.count(),
+            aggregates: state.aggregator.finish(),
+            update,
+        }
+    }
+
+    /// 处理一个时间戳为 `timestamp` 的数据, 返回因此触发或更新的窗口
+    pub fn on_element<T: AggregateValue>(&mut self, timestamp: i64, value: &T) -> Vec<WindowRecord> {
+        let mut output = Vec::new();
+        let mut assigned = false;
+
+        let keys = self.assign(timestamp);
+        let in_gap = keys.is_empty();
+        for key in keys {
+            if self.is_expired(key.1) {
+                continue;
+            }
+            assigned = true;
+            let key = match self.kind {
+                WindowKind::Session { .. } => self.merge_sessions(key),
+                _ => key,
+            };
+            let aggregations = &self.aggregations;
+            let state = self.windows.entry(key).or_insert_with(|| WindowState {
+                aggregator: Aggregator::new(aggregations.clone()),
+                fired: false,
+            });
+            state.aggregator.update(value);
+            if state.fired {
+                let state = &self.windows[&key];
+                output.push(self.record(key, state, true));
+            }
+        }
+        if in_gap {
+            self.unassigned += 1;
+        } else if !assigned {
+            self.dropped += 1;
+        }
+
+        self.max_timestamp = Some(self.max_timestamp.map_or(timestamp, |ts| ts.max(timestamp)));
+        output.extend(self.fire());
+        output
+    }
+
+    /// 处理时间推进到 `now`, 触发到期的窗口
+    pub fn advance_to(&mut self, now: i64) -> Vec<WindowRecord> {
+        self.max_timestamp = Some(self.max_timestamp.map_or(now, |ts| ts.max(now)));
+        self.fire()
+    }
+
+    /// 流结束时输出所有尚未触发的窗口
+    pub fn flush(&mut self) -> Vec<WindowRecord> {
+        let windows = std::mem::take(&mut self.windows);
+        windows
+            .iter()
+            .filter(|(_, state)| !state.fired)
+            .map(|(key, state)| self.record(*key, state, false))
+            .collect()
+    }
+
+    fn fire(&mut self) -> Vec<WindowRecord> {
+        let Some(watermark) = self.watermark() else {
+            return Vec::new();
+        };
+        let mut output = Vec::new();
+        for (key, state) in self.windows.iter_mut() {
+            if !state.fired && key.1 <= watermark {
+                state.fired = true;
+                output.push(WindowRecord {
+                    start: key.0,
+                    end: key.1,
+                    count: state.aggregator.count(),
+                    aggregates: state.aggregator.finish(),
+                    update: false,
+                });
+            }
+        }
+        let lateness = self.allowed_lateness;
+        self.windows.retain(|key, _| key.1 + lateness > watermark);
+        output
+    }
+
+    fn assign(&self, timestamp: i64) -> Vec<(i64, i64)> {
+        match self.kind {
+            WindowKind::Tumbling { size } => {
+                let start = timestamp.div_euclid(size) * size;
+                vec![(start, start + size)]
+            }
+            WindowKind::Sliding { size, slide } => {
+                let mut start = timestamp.div_euclid(slide) * slide;
+                let mut windows = Vec::new();
+                while start > timestamp - size {
+                    windows.push((start, start + size));
+                    start -= slide;
+                }
+                windows
+            }
+            WindowKind::Session { gap } => vec![(timestamp, timestamp + gap)],
+        }
+    }
+
+    /// 合并与新会话重叠的已有会话, 返回合并后的窗口
+    fn merge_sessions(&mut self, (mut start, mut end): (i64, i64)) -> (i64, i64) {
+        let overlapping: Vec<(i64, i64)> = self
+            .windows
+            .keys()
+            .filter(|(s, e)| *s < end && start < *e)
+            .copied()
+            .collect();
+        if overlapping.is_empty() {
+            return (start, end);
+        }
+
+        let mut merged = WindowState {
+            aggregator: Aggregator::new(self.aggregations.clone()),
+            fired: false,
+        };
+        for key in overlapping {
+            let state = self.windows.remove(&key).expect("key from map");
+            merged.aggregator.merge(&state.aggregator);
+            merged.fired |= state.fired;
+            start = start.min(key.0);
+            end = end.max(key.1);
+        }
+        self.windows.insert((start, end), merged);
+        (start, end)
+    }
+}
+
+
+fn window_millis(duration: Duration, what: &str) -> i64 {
+    let millis = duration.as_millis();
+    assert!(millis >= 1, "{} must be at least 1ms, got {:?}", what, duration);
+    millis.min(i64::MAX as u128) as i64
+}
+
+/// 配置窗口算子
+pub struct WindowBuilder<T> {
+    kind: WindowKind,
+    time: TimeMode<T>,
+    aggregations: Vec<Aggregation>,
+}
+
+impl<T> WindowBuilder<T> {
+    fn new(kind: WindowKind) -> Self {
+        Self {
+            kind,
+            time: TimeMode::Processing,
+            aggregations: Vec::new(),
+        }
+    }
+
+    /// 窗口按毫秒划分, `size` 小于 1 毫秒时 panic
+    pub fn tumbling(size: Duration) -> Self {
+        Self::new(WindowKind::Tumbling { size: window_millis(size, "window size") })
+    }
+
+    /// `size` 或 `slide` 小于 1 毫秒时 panic
+    pub fn sliding(size: Duration, slide: Duration) -> Self {
+        Self::new(WindowKind::Sliding {
+            size: window_millis(size, "window size"),
+            slide: window_millis(slide, "window slide"),
+        })
+    }
+
+    /// `gap` 小于 1 毫秒时 panic
+    pub fn session(gap: Duration) -> Self {
+        Self::new(WindowKind::Session { gap: window_millis(gap, "session gap") })
+    }
+
+    /// 使用事件时间, `timestamp` 从数据中取出毫秒时间戳
+    pub fn event_time(mut self, timestamp: impl Fn(&T) -> i64 + Send + 'static, max_out_of_orderness: Duration) -> Self {
+        let allowed_lateness = match self.time {
+            TimeMode::Event { allowed_lateness, .. } => allowed_lateness,
+            TimeMode::Processing => 0,
+        };
+        self.time = TimeMode::Event {
+            timestamp: Box::new(timestamp),
+            max_out_of_orderness: max_out_of_orderness.as_millis() as i64,
+            allowed_lateness,
+        };
+        self
+    }
+
+    /// 窗口触发后继续接受迟到数据的时间, 只对事件时间有效
+    pub fn allowed_lateness(mut self, lateness: Duration) -> Self {
+        if let TimeMode::Event { allowed_lateness, .. } = &mut self.time {
+            *allowed_lateness = lateness.as_millis() as i64;
+        }
+        self
+    }
+
+    pub fn aggregate(mut self, aggregation: Aggregation) -> Self {
+        self.aggregations.push(aggregation);
+        self
+    }
+
+    pub fn build<S>(self, stream: S) -> Windowed<S, T>
+    where
+        S: Stream<Item = T>,
+    {
+        let (ooo, lateness) = match &self.time {
+            TimeMode::Processing => (0, 0),
+            TimeMode::Event { max_out_of_orderness, allowed_lateness, .. } => (*max_out_of_orderness, *allowed_lateness),
+        };
+        Windowed {
+            stream,
+            windower: Windower::new(self.kind, self.aggregations, ooo, lateness),
+            time: self.time,
+            timer: None,
+            started: tokio::time::Instant::now(),
+            pending: VecDeque::new(),
+            ended: false,
+        }
+    }
+}
+
+/// 对上游数据做窗口聚合, 每个窗口输出一条 `WindowRecord`
+#[pin_project]
+pub struct Windowed<S, T> {
+    #[pin]
+    stream: S,
+    windower: Windower,
+    time: TimeMode<T>,
+    /// 处理时间模式下, 在没有新数据时按时触发窗口
+    timer: Option<Pin<Box<tokio::time::Sleep>>>,
+    started: tokio::time::Instant,
+    pending: VecDeque<WindowRecord>,
+    ended: bool,
+}
+
+impl<S, T> Windowed<S, T> {
+    pub fn windower(&self) -> &Windower {
+        &self.windower
+    }
+}
+
+impl<S, T> Stream for Windowed<S, T>
+where
+    S: Stream<Item = T>,
+    T: AggregateValue,
+{
+    type Item = WindowRecord;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            if let Some(record) = this.pending.pop_front() {
+                return Poll::Ready(Some(record));
+            }
+            if *this.ended {
+                return Poll::Ready(None);
+            }
+
+            match this.stream.as_mut().poll_next(cx) {
+                Poll::Ready(Some(item)) => {
+                    let timestamp = match this.time {
+                        TimeMode::Processing => this.started.elapsed().as_millis() as i64,
+                        TimeMode::Event { timestamp, .. } => timestamp(&item),
+                    };
+                    this.pending.extend(this.windower.on_element(timestamp, &item));
+                }
+                Poll::Ready(None) => {
+                    this.pending.extend(this.windower.flush());
+                    *this.ended = true;
+                }
+                Poll::Pending => {
+                    if !matches!(this.time, TimeMode::Processing) {
+                        return Poll::Pending;
+                    }
+                    let Some(fire_at) = this.windower.next_fire_time() else {
+                        return Poll::Pending;
+                    };
+                    let deadline = *this.started + Duration::from_millis(fire_at.max(0) as u64);
+                    let timer = this.timer.get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline)));
+                    if timer.deadline() != deadline {
+                        timer.as_mut().reset(deadline);
+                    }
+                    if timer.as_mut().poll(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                    let now = this.started.elapsed().as_millis() as i64;
+                    this.pending.extend(this.windower.advance_to(now));
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use tokio_stream::StreamExt;
+    use crate::collector::StreamCollector;
+    use super::*;
+
+    fn windows(records: &[WindowRecord]) -> Vec<(i64, i64, u64, bool)> {
+        records.iter().map(|r| (r.start, r.end, r.count, r.update)).collect()
+    }
+
+    #[test]
+    #[should_panic(expected = "window slide must be at least 1ms")]
+    fn test_sub_millisecond_window() {
+        WindowBuilder::<i32>::sliding(Duration::from_secs(1), Duration::from_micros(500));
+    }
+
+    #[test]
+    #[should_panic(expected = "window slide must be positive")]
+    fn test_zero_slide_windower() {
+        Windower::new(WindowKind::Sliding { size: 10, slide: 0 }, vec![], 0, 0);
+    }
+
+    #[test]
+    fn test_sliding_gaps_are_not_late() {
+        // 窗口 [0, 5), [10, 15), ..., 5..10 落在间隙中
+        let mut hopping = Windower::new(WindowKind::Sliding { size: 5, slide: 10 }, vec![], 0, 0);
+        hopping.on_element(2, &1);
+        hopping.on_element(7, &1);
+        assert_eq!(hopping.unassigned(), 1);
+        assert_eq!(hopping.dropped(), 0);
+        // 真正的迟到数据仍然计入 `dropped`
+        hopping.on_element(3, &1);
+        assert_eq!(hopping.dropped(), 1);
+        assert_eq!(hopping.unassigned(), 1);
+    }
+
+    #[test]
+    fn test_tumbling_and_sliding_assignment() {
+        let mut tumbling = Windower::new(WindowKind::Tumbling { size: 10 }, vec![Aggregation::Sum], 0, 0);
+        assert!(tumbling.on_element(1, &1).is_empty());
+        assert!(tumbling.on_element(9, &2).is_empty());
+        let fired = tumbling.on_element(12, &3);
+        assert_eq!(windows(&fired), vec![(0, 10, 2, false)]);
+        assert_eq!(fired[0].aggregates.sum, Some(3.0));
+        assert_eq!(windows(&tumbling.flush()), vec![(10, 20, 1, false)]);
+
+        let mut sliding = Windower::new(WindowKind::Sliding { size: 10, slide: 5 }, vec![], 0, 0);
+        sliding.on_element(7, &1);
+        assert_eq!(windows(&sliding.flush()), vec![(0, 10, 1, false), (5, 15, 1, false)]);
+    }
+
+    #[test]
+    fn test_event_time_lateness_and_sessions() {
+        let mut windower = Windower::new(WindowKind::Tumbling { size: 10 }, vec![], 2, 5);
+        windower.on_element(3, &1);
+        // 水位线 = 12 - 2 = 10, 触发 [0, 10)
+        assert_eq!(windows(&windower.on_element(12, &1)), vec![(0, 10, 1, false)]);
+        // 迟到但仍在允许时间内, 输出更新
+        assert_eq!(windows(&windower.on_element(4, &1)), vec![(0, 10, 2, true)]);
+        // 水位线 = 15, 窗口 [0, 10) 被清除, 之后的迟到数据被丢弃
+        windower.on_element(17, &1);
+        assert!(windower.on_element(5, &1).is_empty());
+        assert_eq!(windower.dropped(), 1);
+
+        let mut sessions = Windower::new(WindowKind::Session { gap: 5 }, vec![], 10, 0);
+        sessions.on_element(0, &1);
+        sessions.on_element(8, &1);
+        // 填补两个会话之间的空隙, 三个数据合并为一个会话
+        sessions.on_element(4, &1);
+        assert_eq!(windows(&sessions.on_element(30, &1)), vec![(0, 13, 3, false)]);
+        assert_eq!(windows(&sessions.flush()), vec![(30, 35, 1, false)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_processing_time_windows() {
+        let source = tokio_stream::iter(vec![1u32, 2, 3]).throttle(Duration::from_millis(40));
+        let windowed = WindowBuilder::tumbling(Duration::from_millis(100))
+            .aggregate(Aggregation::Count)
+            .build(Box::pin(source));
+        let records = StreamCollector::new(Box::pin(windowed)).await;
+        assert_eq!(windows(&records), vec![(0, 100, 3, false)]);
+
+        let source = tokio_stream::iter(vec![(0i64, 1u32), (150, 2), (20, 3), (250, 4)]);
+        let windowed = WindowBuilder::tumbling(Duration::from_millis(100))
+            .event_time(|(ts, _): &(i64, u32)| *ts, Duration::ZERO)
+            .allowed_lateness(Duration::from_millis(100))
+            .aggregate(Aggregation::Sum)
+            .build(source);
+        let records = StreamCollector::new(Box::pin(windowed)).await;
+        assert_eq!(
+            windows(&records),
+            vec![(0, 100, 1, false), (0, 100, 2, true), (100, 200, 1, false), (200, 300, 1, false)]
+        );
+        assert_eq!(records[1].aggregates.sum, Some(4.0));
+    }
+}
@@ -6,13 +6,23 @@ fn main() {
         .protoc_arg("--experimental_allow_proto3_optional")
         .out_dir("src/grpc/pb")
         .with_serde(
-            &["store.Msg", "store.MsgId", "store.MsgTime"],
+            &[
+                "store.Msg",
+                "store.MsgId",
+                "store.MsgTime",
+                "store.TailRequest",
+            ],
             true,
             true,
             Some(&[r#"#[serde(rename_all = "camelCase")]"#]),
         )
         .with_derive_builder(
-            &["store.Msg", "store.MsgId", "store.MsgTime"],
+            &[
+                "store.Msg",
+                "store.MsgId",
+                "store.MsgTime",
+                "store.TailRequest",
+            ],
             Some(&[r#"#[builder(build_fn(name = "private_build"))]"#]),
         )
         .compile(&["protos/store.proto"], &["protos"])
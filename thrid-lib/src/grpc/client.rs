@@ -1,6 +1,7 @@
 use crate::grpc::pb::store_service_client::StoreServiceClient;
 
-use super::pb::{MsgBuilder, MsgIdBuilder, MsgTimeBuilder};
+use super::pb::tail_request::Start;
+use super::pb::{MsgBuilder, MsgIdBuilder, MsgTimeBuilder, TailRequestBuilder};
 
 #[allow(dead_code)]
 #[tokio::main]
@@ -29,5 +30,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let _r = client.subscribe_with_time(msg_time).await?;
 
+    // 订阅之后写入的消息, 流一直保持打开
+    let tail = TailRequestBuilder::default()
+        .start(Start::Latest as i32)
+        .position(0_i64)
+        .buffer(16_u32)
+        .private_build()
+        .unwrap();
+    let _r = client.tail(tail).await?;
+
     Ok(())
 }
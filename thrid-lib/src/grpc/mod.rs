@@ -1,3 +1,4 @@
 mod client;
-mod pb;
-mod server;
+pub mod pb;
+pub mod server;
+pub mod storage;
@@ -35,6 +35,61 @@ pub struct MsgTime {
     #[prost(int64, tag = "1")]
     pub timestamp: i64,
 }
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[derive(derive_builder::Builder)]
+#[builder(setter(into, strip_option), default)]
+#[builder(build_fn(name = "private_build"))]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TailRequest {
+    #[prost(enumeration = "tail_request::Start", tag = "1")]
+    pub start: i32,
+    #[prost(int64, tag = "2")]
+    pub position: i64,
+    /// 订阅者的缓冲消息数, 0 时使用服务端默认值
+    #[prost(uint32, tag = "3")]
+    pub buffer: u32,
+}
+/// Nested message and enum types in `TailRequest`.
+pub mod tail_request {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Start {
+        /// 已有的全部消息
+        Earliest = 0,
+        /// 只接收订阅之后写入的消息
+        Latest = 1,
+        /// id 大于等于 position 的消息
+        Id = 2,
+        /// 时间戳大于 position 的消息
+        Timestamp = 3,
+    }
+    impl Start {
+        /// String value of the enum field names used in the ProtoBuf definition.
+        ///
+        /// The values are not transformed in any way and thus are considered stable
+        /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+        pub fn as_str_name(&self) -> &'static str {
+            match self {
+                Start::Earliest => "EARLIEST",
+                Start::Latest => "LATEST",
+                Start::Id => "ID",
+                Start::Timestamp => "TIMESTAMP",
+            }
+        }
+        /// Creates an enum from field names used in the ProtoBuf definition.
+        pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+            match value {
+                "EARLIEST" => Some(Self::Earliest),
+                "LATEST" => Some(Self::Latest),
+                "ID" => Some(Self::Id),
+                "TIMESTAMP" => Some(Self::Timestamp),
+                _ => None,
+            }
+        }
+    }
+}
 /// Generated client implementations.
 pub mod store_service_client {
     #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
@@ -178,6 +233,22 @@ pub mod store_service_client {
                 .server_streaming(request.into_request(), path, codec)
                 .await
         }
+        pub async fn tail(
+            &mut self,
+            request: impl tonic::IntoRequest<super::TailRequest>,
+        ) -> Result<tonic::Response<tonic::codec::Streaming<super::Msg>>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/store.StoreService/tail");
+            self.inner
+                .server_streaming(request.into_request(), path, codec)
+                .await
+        }
     }
 }
 /// Generated server implementations.
@@ -215,6 +286,14 @@ pub mod store_service_server {
             &self,
             request: tonic::Request<super::MsgTime>,
         ) -> Result<tonic::Response<Self::subscribeWithTimeStream>, tonic::Status>;
+        /// Server streaming response type for the tail method.
+        type tailStream: futures_core::Stream<Item = Result<super::Msg, tonic::Status>>
+            + Send
+            + 'static;
+        async fn tail(
+            &self,
+            request: tonic::Request<super::TailRequest>,
+        ) -> Result<tonic::Response<Self::tailStream>, tonic::Status>;
     }
     #[derive(Debug)]
     pub struct StoreServiceServer<T: StoreService> {
@@ -418,6 +497,39 @@ pub mod store_service_server {
                     };
                     Box::pin(fut)
                 }
+                "/store.StoreService/tail" => {
+                    #[allow(non_camel_case_types)]
+                    struct tailSvc<T: StoreService>(pub Arc<T>);
+                    impl<T: StoreService> tonic::server::ServerStreamingService<super::TailRequest> for tailSvc<T> {
+                        type Response = super::Msg;
+                        type ResponseStream = T::tailStream;
+                        type Future =
+                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::TailRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).tail(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = tailSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
                 _ => Box::pin(async move {
                     Ok(http::Response::builder()
                         .status(200)
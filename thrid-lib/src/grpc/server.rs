@@ -1,13 +1,16 @@
 use super::pb::MsgTime;
 use crate::grpc::pb::store_service_server::{StoreService, StoreServiceServer};
-use crate::grpc::pb::{Msg, MsgId};
+use crate::grpc::pb::tail_request::Start;
+use crate::grpc::pb::{Msg, MsgId, TailRequest};
+use crate::grpc::storage::{MemoryBackend, StorageBackend, StorageError, WalBackend, WalConfig};
 use async_trait::async_trait;
 
 use prost::bytes::Bytes;
-use std::collections::HashMap;
 
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
 use tokio::sync::mpsc::{self};
+use tokio::sync::RwLock;
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::transport::Server;
 use tonic::{Code, Request, Response, Status};
@@ -21,7 +24,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let addr = "127.0.0.1:3000".parse().unwrap();
 
-    let store: KvStoreService = KvStoreService::default();
+    // 指定数据目录时使用持久化存储, 启动时从目录恢复数据
+    let store = match std::env::args().nth(1) {
+        Some(dir) => KvStoreService::new(WalBackend::open(dir, WalConfig::default())?),
+        None => KvStoreService::default(),
+    };
     let kv_service = StoreServiceServer::new(store);
 
     println!("StoreServiceServer listening on {}", addr);
@@ -36,16 +43,134 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-type State = Arc<Mutex<HashMap<i64, Bytes>>>;
+/// 读操作(`get` 和订阅时读取已有消息)共享读锁, 写操作独占写锁.
+/// 锁被占用时请求异步等待, 不会因为竞争而失败
+type State = Arc<RwLock<Box<dyn StorageBackend>>>;
 
-struct KvStoreService {
+/// 所有订阅者共享的新消息队列长度, 落后超过这么多条的订阅者被断开
+const DEFAULT_TAIL_CAPACITY: usize = 1024;
+/// 每个订阅者默认缓冲的消息数
+const DEFAULT_TAIL_BUFFER: usize = 64;
+const MAX_TAIL_BUFFER: usize = 4096;
+
+pub struct KvStoreService {
     db: State,
+    /// `send` 持有存储的锁时发布新消息, 订阅者在同一把锁内读取已有消息并订阅,
+    /// 因此已有消息和新消息之间不会遗漏或重复
+    events: broadcast::Sender<Msg>,
+    tail_buffer: usize,
+}
+
+impl KvStoreService {
+    pub fn new(backend: impl StorageBackend) -> Self {
+        let db: State = Arc::new(RwLock::new(Box::new(backend)));
+        let (events, _) = broadcast::channel(DEFAULT_TAIL_CAPACITY);
+        Self {
+            db,
+            events,
+            tail_buffer: DEFAULT_TAIL_BUFFER,
+        }
+    }
+
+    /// 订阅者最多落后多少条新消息, 至少为 1
+    pub fn with_tail_capacity(mut self, capacity: usize) -> Self {
+        self.events = broadcast::channel(capacity.max(1)).0;
+        self
+    }
+
+    /// 请求中没有指定时每个订阅者缓冲的消息数
+    pub fn with_tail_buffer(mut self, buffer: usize) -> Self {
+        self.tail_buffer = buffer.clamp(1, MAX_TAIL_BUFFER);
+        self
+    }
+
+    /// 先发送起点之后的已有消息, 然后持续发送新写入的消息, 直到客户端断开.
+    /// 消费太慢, 落后超过 `with_tail_capacity` 条时以 `ResourceExhausted` 结束,
+    /// 客户端可以从收到的最后一条消息重新订阅
+    async fn tail_from(
+        &self,
+        from: TailFrom,
+        buffer: usize,
+    ) -> Result<Response<ReceiverStream<Result<Msg, Status>>>, Status> {
+        let (backlog, mut events) = {
+            let db = self.db.read().await;
+            (from.backlog(db.as_ref())?, self.events.subscribe())
+        };
+
+        let (tx, rx) = mpsc::channel(buffer);
+        tokio::spawn(async move {
+            for msg in backlog {
+                if tx.send(Ok(msg)).await.is_err() {
+                    return;
+                }
+            }
+            loop {
+                let msg = tokio::select! {
+                    msg = events.recv() => msg,
+                    _ = tx.closed() => return,
+                };
+                match msg {
+                    Ok(msg) => {
+                        if from.matches(&msg) && tx.send(Ok(msg)).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        event!(Level::WARN, "subscriber lagged by {} messages", skipped);
+                        let status = Status::new(
+                            Code::ResourceExhausted,
+                            format!("subscriber lagged by {} messages", skipped),
+                        );
+                        let _ = tx.send(Err(status)).await;
+                        return;
+                    }
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+/// 订阅的起点
+#[derive(Clone, Copy, Debug)]
+enum TailFrom {
+    Earliest,
+    Latest,
+    Id(i64),
+    After(i64),
+}
+
+impl TailFrom {
+    fn backlog(&self, db: &dyn StorageBackend) -> Result<Vec<Msg>, StorageError> {
+        match *self {
+            TailFrom::Earliest => db.scan_from_id(i64::MIN),
+            TailFrom::Latest => Ok(Vec::new()),
+            TailFrom::Id(id) => db.scan_from_id(id),
+            TailFrom::After(timestamp) => db.scan_after_time(timestamp),
+        }
+    }
+
+    fn matches(&self, msg: &Msg) -> bool {
+        match *self {
+            TailFrom::Earliest | TailFrom::Latest => true,
+            TailFrom::Id(id) => msg.id >= id,
+            TailFrom::After(timestamp) => msg.timestamp.is_some_and(|t| t > timestamp),
+        }
+    }
 }
 
 impl Default for KvStoreService {
     fn default() -> KvStoreService {
-        let db = Arc::new(Mutex::new(HashMap::default()));
-        Self { db }
+        Self::new(MemoryBackend::default())
+    }
+}
+
+impl From<StorageError> for Status {
+    fn from(err: StorageError) -> Self {
+        event!(Level::ERROR, "storage error: {}", err);
+        Status::new(Code::Internal, err.to_string())
     }
 }
 
@@ -57,56 +182,29 @@ impl StoreService for KvStoreService
 {
     async fn get(&self, request: Request<MsgId>) -> Result<Response<Msg>, Status> {
         let msg_id = request.into_inner();
-        match self.db.try_lock() {
-            Ok(lock) => {
-                if let Some(bytes) = lock.get(&msg_id.id) {
-                    let vec = bytes.to_vec();
-                    let vec = vec.as_slice();
-                    let deserialized: Msg = serde_json::from_slice(vec).unwrap();
-                    Ok(Response::new(deserialized))
-                } else {
-                    Err(Status::new(Code::NotFound, ""))
-                }
-            }
-            Err(err) => {
-                event!(Level::ERROR, "Failed to acquire lock!");
-                Err(Status::new(Code::FailedPrecondition, err.to_string()))
-            }
+        let db = self.db.read().await;
+        if let Some(msg) = db.get(msg_id.id)? {
+            Ok(Response::new(msg))
+        } else {
+            Err(Status::new(Code::NotFound, ""))
         }
     }
     async fn send(&self, request: Request<Msg>) -> Result<Response<bool>, Status> {
         let msg = request.into_inner();
-        let id = msg.id;
-
-        let serialized = serde_json::to_vec(&msg).unwrap();
 
-        match self.db.try_lock() {
-            Ok(ref mut lock) => {
-                lock.insert(id, Bytes::from(serialized));
-                Ok(Response::new(true))
-            }
-
-            Err(err) => {
-                event!(Level::ERROR, "Failed to acquire lock!");
-                Err(Status::new(Code::FailedPrecondition, err.to_string()))
-            }
-        }
+        let mut db = self.db.write().await;
+        db.put(msg.clone())?;
+        // 没有订阅者时发送失败, 忽略
+        let _ = self.events.send(msg);
+        Ok(Response::new(true))
     }
 
     async fn delete(&self, request: Request<MsgId>) -> Result<Response<bool>, Status> {
         let msg = request.into_inner();
         let id = msg.id;
-        match self.db.try_lock() {
-            Ok(ref mut lock) => {
-                lock.remove(&id);
-                Ok(Response::new(true))
-            }
-
-            Err(err) => {
-                event!(Level::ERROR, "Failed to acquire lock!");
-                Err(Status::new(Code::FailedPrecondition, err.to_string()))
-            }
-        }
+        let mut db = self.db.write().await;
+        db.delete(id)?;
+        Ok(Response::new(true))
     }
 
     type subscribeStream = ReceiverStream<Result<Msg, Status>>;
@@ -116,23 +214,8 @@ impl StoreService for KvStoreService
         request: Request<MsgId>,
     ) -> Result<Response<Self::subscribeStream>, Status> {
         let msg_id = request.into_inner();
-        let id = msg_id.id;
-        let msgs = match self.db.try_lock() {
-            Ok(lock) => {
-                let msges = lock
-                    .iter()
-                    .filter(|(nid, _)| **nid >= id)
-                    .map(|(_, bytes)| bytes.into())
-                    .collect::<Vec<Msg>>();
-                Some(msges)
-            }
-            Err(_err) => {
-                event!(Level::ERROR, "Failed to acquire lock!");
-                None
-            }
-        };
-
-        return_stream(msgs).await
+        self.tail_from(TailFrom::Id(msg_id.id), self.tail_buffer)
+            .await
     }
 
     type subscribeWithTimeStream = ReceiverStream<Result<Msg, Status>>;
@@ -142,26 +225,34 @@ impl StoreService for KvStoreService
         request: Request<MsgTime>,
     ) -> Result<Response<Self::subscribeWithTimeStream>, Status> {
         let msg_time = request.into_inner();
-        let timestamp = msg_time.timestamp;
-        let msgs = match self.db.try_lock() {
-            Ok(lock) => {
-                let msges = lock
-                    .iter()
-                    .map(|(_, bytes)| bytes.into())
-                    .filter(|m: &Msg| {
-                        let top = m.timestamp;
-                        top.is_some_and(|t| t > timestamp)
-                    })
-                    .collect::<Vec<Msg>>();
-                Some(msges)
-            }
-            Err(_err) => {
-                event!(Level::ERROR, "Failed to acquire lock!");
-                None
+        self.tail_from(TailFrom::After(msg_time.timestamp), self.tail_buffer)
+            .await
+    }
+
+    type tailStream = ReceiverStream<Result<Msg, Status>>;
+
+    async fn tail(
+        &self,
+        request: Request<TailRequest>,
+    ) -> Result<Response<Self::tailStream>, Status> {
+        let req = request.into_inner();
+        let from = match Start::from_i32(req.start) {
+            Some(Start::Earliest) => TailFrom::Earliest,
+            Some(Start::Latest) => TailFrom::Latest,
+            Some(Start::Id) => TailFrom::Id(req.position),
+            Some(Start::Timestamp) => TailFrom::After(req.position),
+            None => {
+                return Err(Status::new(
+                    Code::InvalidArgument,
+                    format!("unknown start {}", req.start),
+                ))
             }
         };
-
-        return_stream(msgs).await
+        let buffer = match req.buffer {
+            0 => self.tail_buffer,
+            buffer => (buffer as usize).min(MAX_TAIL_BUFFER),
+        };
+        self.tail_from(from, buffer).await
     }
 }
 
@@ -173,20 +264,176 @@ impl From<&Bytes> for Msg {
     }
 }
 
-async fn return_stream(
-    msgs: Option<Vec<Msg>>,
-) -> Result<Response<ReceiverStream<Result<Msg, Status>>>, Status> {
-    let (tx, rx) = mpsc::channel(4);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_stream::StreamExt;
+
+    fn msg(id: i64, timestamp: i64) -> Msg {
+        Msg {
+            id,
+            data: vec![id as u8],
+            timestamp: Some(timestamp),
+        }
+    }
+
+    async fn send(service: &KvStoreService, msg: Msg) {
+        service.send(Request::new(msg)).await.unwrap();
+    }
+
+    async fn next_id(stream: &mut ReceiverStream<Result<Msg, Status>>) -> i64 {
+        stream.next().await.unwrap().unwrap().id
+    }
 
-    if let Some(messages) = msgs {
-        for msg in messages {
-            tx.send(Ok(msg)).await.unwrap();
+    #[tokio::test]
+    async fn subscribe_streams_backlog_then_new_messages() {
+        let service = KvStoreService::default();
+        for id in [3, 1, 2] {
+            send(&service, msg(id, 100 + id)).await;
         }
-    } else {
-        tx.send(Err(Status::new(Code::NotFound, "not found resource!")))
+
+        let mut by_id = service
+            .subscribe(Request::new(MsgId { id: 2 }))
             .await
-            .unwrap();
+            .unwrap()
+            .into_inner();
+        let mut by_time = service
+            .subscribe_with_time(Request::new(MsgTime { timestamp: 101 }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(next_id(&mut by_id).await, 2);
+        assert_eq!(next_id(&mut by_id).await, 3);
+        assert_eq!(next_id(&mut by_time).await, 2);
+        assert_eq!(next_id(&mut by_time).await, 3);
+
+        // 起点之前的新消息被跳过
+        send(&service, msg(0, 50)).await;
+        send(&service, msg(5, 200)).await;
+        assert_eq!(next_id(&mut by_id).await, 5);
+        assert_eq!(next_id(&mut by_time).await, 5);
     }
 
-    Ok(Response::new(ReceiverStream::new(rx)))
+    #[tokio::test]
+    async fn tail_latest_skips_existing_messages() {
+        let service = KvStoreService::default();
+        send(&service, msg(1, 1)).await;
+
+        let request = TailRequest {
+            start: Start::Latest as i32,
+            position: 0,
+            buffer: 8,
+        };
+        let mut stream = service
+            .tail(Request::new(request))
+            .await
+            .unwrap()
+            .into_inner();
+        send(&service, msg(0, 2)).await;
+        send(&service, msg(1, 3)).await;
+        assert_eq!(next_id(&mut stream).await, 0);
+        assert_eq!(stream.next().await.unwrap().unwrap(), msg(1, 3));
+
+        let request = TailRequest {
+            start: 42,
+            position: 0,
+            buffer: 0,
+        };
+        let err = service.tail(Request::new(request)).await.unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn slow_subscriber_is_disconnected() {
+        // 容量 0 按 1 处理
+        let service = KvStoreService::default().with_tail_capacity(0);
+        let request = TailRequest {
+            start: Start::Latest as i32,
+            position: 0,
+            buffer: 1,
+        };
+        let mut stream = service
+            .tail(Request::new(request))
+            .await
+            .unwrap()
+            .into_inner();
+        for id in 0..10 {
+            send(&service, msg(id, id)).await;
+        }
+
+        let mut received = Vec::new();
+        let err = loop {
+            match stream.next().await.unwrap() {
+                Ok(msg) => received.push(msg.id),
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(err.code(), Code::ResourceExhausted);
+        assert!(received.len() < 10);
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_clients_never_see_contention_errors() {
+        use crate::grpc::pb::store_service_client::StoreServiceClient;
+
+        const CLIENTS: i64 = 16;
+        const OPS: i64 = 100;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = async_stream::stream! {
+            loop {
+                yield listener.accept().await.map(|(stream, _)| stream);
+            }
+        };
+        let server = tokio::spawn(
+            Server::builder()
+                .add_service(StoreServiceServer::new(KvStoreService::default()))
+                .serve_with_incoming(incoming),
+        );
+
+        // 任何一个请求返回错误都会让对应的任务 panic
+        let mut clients = Vec::new();
+        for client_id in 0..CLIENTS {
+            clients.push(tokio::spawn(async move {
+                let mut client = StoreServiceClient::connect(format!("http://{}", addr))
+                    .await
+                    .unwrap();
+                for i in 0..OPS {
+                    let id = client_id * OPS + i;
+                    client.send(msg(id, id)).await.unwrap();
+                    let got = client.get(MsgId { id }).await.unwrap().into_inner();
+                    assert_eq!(got, msg(id, id));
+                    if i % 10 == 0 {
+                        client.delete(MsgId { id }).await.unwrap();
+                    }
+                    if i % 20 == 5 {
+                        let mut stream = client.subscribe(MsgId { id }).await.unwrap().into_inner();
+                        assert_eq!(stream.message().await.unwrap().unwrap().id, id);
+                    }
+                }
+            }));
+        }
+        for client in clients {
+            client.await.unwrap();
+        }
+
+        let mut client = StoreServiceClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+        let err = client.get(MsgId { id: 10 }).await.unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+        let last = CLIENTS * OPS - 1;
+        assert_eq!(
+            client
+                .get(MsgId { id: last })
+                .await
+                .unwrap()
+                .into_inner()
+                .id,
+            last
+        );
+        server.abort();
+    }
 }
@@ -0,0 +1,154 @@
+use crate::grpc::pb::Msg;
+use prost::bytes::Bytes;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound::{Excluded, Unbounded};
+use thiserror::Error;
+
+mod wal;
+
+pub use wal::{WalBackend, WalConfig};
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("corrupt data: {0}")]
+    Corrupt(String),
+}
+
+/// `KvStoreService` 背后的消息存储
+pub trait StorageBackend: Send + Sync + 'static {
+    fn get(&self, id: i64) -> Result<Option<Msg>, StorageError>;
+
+    /// 写入消息, 覆盖相同 id 的旧消息
+    fn put(&mut self, msg: Msg) -> Result<(), StorageError>;
+
+    /// 返回消息是否存在
+    fn delete(&mut self, id: i64) -> Result<bool, StorageError>;
+
+    /// id 大于等于 `from` 的消息, 按 id 升序
+    fn scan_from_id(&self, from: i64) -> Result<Vec<Msg>, StorageError>;
+
+    /// 时间戳大于 `after` 的消息, 按时间戳升序, 时间戳相同时按 id 升序;
+    /// 没有时间戳的消息不返回
+    fn scan_after_time(&self, after: i64) -> Result<Vec<Msg>, StorageError>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// 内存中按 id 排序的 `BTreeMap`, 另有 `(timestamp, id)` 的时间索引,
+/// 两种范围查询都是 O(log n + k). 消息序列化为 JSON 保存, 重启后丢失
+#[derive(Default)]
+pub struct MemoryBackend {
+    by_id: BTreeMap<i64, (Option<i64>, Bytes)>,
+    by_time: BTreeSet<(i64, i64)>,
+}
+
+impl StorageBackend for MemoryBackend {
+    fn get(&self, id: i64) -> Result<Option<Msg>, StorageError> {
+        self.by_id
+            .get(&id)
+            .map(|(_, bytes)| decode_json(bytes))
+            .transpose()
+    }
+
+    fn put(&mut self, msg: Msg) -> Result<(), StorageError> {
+        let serialized =
+            serde_json::to_vec(&msg).map_err(|e| StorageError::Corrupt(e.to_string()))?;
+        let old = self
+            .by_id
+            .insert(msg.id, (msg.timestamp, Bytes::from(serialized)));
+        if let Some((Some(timestamp), _)) = old {
+            self.by_time.remove(&(timestamp, msg.id));
+        }
+        if let Some(timestamp) = msg.timestamp {
+            self.by_time.insert((timestamp, msg.id));
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, id: i64) -> Result<bool, StorageError> {
+        match self.by_id.remove(&id) {
+            Some((timestamp, _)) => {
+                if let Some(timestamp) = timestamp {
+                    self.by_time.remove(&(timestamp, id));
+                }
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn scan_from_id(&self, from: i64) -> Result<Vec<Msg>, StorageError> {
+        self.by_id
+            .range(from..)
+            .map(|(_, (_, bytes))| decode_json(bytes))
+            .collect()
+    }
+
+    fn scan_after_time(&self, after: i64) -> Result<Vec<Msg>, StorageError> {
+        self.by_time
+            .range((Excluded((after, i64::MAX)), Unbounded))
+            .map(|(_, id)| decode_json(&self.by_id[id].1))
+            .collect()
+    }
+
+    fn len(&self) -> usize {
+        self.by_id.len()
+    }
+}
+
+fn decode_json(bytes: &Bytes) -> Result<Msg, StorageError> {
+    serde_json::from_slice(bytes).map_err(|e| StorageError::Corrupt(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ordered_range_scans() {
+        let mut backend = MemoryBackend::default();
+        for (id, timestamp) in [
+            (5, Some(30)),
+            (1, Some(50)),
+            (9, None),
+            (3, Some(30)),
+            (7, Some(10)),
+        ] {
+            backend
+                .put(Msg {
+                    id,
+                    data: vec![],
+                    timestamp,
+                })
+                .unwrap();
+        }
+        // 覆盖时更新时间索引
+        backend
+            .put(Msg {
+                id: 7,
+                data: vec![1],
+                timestamp: Some(40),
+            })
+            .unwrap();
+        assert!(backend.delete(1).unwrap());
+
+        let ids = |msgs: Vec<Msg>| msgs.iter().map(|m| m.id).collect::<Vec<_>>();
+        assert_eq!(
+            ids(backend.scan_from_id(i64::MIN).unwrap()),
+            vec![3, 5, 7, 9]
+        );
+        assert_eq!(ids(backend.scan_from_id(4).unwrap()), vec![5, 7, 9]);
+        assert_eq!(
+            ids(backend.scan_after_time(i64::MIN).unwrap()),
+            vec![3, 5, 7]
+        );
+        assert_eq!(ids(backend.scan_after_time(30).unwrap()), vec![7]);
+        assert_eq!(backend.get(7).unwrap().unwrap().data, vec![1]);
+    }
+}
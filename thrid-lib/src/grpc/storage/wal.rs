@@ -0,0 +1,359 @@
+use super::{MemoryBackend, StorageBackend, StorageError};
+use crate::grpc::pb::Msg;
+use prost::Message;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use tracing::{event, Level};
+
+const SNAPSHOT: &str = "snapshot";
+const SNAPSHOT_TMP: &str = "snapshot.tmp";
+const WAL: &str = "wal";
+
+const OP_PUT: u8 = 1;
+const OP_DELETE: u8 = 2;
+
+/// 记录头: 数据长度(u32) + crc32(u32), 均为小端
+const RECORD_HEADER_LEN: usize = 8;
+
+#[derive(Clone, Debug)]
+pub struct WalConfig {
+    /// 写入这么多条日志后生成快照并清空日志
+    pub snapshot_every: u64,
+    /// 每次写入后 `fsync`; 关闭时进程崩溃不会丢数据, 但掉电可能丢失最近的写入
+    pub sync: bool,
+}
+
+impl Default for WalConfig {
+    fn default() -> Self {
+        Self {
+            snapshot_every: 10_000,
+            sync: true,
+        }
+    }
+}
+
+/// 预写日志加定期快照的持久化存储
+///
+/// 数据目录中 `snapshot` 为某一时刻的全部消息, `wal` 为之后的写入和删除.
+/// 每个操作先追加到日志, 成功后才修改内存中的数据. 启动时加载快照再重放日志,
+/// 日志末尾不完整或校验失败的记录(写入时崩溃)被截掉.
+/// 快照先写入临时文件再重命名, 重命名后清空日志; 两步之间崩溃时重放的日志
+/// 已经包含在快照中, 重放结果不变.
+pub struct WalBackend {
+    dir: PathBuf,
+    config: WalConfig,
+    memory: MemoryBackend,
+    wal: File,
+    /// 日志中完整记录的字节数
+    wal_len: u64,
+    /// 写入失败后没能截掉不完整的记录, 之后的写入在恢复时会丢失, 因此拒绝写入
+    broken: bool,
+    /// 上次快照后写入日志的记录数
+    since_snapshot: u64,
+}
+
+impl WalBackend {
+    /// 打开或创建数据目录并恢复数据
+    pub fn open(dir: impl AsRef<Path>, config: WalConfig) -> Result<Self, StorageError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut memory = MemoryBackend::default();
+        match fs::read(dir.join(SNAPSHOT)) {
+            Ok(snapshot) => {
+                let (records, valid) = parse_records(&snapshot);
+                if valid != snapshot.len() {
+                    return Err(StorageError::Corrupt("truncated snapshot".to_string()));
+                }
+                for record in records {
+                    apply(&mut memory, record)?;
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let wal_path = dir.join(WAL);
+        let log = match fs::read(&wal_path) {
+            Ok(log) => log,
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let (records, valid) = parse_records(&log);
+        let since_snapshot = records.len() as u64;
+        for record in records {
+            apply(&mut memory, record)?;
+        }
+
+        let wal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&wal_path)?;
+        if valid != log.len() {
+            event!(
+                Level::WARN,
+                "truncating {} bytes of incomplete wal records",
+                log.len() - valid
+            );
+            wal.set_len(valid as u64)?;
+            wal.sync_all()?;
+        }
+        event!(
+            Level::INFO,
+            "recovered {} messages from {}",
+            memory.len(),
+            dir.display()
+        );
+
+        Ok(Self {
+            dir,
+            config,
+            memory,
+            wal,
+            wal_len: valid as u64,
+            broken: false,
+            since_snapshot,
+        })
+    }
+
+    /// 把当前数据写入快照并清空日志
+    pub fn snapshot(&mut self) -> Result<(), StorageError> {
+        let mut data = Vec::new();
+        for msg in self.memory.scan_from_id(i64::MIN)? {
+            data.extend(frame(&encode_put(&msg)));
+        }
+        let tmp = self.dir.join(SNAPSHOT_TMP);
+        let mut file = File::create(&tmp)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(SNAPSHOT))?;
+        sync_dir(&self.dir)?;
+
+        self.wal.set_len(0)?;
+        self.wal_len = 0;
+        self.wal.sync_all()?;
+        self.since_snapshot = 0;
+        Ok(())
+    }
+
+    /// 追加一条记录. 失败时截掉写了一半的记录, 否则恢复时在这里停下,
+    /// 之后确认过的写入全部丢失
+    fn append(&mut self, payload: &[u8]) -> Result<(), StorageError> {
+        if self.broken {
+            return Err(StorageError::Corrupt(
+                "wal contains an incomplete record, reopen the store".to_string(),
+            ));
+        }
+        let record = frame(payload);
+        let written = self.wal.write_all(&record).and_then(|_| {
+            if self.config.sync {
+                self.wal.sync_data()
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(e) = written {
+            if let Err(truncate) = self.wal.set_len(self.wal_len) {
+                event!(Level::ERROR, "failed to truncate wal: {}", truncate);
+                self.broken = true;
+            }
+            return Err(e.into());
+        }
+        self.wal_len += record.len() as u64;
+        self.since_snapshot += 1;
+        Ok(())
+    }
+
+    /// 写入已经落盘, 快照失败不影响这次写入的结果; 计数没有清零, 下次写入时重试
+    fn maybe_snapshot(&mut self) {
+        if self.since_snapshot >= self.config.snapshot_every {
+            if let Err(e) = self.snapshot() {
+                event!(
+                    Level::WARN,
+                    "snapshot failed, retrying on next write: {}",
+                    e
+                );
+            }
+        }
+    }
+}
+
+impl StorageBackend for WalBackend {
+    fn get(&self, id: i64) -> Result<Option<Msg>, StorageError> {
+        self.memory.get(id)
+    }
+
+    fn put(&mut self, msg: Msg) -> Result<(), StorageError> {
+        self.append(&encode_put(&msg))?;
+        self.memory.put(msg)?;
+        self.maybe_snapshot();
+        Ok(())
+    }
+
+    fn delete(&mut self, id: i64) -> Result<bool, StorageError> {
+        let mut payload = vec![OP_DELETE];
+        payload.extend_from_slice(&id.to_le_bytes());
+        self.append(&payload)?;
+        let existed = self.memory.delete(id)?;
+        self.maybe_snapshot();
+        Ok(existed)
+    }
+
+    fn scan_from_id(&self, from: i64) -> Result<Vec<Msg>, StorageError> {
+        self.memory.scan_from_id(from)
+    }
+
+    fn scan_after_time(&self, after: i64) -> Result<Vec<Msg>, StorageError> {
+        self.memory.scan_after_time(after)
+    }
+
+    fn len(&self) -> usize {
+        self.memory.len()
+    }
+}
+
+fn encode_put(msg: &Msg) -> Vec<u8> {
+    let mut payload = vec![OP_PUT];
+    payload.extend(msg.encode_to_vec());
+    payload
+}
+
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    record.extend_from_slice(payload);
+    record
+}
+
+/// 解析完整且校验通过的记录, 返回它们和这些记录占用的字节数
+fn parse_records(data: &[u8]) -> (Vec<&[u8]>, usize) {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while let Some(header) = data.get(offset..offset + RECORD_HEADER_LEN) {
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+        let start = offset + RECORD_HEADER_LEN;
+        let Some(payload) = data.get(start..start + len) else {
+            break;
+        };
+        if crc32fast::hash(payload) != crc {
+            break;
+        }
+        records.push(payload);
+        offset = start + len;
+    }
+    (records, offset)
+}
+
+fn apply(memory: &mut MemoryBackend, record: &[u8]) -> Result<(), StorageError> {
+    match record.split_first() {
+        Some((&OP_PUT, msg)) => {
+            let msg = Msg::decode(msg).map_err(|e| StorageError::Corrupt(e.to_string()))?;
+            memory.put(msg)
+        }
+        Some((&OP_DELETE, id)) => {
+            let id = id
+                .try_into()
+                .map_err(|_| StorageError::Corrupt("bad delete record".to_string()))?;
+            memory.delete(i64::from_le_bytes(id)).map(|_| ())
+        }
+        _ => Err(StorageError::Corrupt("unknown record type".to_string())),
+    }
+}
+
+/// 让重命名落盘
+fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(id: i64) -> Msg {
+        Msg {
+            id,
+            data: vec![id as u8; 3],
+            timestamp: Some(1000 + id),
+        }
+    }
+
+    fn ids(backend: &WalBackend) -> Vec<i64> {
+        let msgs = backend.scan_from_id(i64::MIN).unwrap();
+        msgs.iter().map(|m| m.id).collect()
+    }
+
+    #[test]
+    fn recover_from_snapshot_and_wal() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = WalConfig {
+            snapshot_every: 5,
+            sync: false,
+        };
+        let mut backend = WalBackend::open(dir.path(), config.clone()).unwrap();
+        for id in 0..6 {
+            backend.put(msg(id)).unwrap();
+        }
+        assert!(backend.delete(2).unwrap());
+        assert!(!backend.delete(42).unwrap());
+        backend
+            .put(Msg {
+                data: vec![9],
+                ..msg(1)
+            })
+            .unwrap();
+        // 第 5 条操作后生成了快照, 之后的 4 条只在日志中
+        assert_eq!(backend.since_snapshot, 4);
+        drop(backend);
+
+        // 模拟写入时崩溃: 日志末尾只有半条记录
+        let mut wal = OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(WAL))
+            .unwrap();
+        wal.write_all(&frame(&encode_put(&msg(7)))[..10]).unwrap();
+        drop(wal);
+
+        let mut backend = WalBackend::open(dir.path(), config.clone()).unwrap();
+        assert_eq!(ids(&backend), vec![0, 1, 3, 4, 5]);
+        assert_eq!(backend.get(1).unwrap().unwrap().data, vec![9]);
+        assert_eq!(backend.get(2).unwrap(), None);
+
+        // 截断后继续追加, 再次恢复时新记录可读
+        backend.put(msg(8)).unwrap();
+        drop(backend);
+        let backend = WalBackend::open(dir.path(), config).unwrap();
+        assert_eq!(ids(&backend), vec![0, 1, 3, 4, 5, 8]);
+    }
+
+    #[test]
+    fn failed_snapshot_keeps_write_and_retries() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = WalConfig {
+            snapshot_every: 2,
+            sync: false,
+        };
+        let mut backend = WalBackend::open(dir.path(), config.clone()).unwrap();
+        // 临时文件的位置被目录占用, 快照无法写入
+        fs::create_dir(dir.path().join(SNAPSHOT_TMP)).unwrap();
+        backend.put(msg(0)).unwrap();
+        backend.put(msg(1)).unwrap();
+        assert_eq!(backend.since_snapshot, 2);
+
+        fs::remove_dir(dir.path().join(SNAPSHOT_TMP)).unwrap();
+        backend.put(msg(2)).unwrap();
+        assert_eq!(backend.since_snapshot, 0);
+        assert_eq!(backend.wal_len, 0);
+        backend.put(msg(3)).unwrap();
+        drop(backend);
+
+        let backend = WalBackend::open(dir.path(), config).unwrap();
+        assert_eq!(ids(&backend), vec![0, 1, 2, 3]);
+    }
+}